@@ -0,0 +1,203 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+//! The global allocator wrapper which tells `bpf-mem` about every allocation
+//! of the Rust program, run the profiler with `--alloc-probes`.
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOC: bpf_mem_alloc::Probed<std::alloc::System> = bpf_mem_alloc::Probed(std::alloc::System);
+//! ```
+//!
+//! Each call hits a USDT probe of the provider `bpf_mem`, it is a single `nop`
+//! until the profiler attaches to it. The probes are `alloc(ptr, size)`,
+//! `dealloc(ptr, size)` and `realloc(old_ptr, ptr, size)`, the arguments are
+//! in `rdi`, `rsi` and `rdx`. Other architectures get no probes.
+
+#![no_std]
+
+use core::alloc::{GlobalAlloc, Layout};
+
+/// Wraps the allocator, reports to the profiler and delegates
+pub struct Probed<A>(pub A);
+
+unsafe impl<A> GlobalAlloc for Probed<A>
+where
+    A: GlobalAlloc,
+{
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc(layout);
+        if !ptr.is_null() {
+            probe::alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            probe::alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    // before the memory is released, another thread might get the same pointer
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        probe::dealloc(ptr, layout.size());
+        self.0.dealloc(ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.0.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            probe::realloc(ptr, new_ptr, new_size);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod probe {
+    /// The `nop` and the note in `.note.stapsdt` as in `sys/sdt.h` of systemtap,
+    /// the `.stapsdt.base` lets the tools detect prelinking; the arguments are integers,
+    /// the block only hands the addresses to the probe and never reads the memory
+    macro_rules! usdt {
+        ($name:literal, $a:expr, $b:expr, $c:expr) => {
+            core::arch::asm!(
+                "990: nop",
+                ".pushsection .note.stapsdt, \"\", \"note\"",
+                ".balign 4",
+                ".4byte 992f-991f, 994f-993f, 3",
+                "991: .asciz \"stapsdt\"",
+                "992: .balign 4",
+                "993: .8byte 990b",
+                ".8byte _.stapsdt.base",
+                ".8byte 0",
+                ".asciz \"bpf_mem\"",
+                concat!(".asciz \"", $name, "\""),
+                ".asciz \"8@%rdi 8@%rsi 8@%rdx\"",
+                "994: .balign 4",
+                ".popsection",
+                ".ifndef _.stapsdt.base",
+                ".pushsection .stapsdt.base, \"aG\", \"progbits\", .stapsdt.base, comdat",
+                ".weak _.stapsdt.base",
+                ".hidden _.stapsdt.base",
+                "_.stapsdt.base: .space 1",
+                ".size _.stapsdt.base, 1",
+                ".popsection",
+                ".endif",
+                in("rdi") $a,
+                in("rsi") $b,
+                in("rdx") $c,
+                options(att_syntax, nomem, nostack, preserves_flags),
+            )
+        };
+    }
+
+    #[inline(always)]
+    pub fn alloc(ptr: *mut u8, size: usize) {
+        unsafe { usdt!("alloc", ptr as usize, size, 0usize) }
+    }
+
+    #[inline(always)]
+    pub fn dealloc(ptr: *mut u8, size: usize) {
+        unsafe { usdt!("dealloc", ptr as usize, size, 0usize) }
+    }
+
+    #[inline(always)]
+    pub fn realloc(old_ptr: *mut u8, ptr: *mut u8, size: usize) {
+        unsafe { usdt!("realloc", old_ptr as usize, ptr as usize, size) }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod probe {
+    #[inline(always)]
+    pub fn alloc(_ptr: *mut u8, _size: usize) {}
+
+    #[inline(always)]
+    pub fn dealloc(_ptr: *mut u8, _size: usize) {}
+
+    #[inline(always)]
+    pub fn realloc(_old_ptr: *mut u8, _ptr: *mut u8, _size: usize) {}
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use core::{
+        alloc::{GlobalAlloc, Layout},
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use std::{alloc::System, fs, string::ToString};
+
+    use server::{usdt_offsets, HeapFunction};
+
+    use super::Probed;
+
+    #[derive(Default)]
+    struct Counting {
+        alloc: AtomicUsize,
+        dealloc: AtomicUsize,
+        realloc: AtomicUsize,
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            self.alloc.fetch_add(1, Ordering::SeqCst);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            self.dealloc.fetch_add(1, Ordering::SeqCst);
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            self.realloc.fetch_add(1, Ordering::SeqCst);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[test]
+    fn delegate() {
+        let probed = Probed(Counting::default());
+        let layout = Layout::from_size_align(0x20, 8).unwrap();
+        unsafe {
+            let ptr = probed.alloc(layout);
+            assert!(!ptr.is_null());
+            ptr.write_bytes(0xab, 0x20);
+            let ptr = probed.realloc(ptr, layout, 0x40);
+            assert!(!ptr.is_null());
+            assert_eq!(*ptr.add(0x1f), 0xab);
+            probed.dealloc(ptr, Layout::from_size_align(0x40, 8).unwrap());
+        }
+        let counting = &probed.0;
+        assert_eq!(counting.alloc.load(Ordering::SeqCst), 1);
+        assert_eq!(counting.realloc.load(Ordering::SeqCst), 1);
+        assert_eq!(counting.dealloc.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn notes() {
+        // the test binary has the probes of the `Probed` used above
+        let data = fs::read("/proc/self/exe").unwrap();
+        let mut names = usdt_offsets(&data, HeapFunction::USDT_PROVIDER)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<std::vec::Vec<_>>();
+        names.sort();
+        names.dedup();
+        assert_eq!(
+            names,
+            ["alloc", "dealloc", "realloc"].map(ToString::to_string)
+        );
+    }
+}
@@ -22,6 +22,16 @@ pub enum EventKind {
     PercpuFree(PercpuFree),
     AddToPageCache(AddToPageCache),
     RemoveFromPageCache(RemoveFromPageCache),
+    PageFaultUser(PageFaultUser),
+    SysEnterMmap(SysEnterMmap),
+    SysEnterMunmap(SysEnterMunmap),
+    SysEnterMremap(SysEnterMremap),
+    SysEnterBrk(SysEnterBrk),
+    SysExit(SysExit),
+    HeapCall(HeapCall),
+    HeapReturn(HeapReturn),
+    AllocProbe(AllocProbe),
+    MmapExec(MmapExec),
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -106,6 +116,20 @@ impl Stack {
         &self.ips[..self.length]
     }
 
+    fn id_from_slice(slice: &[u8]) -> Option<u64> {
+        if slice.len() < 0x10 {
+            return None;
+        }
+        let length = u64::from_ne_bytes(TryFrom::try_from(&slice[0x00..0x08]).unwrap());
+        if length & STACK_ID_FLAG == 0 {
+            return None;
+        }
+
+        Some(u64::from_ne_bytes(
+            TryFrom::try_from(&slice[0x08..0x10]).unwrap(),
+        ))
+    }
+
     pub fn from_slice(slice: &[u8]) -> Option<Self> {
         use std::mem;
 
@@ -139,6 +163,45 @@ pub struct Event {
     pub pid: u32,
     pub event: EventKind,
     pub stack: Stack,
+    // the kernel stored the stack in the map, `stack` is empty until resolved
+    #[serde(default)]
+    pub stack_id: Option<u64>,
+    // the kernel copied the user stack, `stack` is empty until unwound
+    #[serde(default)]
+    pub raw_stack: Option<RawStack>,
+}
+
+impl EventKind {
+    /// The serialized name of the event kind by the discriminant which the kernel uses
+    pub fn name(discriminant: u32) -> Option<&'static str> {
+        let name = match discriminant {
+            x if Some(x) == KFree::DISCRIMINANT => "k_free",
+            x if Some(x) == KMAlloc::DISCRIMINANT => "k_m_alloc",
+            x if Some(x) == KMAllocNode::DISCRIMINANT => "k_m_alloc_node",
+            x if Some(x) == CacheAlloc::DISCRIMINANT => "cache_alloc",
+            x if Some(x) == CacheAllocNode::DISCRIMINANT => "cache_alloc_node",
+            x if Some(x) == CacheFree::DISCRIMINANT => "cache_free",
+            x if Some(x) == PageAlloc::DISCRIMINANT => "page_alloc",
+            x if Some(x) == PageFree::DISCRIMINANT => "page_free",
+            x if Some(x) == PageFreeBatched::DISCRIMINANT => "page_free_batched",
+            x if Some(x) == RssStat::DISCRIMINANT => "rss_stat",
+            x if Some(x) == PercpuAlloc::DISCRIMINANT => "percpu_alloc",
+            x if Some(x) == AddToPageCache::DISCRIMINANT => "add_to_page_cache",
+            x if Some(x) == RemoveFromPageCache::DISCRIMINANT => "remove_from_page_cache",
+            x if Some(x) == PageFaultUser::DISCRIMINANT => "page_fault_user",
+            x if Some(x) == SysEnterMmap::DISCRIMINANT => "sys_enter_mmap",
+            x if Some(x) == SysEnterMunmap::DISCRIMINANT => "sys_enter_munmap",
+            x if Some(x) == SysEnterMremap::DISCRIMINANT => "sys_enter_mremap",
+            x if Some(x) == SysEnterBrk::DISCRIMINANT => "sys_enter_brk",
+            x if Some(x) == SysExit::DISCRIMINANT => "sys_exit",
+            x if Some(x) == HeapCall::DISCRIMINANT => "heap_call",
+            x if Some(x) == HeapReturn::DISCRIMINANT => "heap_return",
+            x if Some(x) == AllocProbe::DISCRIMINANT => "alloc_probe",
+            x if Some(x) == MmapExec::DISCRIMINANT => "mmap_exec",
+            _ => return None,
+        };
+        Some(name)
+    }
 }
 
 impl Event {
@@ -208,16 +271,64 @@ impl Event {
                 EventKind::RemoveFromPageCache(RemoveFromPageCache::from_slice(slice).ok_or(0)?),
                 RemoveFromPageCache::SIZE,
             ),
+            x if Some(x) == PageFaultUser::DISCRIMINANT => (
+                EventKind::PageFaultUser(PageFaultUser::from_slice(slice).ok_or(0)?),
+                PageFaultUser::SIZE,
+            ),
+            x if Some(x) == SysEnterMmap::DISCRIMINANT => (
+                EventKind::SysEnterMmap(SysEnterMmap::from_slice(slice).ok_or(0)?),
+                SysEnterMmap::SIZE,
+            ),
+            x if Some(x) == SysEnterMunmap::DISCRIMINANT => (
+                EventKind::SysEnterMunmap(SysEnterMunmap::from_slice(slice).ok_or(0)?),
+                SysEnterMunmap::SIZE,
+            ),
+            x if Some(x) == SysEnterMremap::DISCRIMINANT => (
+                EventKind::SysEnterMremap(SysEnterMremap::from_slice(slice).ok_or(0)?),
+                SysEnterMremap::SIZE,
+            ),
+            x if Some(x) == SysEnterBrk::DISCRIMINANT => (
+                EventKind::SysEnterBrk(SysEnterBrk::from_slice(slice).ok_or(0)?),
+                SysEnterBrk::SIZE,
+            ),
+            x if Some(x) == SysExit::DISCRIMINANT => (
+                EventKind::SysExit(SysExit::from_slice(slice).ok_or(0)?),
+                SysExit::SIZE,
+            ),
+            x if Some(x) == HeapCall::DISCRIMINANT => (
+                EventKind::HeapCall(HeapCall::from_slice(slice).ok_or(0)?),
+                HeapCall::SIZE,
+            ),
+            x if Some(x) == HeapReturn::DISCRIMINANT => (
+                EventKind::HeapReturn(HeapReturn::from_slice(slice).ok_or(0)?),
+                HeapReturn::SIZE,
+            ),
+            x if Some(x) == AllocProbe::DISCRIMINANT => (
+                EventKind::AllocProbe(AllocProbe::from_slice(slice).ok_or(0)?),
+                AllocProbe::SIZE,
+            ),
+            x if Some(x) == MmapExec::DISCRIMINANT => (
+                EventKind::MmapExec(MmapExec::from_slice(slice).ok_or(0)?),
+                MmapExec::SIZE,
+            ),
             _ => return Err(1),
         };
         let slice = &slice[size..];
-        let stack = Stack::from_slice(slice).ok_or(0)?;
+        let (stack, stack_id, raw_stack) = match Stack::id_from_slice(slice) {
+            Some(stack_id) => (Stack::from_frames(&[]), Some(stack_id), None),
+            None => match RawStack::from_slice(slice) {
+                Some(raw_stack) => (Stack::from_frames(&[]), None, Some(raw_stack)),
+                None => (Stack::from_slice(slice).ok_or(0)?, None, None),
+            },
+        };
 
         Ok(Event {
             header,
             pid,
             event,
             stack,
+            stack_id,
+            raw_stack,
         })
     }
 }
@@ -8,6 +8,16 @@ mod event;
 #[cfg(feature = "user")]
 pub use self::event::{Event, EventKind, Stack};
 
+#[cfg(feature = "user")]
+mod stack_cache;
+#[cfg(feature = "user")]
+pub use self::stack_cache::{StackSource, StackCache};
+
+#[cfg(feature = "user")]
+mod raw_stack;
+#[cfg(feature = "user")]
+pub use self::raw_stack::{RawStack, Unwinder};
+
 use core::{convert::TryFrom, fmt};
 
 #[cfg(feature = "user")]
@@ -15,6 +25,23 @@ use serde::{Deserialize, Serialize};
 
 pub const STACK_MAX_DEPTH: usize = 127;
 
+/// If this bit is set in the stack length, the event carries only the id of the stack
+pub const STACK_ID_FLAG: u64 = 1 << 63;
+
+/// If this bit is set in the stack length, the event carries the registers `ip`, `sp`, `bp`
+/// and a copy of the user stack, the rest of the length is the size of the copy
+pub const STACK_RAW_FLAG: u64 = 1 << 62;
+
+/// How many bytes of the user stack the kernel copies if the stack is unwound in the user space
+pub const RAW_STACK_SIZE: usize = 0x2000;
+
+/// The orders of the page allocations the aggregate mode counts, the greater are counted
+/// as the last one, the `usage` map has the number of allocations of each order
+pub const PAGE_ORDERS: usize = 11;
+
+/// All discriminants are less than this, the kernel counts lost events per discriminant
+pub const DISCRIMINANT_LIMIT: usize = 0x20;
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Hex64(pub u64);
 
@@ -39,6 +66,8 @@ where
 {
     const DISCRIMINANT: Option<u32>;
     const SIZE: usize;
+    /// The event is the registers of a uprobe, not the data of a tracepoint
+    const REGS: bool = false;
 
     fn from_slice(s: &[u8]) -> Option<Self>;
 }
@@ -71,6 +100,13 @@ impl Pod for CommonHeader {
     }
 }
 
+impl CommonHeader {
+    /// The kernel calls it pid, but it is the id of the thread
+    pub fn tid(&self) -> u32 {
+        self.pid
+    }
+}
+
 #[cfg_attr(feature = "user", derive(Serialize, Deserialize))]
 #[cfg_attr(not(feature = "user"), allow(dead_code))]
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -536,3 +572,314 @@ impl Pod for RemoveFromPageCache {
         })
     }
 }
+
+#[cfg_attr(feature = "user", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "user"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageFaultUser {
+    pub address: Hex64,
+    ip: Hex64,
+    error_code: Hex64,
+}
+
+impl Pod for PageFaultUser {
+    const DISCRIMINANT: Option<u32> = Some(18);
+    const SIZE: usize = 0x18;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        Some(PageFaultUser {
+            address: Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[0x00..0x08]).unwrap(),
+            )),
+            ip: Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[0x08..0x10]).unwrap(),
+            )),
+            error_code: Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[0x10..0x18]).unwrap(),
+            )),
+        })
+    }
+}
+
+#[cfg_attr(feature = "user", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "user"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SysEnterMmap {
+    pub nr: i32,
+    pub addr: Hex64,
+    pub len: u64,
+    prot: Hex64,
+    flags: Hex64,
+    fd: i64,
+    off: Hex64,
+}
+
+impl Pod for SysEnterMmap {
+    const DISCRIMINANT: Option<u32> = Some(19);
+    const SIZE: usize = 0x38;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        Some(SysEnterMmap {
+            nr: i32::from_ne_bytes(TryFrom::try_from(&s[0x00..0x04]).unwrap()),
+            addr: Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[0x08..0x10]).unwrap(),
+            )),
+            len: u64::from_ne_bytes(TryFrom::try_from(&s[0x10..0x18]).unwrap()),
+            prot: Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[0x18..0x20]).unwrap(),
+            )),
+            flags: Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[0x20..0x28]).unwrap(),
+            )),
+            fd: i64::from_ne_bytes(TryFrom::try_from(&s[0x28..0x30]).unwrap()),
+            off: Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[0x30..0x38]).unwrap(),
+            )),
+        })
+    }
+}
+
+#[cfg_attr(feature = "user", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "user"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SysEnterMunmap {
+    pub nr: i32,
+    pub addr: Hex64,
+    pub len: u64,
+}
+
+impl SysEnterMmap {
+    /// Maps the code, the map of the process has a new executable region
+    pub fn is_exec(&self) -> bool {
+        const PROT_EXEC: u64 = 0x4;
+
+        self.prot.0 & PROT_EXEC != 0
+    }
+}
+
+impl Pod for SysEnterMunmap {
+    const DISCRIMINANT: Option<u32> = Some(20);
+    const SIZE: usize = 0x18;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        Some(SysEnterMunmap {
+            nr: i32::from_ne_bytes(TryFrom::try_from(&s[0x00..0x04]).unwrap()),
+            addr: Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[0x08..0x10]).unwrap(),
+            )),
+            len: u64::from_ne_bytes(TryFrom::try_from(&s[0x10..0x18]).unwrap()),
+        })
+    }
+}
+
+#[cfg_attr(feature = "user", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "user"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SysEnterMremap {
+    pub nr: i32,
+    pub addr: Hex64,
+    pub old_len: u64,
+    pub new_len: u64,
+    pub flags: Hex64,
+    new_addr: Hex64,
+}
+
+impl Pod for SysEnterMremap {
+    const DISCRIMINANT: Option<u32> = Some(21);
+    const SIZE: usize = 0x30;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        Some(SysEnterMremap {
+            nr: i32::from_ne_bytes(TryFrom::try_from(&s[0x00..0x04]).unwrap()),
+            addr: Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[0x08..0x10]).unwrap(),
+            )),
+            old_len: u64::from_ne_bytes(TryFrom::try_from(&s[0x10..0x18]).unwrap()),
+            new_len: u64::from_ne_bytes(TryFrom::try_from(&s[0x18..0x20]).unwrap()),
+            flags: Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[0x20..0x28]).unwrap(),
+            )),
+            new_addr: Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[0x28..0x30]).unwrap(),
+            )),
+        })
+    }
+}
+
+#[cfg_attr(feature = "user", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "user"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SysEnterBrk {
+    pub nr: i32,
+    pub brk: Hex64,
+}
+
+impl Pod for SysEnterBrk {
+    const DISCRIMINANT: Option<u32> = Some(22);
+    const SIZE: usize = 0x10;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        Some(SysEnterBrk {
+            nr: i32::from_ne_bytes(TryFrom::try_from(&s[0x00..0x04]).unwrap()),
+            brk: Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[0x08..0x10]).unwrap(),
+            )),
+        })
+    }
+}
+
+/// The exit of any of the syscalls above
+#[cfg_attr(feature = "user", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "user"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SysExit {
+    pub nr: i32,
+    pub ret: i64,
+}
+
+impl Pod for SysExit {
+    const DISCRIMINANT: Option<u32> = Some(23);
+    const SIZE: usize = 0x10;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        Some(SysExit {
+            nr: i32::from_ne_bytes(TryFrom::try_from(&s[0x00..0x04]).unwrap()),
+            ret: i64::from_ne_bytes(TryFrom::try_from(&s[0x08..0x10]).unwrap()),
+        })
+    }
+}
+
+/// The entry of an allocator function, the registers as in `struct pt_regs` on x86_64
+#[cfg_attr(feature = "user", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "user"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapCall {
+    /// The first four arguments
+    pub args: [Hex64; 4],
+    /// The address of the function
+    pub ip: Hex64,
+}
+
+impl Pod for HeapCall {
+    const DISCRIMINANT: Option<u32> = Some(24);
+    const SIZE: usize = 0x88;
+    const REGS: bool = true;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        let reg = |offset: usize| {
+            Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[offset..(offset + 0x08)]).unwrap(),
+            ))
+        };
+        // rdi, rsi, rdx, rcx
+        Some(HeapCall {
+            args: [reg(0x70), reg(0x68), reg(0x60), reg(0x58)],
+            ip: reg(0x80),
+        })
+    }
+}
+
+/// The return from an allocator function
+#[cfg_attr(feature = "user", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "user"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapReturn {
+    pub ret: Hex64,
+}
+
+impl Pod for HeapReturn {
+    const DISCRIMINANT: Option<u32> = Some(25);
+    const SIZE: usize = 0x58;
+    const REGS: bool = true;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        // rax
+        Some(HeapReturn {
+            ret: Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[0x50..0x58]).unwrap(),
+            )),
+        })
+    }
+}
+
+/// The USDT probe of the `bpf-mem-alloc` global allocator, the arguments are pinned to registers
+#[cfg_attr(feature = "user", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "user"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocProbe {
+    pub args: [Hex64; 3],
+    /// The address of the probe, tells which one it is
+    pub ip: Hex64,
+}
+
+impl Pod for AllocProbe {
+    const DISCRIMINANT: Option<u32> = Some(26);
+    const SIZE: usize = 0x88;
+    const REGS: bool = true;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        if s.len() < Self::SIZE {
+            return None;
+        }
+        let reg = |offset: usize| {
+            Hex64(u64::from_ne_bytes(
+                TryFrom::try_from(&s[offset..(offset + 0x08)]).unwrap(),
+            ))
+        };
+        // rdi, rsi, rdx
+        Some(AllocProbe {
+            args: [reg(0x70), reg(0x68), reg(0x60)],
+            ip: reg(0x80),
+        })
+    }
+}
+
+/// The `mmap` of the code when the syscalls are not tracked, the same body as `SysEnterMmap`,
+/// tells to read the process map again
+#[cfg_attr(feature = "user", derive(Serialize, Deserialize))]
+#[cfg_attr(not(feature = "user"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MmapExec(pub SysEnterMmap);
+
+impl Pod for MmapExec {
+    const DISCRIMINANT: Option<u32> = Some(27);
+    const SIZE: usize = SysEnterMmap::SIZE;
+
+    #[inline(always)]
+    fn from_slice(s: &[u8]) -> Option<Self> {
+        SysEnterMmap::from_slice(s).map(MmapExec)
+    }
+}
@@ -0,0 +1,66 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::convert::TryFrom;
+
+use serde::{Serialize, Deserialize};
+
+use super::{Event, Stack, STACK_RAW_FLAG};
+
+/// The user registers and the top of the user stack at the moment of the event,
+/// the binaries without frame pointers are unwound from it in the user space
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RawStack {
+    pub ip: u64,
+    pub sp: u64,
+    pub bp: u64,
+    /// The memory starting at `sp`
+    pub data: Vec<u8>,
+}
+
+/// Something that can turn the registers and the stack memory into the frames,
+/// usually the unwind tables of the mapped files
+pub trait Unwinder {
+    fn unwind(&self, raw: &RawStack) -> Stack;
+}
+
+impl RawStack {
+    pub(crate) fn from_slice(slice: &[u8]) -> Option<Self> {
+        if slice.len() < 0x20 {
+            return None;
+        }
+        let u64_at = |o: usize| u64::from_ne_bytes(TryFrom::try_from(&slice[o..(o + 8)]).unwrap());
+        let length = u64_at(0x00);
+        if length & STACK_RAW_FLAG == 0 {
+            return None;
+        }
+        let size = (length & !STACK_RAW_FLAG) as usize;
+        let data = slice.get(0x20..(0x20 + size))?.to_vec();
+
+        Some(RawStack {
+            ip: u64_at(0x08),
+            sp: u64_at(0x10),
+            bp: u64_at(0x18),
+            data,
+        })
+    }
+
+    /// The word of the copied stack at the address
+    pub fn read(&self, address: u64) -> Option<u64> {
+        let offset = usize::try_from(address.checked_sub(self.sp)?).ok()?;
+        let bytes = self.data.get(offset..offset.checked_add(8)?)?;
+        Some(u64::from_ne_bytes(TryFrom::try_from(bytes).unwrap()))
+    }
+}
+
+impl Event {
+    /// If the kernel sent the copy of the stack, unwind it.
+    pub fn unwind_stack<U>(&mut self, unwinder: &U)
+    where
+        U: Unwinder + ?Sized,
+    {
+        if let Some(raw) = self.raw_stack.take() {
+            self.stack = unwinder.unwind(&raw);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::collections::{HashMap, VecDeque, hash_map::Entry};
+
+use super::{Event, Stack};
+
+/// Something that can fetch the stack stored by the kernel, usually the bpf map
+pub trait StackSource {
+    fn lookup(&mut self, stack_id: u64) -> Option<Stack>;
+}
+
+impl<S> StackSource for Box<S>
+where
+    S: StackSource + ?Sized,
+{
+    fn lookup(&mut self, stack_id: u64) -> Option<Stack> {
+        (**self).lookup(stack_id)
+    }
+}
+
+/// The stacks are immutable once stored, so each id is fetched from the source only once;
+/// the oldest stacks are evicted when the cache is full, the source still has them
+pub struct StackCache<S> {
+    source: S,
+    cache: HashMap<u64, Stack>,
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl<S> StackCache<S>
+where
+    S: StackSource,
+{
+    /// The same as the number of entries of the kernel map
+    pub const DEFAULT_CAPACITY: usize = 0x8000;
+
+    pub fn new(source: S) -> Self {
+        Self::with_capacity(source, Self::DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(source: S, capacity: usize) -> Self {
+        StackCache {
+            source,
+            cache: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn get(&mut self, stack_id: u64) -> Option<&Stack> {
+        if !self.cache.contains_key(&stack_id) && self.cache.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.cache.remove(&oldest);
+            }
+        }
+        let &mut StackCache {
+            ref mut source,
+            ref mut cache,
+            ref mut order,
+            ..
+        } = self;
+        match cache.entry(stack_id) {
+            Entry::Occupied(entry) => Some(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let stack = source.lookup(stack_id)?;
+                order.push_back(stack_id);
+                Some(entry.insert(stack))
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+}
+
+impl Event {
+    /// If the kernel sent only the id of the stack, take the stack from the cache.
+    /// Returns `false` if the stack is unknown.
+    pub fn resolve_stack<S>(&mut self, cache: &mut StackCache<S>) -> bool
+    where
+        S: StackSource,
+    {
+        match self.stack_id {
+            None => true,
+            Some(stack_id) => match cache.get(stack_id) {
+                Some(stack) => {
+                    self.stack = stack.clone();
+                    self.stack_id = None;
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{Stack, StackCache, StackSource};
+
+    /// Counts the lookups
+    #[derive(Default)]
+    struct Source {
+        stacks: HashMap<u64, Stack>,
+        lookups: usize,
+    }
+
+    impl StackSource for Source {
+        fn lookup(&mut self, stack_id: u64) -> Option<Stack> {
+            self.lookups += 1;
+            self.stacks.get(&stack_id).cloned()
+        }
+    }
+
+    #[test]
+    fn hit_miss_evict() {
+        let mut source = Source::default();
+        for id in 1..=3 {
+            source
+                .stacks
+                .insert(id, Stack::from_frames(&[id, id * 0x10]));
+        }
+        let mut cache = StackCache::with_capacity(source, 2);
+
+        assert_eq!(cache.get(1).unwrap().ips()[1].0, 0x10);
+        assert_eq!(cache.get(1).unwrap().ips()[1].0, 0x10);
+        assert_eq!(cache.source.lookups, 1);
+
+        // unknown ids are not cached
+        assert!(cache.get(4).is_none());
+        assert!(cache.get(4).is_none());
+        assert_eq!((cache.source.lookups, cache.len()), (3, 1));
+
+        cache.get(2).unwrap();
+        cache.get(3).unwrap();
+        assert_eq!((cache.source.lookups, cache.len()), (5, 2));
+        assert!(!cache.cache.contains_key(&1));
+
+        // evicted, fetched again
+        assert_eq!(cache.get(1).unwrap().ips()[0].0, 1);
+        assert_eq!((cache.source.lookups, cache.len()), (6, 2));
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use regex::Regex;
+use serde::Deserialize;
+
+use super::demangle::{Demangled, Language};
+
+/// The rule of the config, the function gets the `category` if every given pattern matches,
+/// the rule without patterns matches every function
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CategoryRule {
+    pub category: String,
+    /// Regex on the name of the executable, e.g. `^libssl`
+    pub executable: Option<String>,
+    /// Regex on the demangled name of the function
+    pub symbol: Option<String>,
+    /// Regex on the path of the Rust function without the hashes, e.g. `^<?tokio::`,
+    /// never matches other languages
+    pub crate_path: Option<String>,
+}
+
+struct CompiledRule {
+    category: String,
+    executable: Option<Regex>,
+    symbol: Option<Regex>,
+    crate_path: Option<Regex>,
+}
+
+/// Tells the category of the function, the first matching rule wins,
+/// then `systemLib` for the files named `lib*` or `linux*`,
+/// then by the language `nodeRust`, `go`, `swift`, or `nodeCpp`
+#[derive(Default)]
+pub struct Categories(Vec<CompiledRule>);
+
+impl Categories {
+    pub fn new(rules: &[CategoryRule]) -> Result<Self, String> {
+        let compile = |pattern: &Option<String>| {
+            pattern
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .map_err(|error| error.to_string())
+        };
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                Ok(CompiledRule {
+                    category: rule.category.clone(),
+                    executable: compile(&rule.executable)?,
+                    symbol: compile(&rule.symbol)?,
+                    crate_path: compile(&rule.crate_path)?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Categories(rules))
+    }
+
+    pub fn category(&self, executable: &str, function: Option<&Demangled>) -> String {
+        let matches = |pattern: &Option<Regex>, s: Option<&str>| match (pattern, s) {
+            (None, _) => true,
+            (Some(pattern), Some(s)) => pattern.is_match(s),
+            (Some(_), None) => false,
+        };
+        let name = function.map(|f| f.name.as_str());
+        let crate_path = function.and_then(|f| f.crate_path.as_deref());
+        let rule = self.0.iter().find(|rule| {
+            matches(&rule.executable, Some(executable))
+                && matches(&rule.symbol, name)
+                && matches(&rule.crate_path, crate_path)
+        });
+        if let Some(rule) = rule {
+            return rule.category.clone();
+        }
+
+        if executable.starts_with("lib") || executable.starts_with("linux") {
+            return "systemLib".to_string();
+        }
+        match function.map(|f| f.language) {
+            Some(Language::Rust) => "nodeRust",
+            Some(Language::Go) => "go",
+            Some(Language::Swift) => "swift",
+            _ => "nodeCpp",
+        }
+        .to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Categories, CategoryRule};
+    use crate::demangle::demangle;
+
+    #[test]
+    fn rules() {
+        let pattern = |p: Option<&str>| p.map(str::to_string);
+        let rule = |category: &str, executable, symbol, crate_path| CategoryRule {
+            category: category.to_string(),
+            executable: pattern(executable),
+            symbol: pattern(symbol),
+            crate_path: pattern(crate_path),
+        };
+        let categories = Categories::new(&[
+            rule("runtime", None, None, Some("^<?tokio::")),
+            rule("crypto", Some("^libcrypto"), None, None),
+            rule("v8", Some("^node$"), Some("^v8::"), None),
+        ])
+        .unwrap();
+
+        let tokio = demangle("_ZN5tokio7runtime7Runtime8block_on17h0123456789abcdefE");
+        assert_eq!(categories.category("node", Some(&tokio)), "runtime");
+        let v0 = demangle("_RNvMNtCs4fqI2P2rA04_5tokio7runtimeNtB2_7Runtime8block_on");
+        assert_eq!(categories.category("node", Some(&v0)), "runtime");
+        let std = demangle("_ZN3std2rt10lang_start17h0123456789abcdefE");
+        assert_eq!(categories.category("node", Some(&std)), "nodeRust");
+
+        let bn = demangle("bn_mul_mont");
+        assert_eq!(categories.category("libcrypto.so.3", Some(&bn)), "crypto");
+        assert_eq!(categories.category("libcrypto.so.3", None), "crypto");
+        assert_eq!(categories.category("libc.so.6", Some(&bn)), "systemLib");
+
+        let gc = demangle("_ZN2v88internal4Heap14CollectGarbageEv");
+        assert_eq!(categories.category("node", Some(&gc)), "v8");
+        assert_eq!(categories.category("d8", Some(&gc)), "nodeCpp");
+        assert_eq!(categories.category("node", None), "nodeCpp");
+
+        let go = demangle("runtime.mallocgc");
+        assert_eq!(categories.category("server", Some(&go)), "go");
+
+        assert!(Categories::new(&[rule("bad", Some("("), None, None)]).is_err());
+    }
+}
@@ -3,10 +3,9 @@
 
 use std::{sync::Arc, collections::HashMap};
 
-use serde::{Serialize, Deserialize};
 use event::{Hex64, Hex32, Stack};
 
-use crate::{Tracker, Page};
+use crate::{Tracker, Page, Sampling};
 
 #[derive(Clone, Hash, PartialEq, Eq, Debug)]
 pub struct FuncPath(Arc<Vec<Hex64>>);
@@ -30,19 +29,11 @@ pub struct PageInfo {
     is_cache: bool,
 }
 
+/// The values are in KiB, each page weighted by the sampling
 pub struct Usage {
     func_path: FuncPath,
-    value: u32,
-    cache_value: u32,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub enum RawEvent {
-    Alloc { page: u32, order: u8 },
-    Free { page: u32 },
-    Cache { page: u32 },
-    UnCache { page: u32 },
-    RssAnon(u32),
+    value: u64,
+    cache_value: u64,
 }
 
 #[derive(Default)]
@@ -51,26 +42,11 @@ pub struct Aggregator {
     paths: HashMap<FuncPath, FuncPathIndex>,
     pages: HashMap<PageAddress, PageInfo>,
     groups: HashMap<FuncPathIndex, Usage>,
-    dump: Option<Vec<RawEvent>>,
+    pub(super) sampling: Sampling,
 }
 
 impl Aggregator {
-    pub fn turn_on_dump(&mut self) {
-        self.dump = Some(Vec::new());
-    }
-
-    pub fn store_dump(&mut self) {
-        if let Some(dump) = &self.dump {
-            log::info!("writing dump...");
-            bincode::serialize_into(std::fs::File::create("target/dump").unwrap(), dump).unwrap();
-            log::info!("done dump");
-        }
-    }
-
     pub fn track_alloc(&mut self, page: u32, order: u8, stack: &Stack) {
-        if let Some(dump) = &mut self.dump {
-            dump.push(RawEvent::Alloc { page, order });
-        }
         let &mut Aggregator {
             ref mut counter, ..
         } = self;
@@ -88,26 +64,26 @@ impl Aggregator {
             is_allocated: false,
             is_cache: false,
         });
-        let pages_count = 1 << info.order;
+        let kib = self.sampling.scale(4 << info.order);
 
         let old_index = &info.func_path_index;
         if *old_index != *index {
             let usage = self.groups.get_mut(old_index).unwrap();
             if info.is_allocated {
-                if usage.value < pages_count {
+                if usage.value < kib {
                     log::warn!("alloc underflow, page: {:08x}-{}", page, info.order);
                 }
-                usage.value -= pages_count;
+                usage.value -= kib;
             } else {
                 if info.is_cache {
                     log::warn!("not alloc, but cache, page: {:08x}-{}", page, info.order);
                 }
             }
             if info.is_cache {
-                if usage.cache_value < pages_count {
+                if usage.cache_value < kib {
                     log::warn!("cache underflow, page: {:08x}-{}", page, info.order);
                 }
-                usage.cache_value -= pages_count;
+                usage.cache_value -= kib;
             }
             info.func_path_index = index.clone();
         }
@@ -122,10 +98,10 @@ impl Aggregator {
             // still need to increase usage for the new page
             // turn off double allocation tests
             // TODO: fix
-            usage.value += pages_count;
+            usage.value += kib;
         } else {
             info.is_allocated = true;
-            usage.value += pages_count;
+            usage.value += kib;
         }
 
         if usage.func_path != path {
@@ -134,45 +110,33 @@ impl Aggregator {
     }
 
     pub fn track_free(&mut self, page: u32) {
-        if let Some(dump) = &mut self.dump {
-            dump.push(RawEvent::Free { page });
-        }
-
         let address = PageAddress(page);
         if let Some(info) = self.pages.remove(&address) {
-            let pages_count = 1 << info.order;
+            let kib = self.sampling.scale(4 << info.order);
             let usage = self.groups.get_mut(&info.func_path_index).unwrap();
             if info.is_allocated {
-                if usage.value < pages_count {
+                if usage.value < kib {
                     log::warn!("alloc underflow, page: {:08x}-{}", page, info.order);
                 }
-                usage.value -= pages_count;
+                usage.value -= kib;
             } else {
                 if info.is_cache {
                     log::warn!("not alloc, but cache, page: {:08x}-{}", page, info.order);
                 }
             }
             if info.is_cache {
-                if usage.cache_value < pages_count {
+                if usage.cache_value < kib {
                     log::warn!("cache underflow, page: {:08x}-{}", page, info.order);
                 }
-                usage.cache_value -= pages_count;
+                usage.cache_value -= kib;
             }
         }
     }
 
     pub fn mark_cache(&mut self, page: u32, b: bool) {
-        if let Some(dump) = &mut self.dump {
-            if b {
-                dump.push(RawEvent::Cache { page });
-            } else {
-                dump.push(RawEvent::UnCache { page });
-            }
-        }
-
         let address = PageAddress(page);
         if let Some(info) = self.pages.get_mut(&address) {
-            let pages_count = 1 << info.order;
+            let kib = self.sampling.scale(4 << info.order);
             let usage = self.groups.get_mut(&info.func_path_index).unwrap();
 
             if !info.is_allocated {
@@ -186,28 +150,22 @@ impl Aggregator {
             if info.is_cache != b {
                 info.is_cache = b;
                 if b {
-                    usage.cache_value += pages_count;
+                    usage.cache_value += kib;
                 } else {
-                    if usage.cache_value < pages_count {
+                    if usage.cache_value < kib {
                         log::warn!("cache underflow, page: {:08x}-{}", page, info.order);
                     }
-                    usage.cache_value -= pages_count;
+                    usage.cache_value -= kib;
                 }
             }
         }
     }
 
-    pub fn track_rss_anon(&mut self, value: u32) {
-        if let Some(dump) = &mut self.dump {
-            dump.push(RawEvent::RssAnon(value));
-        }
-    }
-
     pub fn report(&self) -> impl Iterator<Item = (u64, u64, &[Hex64])> {
         self.groups.iter().map(|(_, usage)| {
             (
-                (usage.value as u64) * 4,
-                (usage.cache_value as u64) * 4,
+                usage.value,
+                usage.cache_value,
                 usage.func_path.0.as_ref().as_ref(),
             )
         })
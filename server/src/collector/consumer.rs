@@ -1,17 +1,25 @@
 // Copyright (c) Viable Systems
 // SPDX-License-Identifier: MIT
 
-use std::ops::Deref;
+use std::{collections::HashMap, ops::Deref};
 use std::sync::{
-    Arc, Mutex,
+    Arc, Mutex, RwLock,
     atomic::{Ordering, AtomicU32},
 };
 
-use event::{EventKind, Event};
+use event::{EventKind, Event, Hex64, MmapExec, Stack, StackSource, StackCache, SysExit};
 
-use super::{Reporter, StackResolver, FrameReport, aggregator::Aggregator};
+use super::{Reporter, StackResolver, FrameReport, aggregator::Aggregator, tracked::Tracked};
+use crate::{
+    Tracker, Page, EventStream, StreamEvent, VirtualEvent, HeapFunction, HeapFunctions, MapRefresh,
+    Sampling,
+};
 
 impl Reporter for Aggregator {
+    fn set_sampling(&mut self, sampling: Sampling) {
+        self.sampling = sampling;
+    }
+
     fn short_report(&self) -> (u64, u64) {
         let (mut value, mut cache_value) = (0, 0);
         for (v, c, _) in self.report() {
@@ -38,29 +46,110 @@ impl Reporter for Aggregator {
 
         report
     }
+
+    fn stack_report(&self) -> HashMap<Vec<Hex64>, (u64, u64)> {
+        let mut report = HashMap::new();
+        for (value, cache_value, stack) in self.report() {
+            if value != 0 {
+                report.insert(stack.to_vec(), (value, cache_value));
+            }
+        }
+
+        report
+    }
 }
 
-#[derive(Default)]
-pub struct Consumer {
+/// How deep the allocator functions nest on a thread
+const MAX_HEAP_CALLS: usize = 0x40;
+
+/// The function, its arguments and the stack of the call
+type HeapCallState = (HeapFunction, [u64; 4], Stack);
+
+pub struct Consumer<T> {
     has_pid: bool,
     pid: Arc<AtomicU32>,
-    aggregator: Arc<Mutex<Aggregator>>,
+    tracker: Arc<Mutex<Tracked<T>>>,
     last: Option<EventKind>,
+    stacks: Option<StackCache<Box<dyn StackSource>>>,
+    unwinder: Option<Arc<RwLock<StackResolver>>>,
+    map_refresh: Option<MapRefresh>,
+    stream: EventStream,
+    /// The address of the page fault in progress on each thread
+    faults: HashMap<u32, u64>,
+    /// The syscall in progress on each thread
+    syscalls: HashMap<u32, Event>,
+    heap_functions: Arc<RwLock<HeapFunctions>>,
+    /// The allocator functions in progress on each thread, waiting for the return value,
+    /// `None` is the call of an unknown function, its return must not take the outer call
+    heap_calls: HashMap<u32, Vec<Option<HeapCallState>>>,
 }
 
-impl Consumer {
-    pub fn reporter(&self) -> Arc<Mutex<Aggregator>> {
-        self.aggregator.clone()
+impl<T> Default for Consumer<T>
+where
+    T: Default,
+{
+    fn default() -> Self {
+        Consumer {
+            has_pid: false,
+            pid: Arc::default(),
+            tracker: Arc::default(),
+            last: None,
+            stacks: None,
+            unwinder: None,
+            map_refresh: None,
+            stream: EventStream::default(),
+            faults: HashMap::default(),
+            syscalls: HashMap::default(),
+            heap_functions: Arc::default(),
+            heap_calls: HashMap::default(),
+        }
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Required if the kernel sends stack ids instead of stacks
+    pub fn set_stack_source<S>(&mut self, source: S)
+    where
+        S: StackSource + 'static,
+    {
+        self.stacks = Some(StackCache::new(Box::new(source)));
+    }
+
+    /// Required if the kernel sends the copies of the user stack,
+    /// the resolver has the unwind tables of the mapped files
+    pub fn set_unwinder(&mut self, resolver: Arc<RwLock<StackResolver>>) {
+        self.unwinder = Some(resolver);
+    }
+
+    /// Triggered when the process maps new code, so its symbols are loaded right away
+    pub fn set_map_refresh(&mut self, refresh: MapRefresh) {
+        self.map_refresh = Some(refresh);
+    }
+
+    pub fn reporter(&self) -> Arc<Mutex<Tracked<T>>> {
+        self.tracker.clone()
     }
 
     pub fn pid(&self) -> Arc<AtomicU32> {
         self.pid.clone()
     }
+
+    pub fn stream(&self) -> EventStream {
+        self.stream.clone()
+    }
+
+    /// Where the allocator functions are, filled when the uprobes are attached
+    pub fn heap_functions(&self) -> Arc<RwLock<HeapFunctions>> {
+        self.heap_functions.clone()
+    }
 }
 
-impl Consumer {
+impl<T> Consumer<T>
+where
+    T: Tracker,
+{
     pub fn arrive(&mut self, data: &[u8]) {
-        let event = match Event::from_slice(data) {
+        let mut event = match Event::from_slice(data) {
             Ok(v) => v,
             Err(error) => {
                 log::error!("failed to read slice from kernel: {}", error);
@@ -68,42 +157,320 @@ impl Consumer {
             }
         };
 
+        // the calls of different threads may look the same
+        let per_thread = matches!(
+            event.event,
+            EventKind::SysEnterMmap(_)
+                | EventKind::SysEnterMunmap(_)
+                | EventKind::SysEnterMremap(_)
+                | EventKind::SysEnterBrk(_)
+                | EventKind::SysExit(_)
+                | EventKind::HeapCall(_)
+                | EventKind::HeapReturn(_)
+                | EventKind::AllocProbe(_)
+                | EventKind::MmapExec(_)
+        );
+        // the fault accounts the new page in the rss counters when it is done,
+        // the thread in a syscall or in the user space is not in the fault anymore;
+        // before the check of repeat, the same counters come from every thread of the process
+        if per_thread || matches!(event.event, EventKind::RssStat(_)) {
+            self.faults.remove(&event.header.tid());
+        }
         if let Some(last) = &self.last {
-            if last.eq(&event.event) {
+            if !per_thread && last.eq(&event.event) {
                 log::trace!("repeat");
                 return;
             }
         }
+        let need_stack = matches!(
+            event.event,
+            EventKind::PageAlloc(_)
+                | EventKind::SysEnterMmap(_)
+                | EventKind::SysEnterMremap(_)
+                | EventKind::SysEnterBrk(_)
+                | EventKind::HeapCall(_)
+                | EventKind::AllocProbe(_)
+        );
+        if let (true, Some(stacks)) = (need_stack, &mut self.stacks) {
+            if !event.resolve_stack(stacks) {
+                log::debug!("unknown stack id: {:?}", event.stack_id);
+            }
+        }
+        if let (true, Some(unwinder)) = (need_stack, &self.unwinder) {
+            event.unwind_stack(&*unwinder.read().unwrap());
+        }
         match &event.event {
             &EventKind::PageAlloc(ref v) if v.pfn.0 != 0 => {
                 self.has_pid = true;
                 self.pid.store(event.pid, Ordering::SeqCst);
-                self.aggregator.lock().unwrap().track_alloc(
-                    v.pfn.0 as u32,
-                    v.order as u8,
-                    &event.stack,
-                );
+                let page = Page::new(v.pfn, v.order);
+                let mut tracker = self.tracker.lock().unwrap();
+                tracker.track_alloc(page, &event.stack, v.gfp_flags, event.pid);
+                // the fault might also allocate the page tables, they get the same address
+                if let Some(address) = self.faults.get(&event.header.tid()) {
+                    tracker.track_page_address(page, address & !0xfff);
+                }
+                drop(tracker);
+                if self.stream.is_watched() {
+                    self.stream.send(StreamEvent::Alloc {
+                        pid: event.pid,
+                        pfn: v.pfn.0 as u32,
+                        order: v.order,
+                        flags: v.gfp_flags,
+                        stack: Arc::new(event.stack.ips().to_vec()),
+                    });
+                }
             }
             &EventKind::PageFree(ref v) if v.pfn.0 != 0 && self.has_pid => {
-                self.aggregator.lock().unwrap().track_free(v.pfn.0 as u32);
+                self.tracker
+                    .lock()
+                    .unwrap()
+                    .track_free(Page::new(v.pfn, v.order), event.pid);
+                if self.stream.is_watched() {
+                    self.stream.send(StreamEvent::Free {
+                        pid: event.pid,
+                        pfn: v.pfn.0 as u32,
+                        order: v.order,
+                    });
+                }
             }
+            // the page cache events don't tell the order
             &EventKind::AddToPageCache(ref v) if v.pfn.0 != 0 && self.has_pid => {
-                self.aggregator
+                self.tracker
                     .lock()
                     .unwrap()
-                    .mark_cache(v.pfn.0 as u32, true);
+                    .mark_page_cache(Page::new(v.pfn, 0), true);
             }
             &EventKind::RemoveFromPageCache(ref v) if v.pfn.0 != 0 && self.has_pid => {
-                self.aggregator
+                self.tracker
                     .lock()
                     .unwrap()
-                    .mark_cache(v.pfn.0 as u32, false);
+                    .mark_page_cache(Page::new(v.pfn, 0), false);
             }
             &EventKind::RssStat(ref v) if v.member == 1 && self.has_pid => {
-                self.aggregator.lock().unwrap().track_rss_anon(v.size as _);
+                self.tracker.lock().unwrap().track_rss_anon(v.size as _);
+            }
+            &EventKind::PageFaultUser(ref v) => {
+                self.faults.insert(event.header.tid(), v.address.0);
+            }
+            &EventKind::SysEnterMmap(_)
+            | &EventKind::SysEnterMunmap(_)
+            | &EventKind::SysEnterMremap(_)
+            | &EventKind::SysEnterBrk(_)
+            | &EventKind::MmapExec(_) => {
+                self.syscalls.insert(event.header.tid(), event.clone());
+            }
+            &EventKind::SysExit(ref v) => {
+                if let Some(enter) = self.syscalls.remove(&event.header.tid()) {
+                    if let Some(virtual_event) = virtual_event(&enter.event, v) {
+                        self.tracker
+                            .lock()
+                            .unwrap()
+                            .track_virtual(virtual_event, &enter.stack);
+                    }
+                    if let (true, Some(refresh)) = (maps_code(&enter.event, v), &self.map_refresh) {
+                        refresh.trigger();
+                    }
+                }
+            }
+            &EventKind::HeapCall(ref v) => {
+                let function = self.heap_functions.read().unwrap().get(v.ip.0);
+                let args = v.args.map(|a| a.0);
+                match function {
+                    Some(function) if !function.returns_pointer() => {
+                        let mut tracker = self.tracker.lock().unwrap();
+                        for heap_event in function.events(args, 0) {
+                            tracker.track_heap(heap_event, &event.stack);
+                        }
+                    }
+                    function => {
+                        if function.is_none() {
+                            log::debug!("unknown allocator function at {:?}", v.ip);
+                        }
+                        let calls = self.heap_calls.entry(event.header.tid()).or_default();
+                        // a call might never return, e.g. the thread is gone
+                        if calls.len() >= MAX_HEAP_CALLS {
+                            calls.remove(0);
+                        }
+                        calls.push(function.map(|function| (function, args, event.stack.clone())));
+                    }
+                }
+            }
+            // the calls nest, if the allocator calls itself through the plt
+            &EventKind::HeapReturn(ref v) => {
+                let call = self
+                    .heap_calls
+                    .get_mut(&event.header.tid())
+                    .and_then(Vec::pop);
+                if let Some(Some((function, args, stack))) = call {
+                    let mut tracker = self.tracker.lock().unwrap();
+                    for heap_event in function.events(args, v.ret.0) {
+                        tracker.track_heap(heap_event, &stack);
+                    }
+                }
+            }
+            &EventKind::AllocProbe(ref v) => {
+                let function = self.heap_functions.read().unwrap().get(v.ip.0);
+                let [a, b, c] = v.args.map(|a| a.0);
+                match function {
+                    Some(function) => {
+                        let mut tracker = self.tracker.lock().unwrap();
+                        for heap_event in function.events([a, b, c, 0], 0) {
+                            tracker.track_heap(heap_event, &event.stack);
+                        }
+                    }
+                    None => log::debug!("unknown allocator probe at {:?}", v.ip),
+                }
             }
             _ => (),
         }
         self.last = Some(event.event);
     }
 }
+
+/// The successful `mmap` of the code, the process map is stale
+fn maps_code(enter: &EventKind, exit: &SysExit) -> bool {
+    let failed = (-4095..0).contains(&exit.ret);
+    match enter {
+        EventKind::SysEnterMmap(v) | EventKind::MmapExec(MmapExec(v)) => {
+            v.nr == exit.nr && v.is_exec() && !failed
+        }
+        _ => false,
+    }
+}
+
+/// What the syscall did to the address space, `None` if it failed
+fn virtual_event(enter: &EventKind, exit: &SysExit) -> Option<VirtualEvent> {
+    const MREMAP_DONTUNMAP: u64 = 4;
+
+    let page_up = |len: u64| (len + 0xfff) & !0xfff;
+    // the error is a small negative number
+    let failed = (-4095..0).contains(&exit.ret);
+    let ret = exit.ret as u64;
+    match enter {
+        EventKind::SysEnterMmap(v) if v.nr == exit.nr && !failed => {
+            Some(VirtualEvent::Map(ret..(ret + page_up(v.len))))
+        }
+        EventKind::SysEnterMunmap(v) if v.nr == exit.nr && !failed => {
+            Some(VirtualEvent::Unmap(v.addr.0..(v.addr.0 + page_up(v.len))))
+        }
+        EventKind::SysEnterMremap(v) if v.nr == exit.nr && !failed => Some(VirtualEvent::Remap {
+            old: v.addr.0..(v.addr.0 + page_up(v.old_len)),
+            new: ret..(ret + page_up(v.new_len)),
+            keep: v.flags.0 & MREMAP_DONTUNMAP != 0,
+        }),
+        // on failure it returns the current break
+        EventKind::SysEnterBrk(v) if v.nr == exit.nr => Some(VirtualEvent::Brk(ret)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use event::{EventKind, Pod, PageAlloc, PageFaultUser, RssStat, SysEnterBrk, HeapCall, HeapReturn};
+
+    use super::Consumer;
+    use crate::{AllocationState, ProcessMap, Reporter, HeapFunction};
+
+    fn raw<T>(tid: u32, body: &[u8]) -> Vec<u8>
+    where
+        T: Pod,
+    {
+        let mut data = vec![0; 0x10];
+        data[0x04..0x08].clone_from_slice(&tid.to_ne_bytes());
+        data[0x08..0x0c].clone_from_slice(&1u32.to_ne_bytes());
+        data[0x0c..0x10].clone_from_slice(&T::DISCRIMINANT.unwrap().to_ne_bytes());
+        data.extend_from_slice(body);
+        data.resize(0x10 + T::SIZE, 0);
+        // empty stack
+        data.extend_from_slice(&0u64.to_ne_bytes());
+        data
+    }
+
+    fn alloc(tid: u32, pfn: u64) -> Vec<u8> {
+        raw::<PageAlloc>(tid, &pfn.to_ne_bytes())
+    }
+
+    fn fault(tid: u32, address: u64) -> Vec<u8> {
+        raw::<PageFaultUser>(tid, &address.to_ne_bytes())
+    }
+
+    fn rss(tid: u32) -> Vec<u8> {
+        let mut body = [0; 0x18];
+        body[0x08..0x0c].clone_from_slice(&1i32.to_ne_bytes());
+        body[0x10..0x18].clone_from_slice(&0x100i64.to_ne_bytes());
+        raw::<RssStat>(tid, &body)
+    }
+
+    fn heap_call(tid: u32, ip: u64, size: u64) -> Vec<u8> {
+        let mut regs = [0; 0x88];
+        regs[0x70..0x78].clone_from_slice(&size.to_ne_bytes());
+        regs[0x80..0x88].clone_from_slice(&ip.to_ne_bytes());
+        raw::<HeapCall>(tid, &regs)
+    }
+
+    fn heap_return(tid: u32, ret: u64) -> Vec<u8> {
+        let mut regs = [0; 0x58];
+        regs[0x50..0x58].clone_from_slice(&ret.to_ne_bytes());
+        raw::<HeapReturn>(tid, &regs)
+    }
+
+    /// The KiB at the addresses in the map and the KiB allocated not in a fault
+    fn regions(consumer: &Consumer<AllocationState>) -> (u64, u64) {
+        let map = "7f0000000000-7f0000100000 rw-p 00000000 00:00 0\n"
+            .parse::<ProcessMap>()
+            .unwrap();
+        let tracker = consumer.reporter();
+        let report = tracker.lock().unwrap().region_report(&map).unwrap();
+        let report = serde_json::to_value(report).unwrap();
+        let value = report["regions"][0]["value"].as_u64().unwrap_or(0);
+        (value, report["unknown"].as_u64().unwrap())
+    }
+
+    #[test]
+    fn alloc_after_fault() {
+        let mut consumer = Consumer::<AllocationState>::default();
+        consumer.arrive(&fault(1, 0x7f0000001234));
+        consumer.arrive(&alloc(1, 0x10));
+        // no rss event, the thread is in the syscall
+        consumer.arrive(&raw::<SysEnterBrk>(1, &[0; 0x10]));
+        consumer.arrive(&alloc(1, 0x11));
+        assert_eq!(regions(&consumer), (4, 4));
+    }
+
+    #[test]
+    fn same_rss_of_two_threads() {
+        let mut consumer = Consumer::<AllocationState>::default();
+        consumer.arrive(&fault(1, 0x7f0000001000));
+        consumer.arrive(&fault(2, 0x7f0000002000));
+        consumer.arrive(&alloc(1, 0x10));
+        consumer.arrive(&alloc(2, 0x20));
+        consumer.arrive(&rss(1));
+        // the same event, dropped as a repeat, still ends the fault
+        consumer.arrive(&rss(2));
+        assert!(matches!(consumer.last, Some(EventKind::RssStat(_))));
+        consumer.arrive(&alloc(2, 0x21));
+        assert_eq!(regions(&consumer), (8, 4));
+    }
+
+    #[test]
+    fn unknown_heap_call() {
+        let mut consumer = Consumer::<AllocationState>::default();
+        let functions = consumer.heap_functions();
+        functions
+            .write()
+            .unwrap()
+            .insert(0x1000, HeapFunction::Malloc);
+        consumer.arrive(&heap_call(1, 0x1000, 0x20));
+        // e.g. the probe fired before the function was known
+        consumer.arrive(&heap_call(1, 0x2000, 0x40));
+        consumer.arrive(&heap_return(1, 0x5000));
+        let calls = &consumer.heap_calls[&1];
+        assert!(matches!(
+            calls.as_slice(),
+            [Some((HeapFunction::Malloc, [0x20, ..], _))]
+        ));
+        consumer.arrive(&heap_return(1, 0x6000));
+        assert!(consumer.heap_calls[&1].is_empty());
+    }
+}
@@ -0,0 +1,81 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::{collections::HashMap, ops::Deref};
+
+use event::{Hex32, Hex64, Stack};
+
+use super::{Reporter, StackResolver, FrameReport, aggregator::Aggregator};
+use crate::{Tracker, Page, History, EventLast, AllocationState, Consistency, Sampling};
+
+/// Feeds every event into all the trackers, reports what the aggregator sees,
+/// the others are only compared against it
+#[derive(Default)]
+pub struct CrossCheck {
+    aggregator: Aggregator,
+    history: History<EventLast>,
+    allocation_state: AllocationState,
+}
+
+impl Tracker for CrossCheck {
+    fn track_alloc(&mut self, page: Page, stack: &Stack, flags: Hex32, pid: u32) {
+        Tracker::track_alloc(&mut self.aggregator, page, stack, flags, pid);
+        self.history.track_alloc(page, stack, flags, pid);
+        self.allocation_state.track_alloc(page, stack, flags, pid);
+    }
+
+    fn track_free(&mut self, page: Page, pid: u32) {
+        Tracker::track_free(&mut self.aggregator, page, pid);
+        self.history.track_free(page, pid);
+        self.allocation_state.track_free(page, pid);
+    }
+
+    fn mark_page_cache(&mut self, page: Page, b: bool) {
+        self.aggregator.mark_page_cache(page, b);
+        self.history.mark_page_cache(page, b);
+        self.allocation_state.mark_page_cache(page, b);
+    }
+}
+
+impl Reporter for CrossCheck {
+    fn set_sampling(&mut self, sampling: Sampling) {
+        self.aggregator.set_sampling(sampling);
+        self.history.set_sampling(sampling);
+        self.allocation_state.set_sampling(sampling);
+    }
+
+    fn short_report(&self) -> (u64, u64) {
+        self.aggregator.short_report()
+    }
+
+    fn tree_report<R>(&self, resolver: R, threshold: u64, reverse: bool) -> FrameReport<R>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        self.aggregator.tree_report(resolver, threshold, reverse)
+    }
+
+    fn stack_report(&self) -> HashMap<Vec<Hex64>, (u64, u64)> {
+        self.aggregator.stack_report()
+    }
+
+    fn consistency(&self) -> Option<Consistency> {
+        Some(Consistency::new([
+            (
+                "aggregator",
+                self.aggregator.short_report(),
+                self.aggregator.stack_report(),
+            ),
+            (
+                "history",
+                self.history.short_report(),
+                self.history.stack_report(),
+            ),
+            (
+                "allocationState",
+                self.allocation_state.short_report(),
+                self.allocation_state.stack_report(),
+            ),
+        ]))
+    }
+}
@@ -4,7 +4,16 @@
 use super::{Reporter, StackResolver, FrameReport};
 
 mod aggregator;
-pub use self::aggregator::{Aggregator, RawEvent};
+pub use self::aggregator::Aggregator;
+
+mod tracked;
+pub use self::tracked::{Tracked, RawEvent};
 
 mod consumer;
 pub use self::consumer::Consumer;
+
+mod cross_check;
+pub use self::cross_check::CrossCheck;
+
+mod snapshot;
+pub use self::snapshot::Snapshot;
@@ -0,0 +1,152 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::{collections::HashMap, ops::Deref};
+
+use event::{Hex64, Stack, PAGE_ORDERS};
+
+use super::{Reporter, StackResolver, FrameReport};
+use crate::Sampling;
+
+/// The number of allocations of each order
+type Counters = [u64; PAGE_ORDERS];
+
+/// Usage per stack aggregated by the bpf program, replaced on every poll of the maps
+#[derive(Default)]
+pub struct Snapshot {
+    usage: Vec<(Vec<Hex64>, Counters, Counters)>,
+    sampling: Sampling,
+}
+
+impl Snapshot {
+    /// Takes the stack, the number of allocations of each order
+    /// and the number of those in the page cache
+    pub fn update<I>(&mut self, usage: I)
+    where
+        I: IntoIterator<Item = (Stack, Counters, Counters)>,
+    {
+        self.usage = usage
+            .into_iter()
+            .filter(|(_, allocations, _)| allocations.iter().any(|&n| n != 0))
+            .map(|(stack, allocations, cache)| (stack.ips().to_vec(), allocations, cache))
+            .collect();
+    }
+
+    /// Each allocation weights by its size, as in the trackers of the events
+    fn kib(&self, counters: &Counters) -> u64 {
+        counters
+            .iter()
+            .enumerate()
+            .map(|(order, &n)| n * self.sampling.scale(4 << order))
+            .sum()
+    }
+}
+
+impl Reporter for Snapshot {
+    fn set_sampling(&mut self, sampling: Sampling) {
+        self.sampling = sampling;
+    }
+
+    fn short_report(&self) -> (u64, u64) {
+        let (mut value, mut cache_value) = (0, 0);
+        for (_, allocations, cache) in &self.usage {
+            value += self.kib(allocations);
+            cache_value += self.kib(cache);
+        }
+
+        (value, cache_value)
+    }
+
+    fn tree_report<R>(&self, resolver: R, threshold: u64, reverse: bool) -> FrameReport<R>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        let mut report = FrameReport::new(resolver);
+        for (stack, allocations, cache) in &self.usage {
+            let (value, cache_value) = (self.kib(allocations), self.kib(cache));
+            if reverse {
+                report.inner.insert(stack.iter().rev(), value, cache_value);
+            } else {
+                report.inner.insert(stack.iter(), value, cache_value);
+            }
+        }
+        report.inner.strip(threshold);
+
+        report
+    }
+
+    fn stack_report(&self) -> HashMap<Vec<Hex64>, (u64, u64)> {
+        self.usage
+            .iter()
+            .map(|(stack, allocations, cache)| {
+                (stack.clone(), (self.kib(allocations), self.kib(cache)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use event::{Hex64, Stack, PAGE_ORDERS};
+
+    use super::{Counters, Reporter, Snapshot, StackResolver};
+    use crate::Sampling;
+
+    /// `n` allocations of the `order`
+    fn counters(order: usize, n: u64) -> Counters {
+        let mut counters = [0; PAGE_ORDERS];
+        counters[order] = n;
+        counters
+    }
+
+    #[test]
+    fn update() {
+        let a = Stack::from_frames(&[1, 2, 3]);
+        let b = Stack::from_frames(&[1, 2, 4]);
+        let freed = Stack::from_frames(&[5]);
+
+        let mut snapshot = Snapshot::default();
+        snapshot.update(vec![
+            (a.clone(), counters(0, 3), counters(0, 1)),
+            (b, counters(0, 1), counters(0, 0)),
+            (freed, counters(0, 0), counters(0, 0)),
+        ]);
+        assert_eq!(snapshot.short_report(), (16, 4));
+
+        let stacks = snapshot.stack_report();
+        assert_eq!(stacks.len(), 2);
+        assert_eq!(stacks[&a.ips().to_vec()], (12, 4));
+
+        let resolver = StackResolver::mock();
+        let report = snapshot.tree_report(&resolver, 0, false);
+        assert_eq!((report.value(), report.cache_value()), (16, 4));
+
+        // the stacks missing in the maps are gone
+        snapshot.update(vec![(a, counters(0, 1), counters(0, 0))]);
+        assert_eq!(snapshot.short_report(), (4, 0));
+        let stacks = snapshot.stack_report();
+        assert_eq!(
+            stacks.keys().collect::<Vec<_>>(),
+            [&[Hex64(1), Hex64(2), Hex64(3)]]
+        );
+    }
+
+    #[test]
+    fn sampled_orders() {
+        let a = Stack::from_frames(&[1, 2, 3]);
+        let mut allocations = counters(2, 1);
+        allocations[0] = 2;
+        allocations[5] = 1;
+
+        let mut snapshot = Snapshot::default();
+        snapshot.update(vec![(a, allocations, counters(2, 1))]);
+        assert_eq!(snapshot.short_report(), (8 + 16 + 128, 16));
+
+        // a sampled allocation weights as the sample, unless it is bigger
+        snapshot.set_sampling(Sampling::Kib(64));
+        assert_eq!(snapshot.short_report(), (64 * 3 + 128, 64));
+
+        snapshot.set_sampling(Sampling::Every(4));
+        assert_eq!(snapshot.short_report(), ((8 + 16 + 128) * 4, 64));
+    }
+}
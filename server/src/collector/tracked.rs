@@ -0,0 +1,184 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::{collections::HashMap, ops::Deref, path::Path};
+
+use serde::{Serialize, Deserialize};
+use event::{Hex32, Hex64, Stack};
+
+use super::{Reporter, StackResolver, FrameReport};
+use crate::{
+    Tracker, Page, Sampling, LostEvents, Consistency, PageAddresses, RegionReport, ProcessMap,
+    VirtualEvent, VirtualRanges, HeapEvent, HeapAllocations,
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RawEvent {
+    Alloc { page: u32, order: u8 },
+    Free { page: u32 },
+    Cache { page: u32 },
+    UnCache { page: u32 },
+    RssAnon(u32),
+}
+
+/// Any tracker together with the conditions the events were captured in
+#[derive(Default)]
+pub struct Tracked<T> {
+    tracker: T,
+    sampling: Sampling,
+    lost: LostEvents,
+    dump: Option<Vec<RawEvent>>,
+    addresses: PageAddresses,
+    virtual_ranges: VirtualRanges,
+    heap: HeapAllocations,
+}
+
+impl<T> Tracked<T> {
+    pub fn tracker(&self) -> &T {
+        &self.tracker
+    }
+
+    pub fn tracker_mut(&mut self) -> &mut T {
+        &mut self.tracker
+    }
+
+    pub fn lost_events_mut(&mut self) -> &mut LostEvents {
+        &mut self.lost
+    }
+
+    pub fn turn_on_dump(&mut self) {
+        self.dump = Some(Vec::new());
+    }
+
+    pub fn store_dump(&mut self, path: &Path) {
+        if let Some(dump) = &self.dump {
+            log::info!("writing dump...");
+            bincode::serialize_into(std::fs::File::create(path).unwrap(), dump).unwrap();
+            log::info!("done dump");
+        }
+    }
+}
+
+impl<T> Tracker for Tracked<T>
+where
+    T: Tracker,
+{
+    fn track_alloc(&mut self, page: Page, stack: &Stack, flags: Hex32, pid: u32) {
+        if let Some(dump) = &mut self.dump {
+            dump.push(RawEvent::Alloc {
+                page: page.pfn(),
+                order: page.order(),
+            });
+        }
+        self.tracker.track_alloc(page, stack, flags, pid)
+    }
+
+    fn track_free(&mut self, page: Page, pid: u32) {
+        if let Some(dump) = &mut self.dump {
+            dump.push(RawEvent::Free { page: page.pfn() });
+        }
+        self.addresses.remove(page);
+        self.tracker.track_free(page, pid)
+    }
+
+    fn mark_page_cache(&mut self, page: Page, b: bool) {
+        if let Some(dump) = &mut self.dump {
+            if b {
+                dump.push(RawEvent::Cache { page: page.pfn() });
+            } else {
+                dump.push(RawEvent::UnCache { page: page.pfn() });
+            }
+        }
+        self.tracker.mark_page_cache(page, b)
+    }
+
+    fn track_rss_anon(&mut self, value: u32) {
+        if let Some(dump) = &mut self.dump {
+            dump.push(RawEvent::RssAnon(value));
+        }
+        self.tracker.track_rss_anon(value)
+    }
+
+    fn track_page_address(&mut self, page: Page, address: u64) {
+        self.addresses.insert(page, address);
+    }
+
+    fn track_virtual(&mut self, event: VirtualEvent, stack: &Stack) {
+        self.virtual_ranges.track(event, stack);
+    }
+
+    fn track_heap(&mut self, event: HeapEvent, stack: &Stack) {
+        self.heap.track(event, stack);
+    }
+}
+
+impl<T> Reporter for Tracked<T>
+where
+    T: Reporter,
+{
+    // the tracker weights each sampled allocation
+    fn set_sampling(&mut self, sampling: Sampling) {
+        self.sampling = sampling;
+        self.tracker.set_sampling(sampling);
+    }
+
+    fn short_report(&self) -> (u64, u64) {
+        self.tracker.short_report()
+    }
+
+    fn tree_report<R>(&self, resolver: R, threshold: u64, reverse: bool) -> FrameReport<R>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        self.tracker.tree_report(resolver, threshold, reverse)
+    }
+
+    fn stack_report(&self) -> HashMap<Vec<Hex64>, (u64, u64)> {
+        self.tracker.stack_report()
+    }
+
+    fn lost_events(&self) -> Option<&LostEvents> {
+        Some(&self.lost)
+    }
+
+    // all trackers see the same sample, no need to scale
+    fn consistency(&self) -> Option<Consistency> {
+        self.tracker.consistency()
+    }
+
+    fn region_report(&self, map: &ProcessMap) -> Option<RegionReport> {
+        let (total, _) = self.short_report();
+        Some(
+            self.addresses
+                .report(map, total, |kib| self.sampling.scale(kib)),
+        )
+    }
+
+    // the kernel does not sample the syscalls
+    fn virtual_tree_report<R>(
+        &self,
+        resolver: R,
+        threshold: u64,
+        reverse: bool,
+    ) -> Option<FrameReport<R>>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        Some(
+            self.virtual_ranges
+                .tree_report(resolver, threshold, reverse),
+        )
+    }
+
+    fn heap_tree_report<R>(
+        &self,
+        resolver: R,
+        threshold: u64,
+        reverse: bool,
+    ) -> Option<FrameReport<R>>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        Some(self.heap.tree_report(resolver, threshold, reverse))
+    }
+}
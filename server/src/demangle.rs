@@ -0,0 +1,210 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+/// The language of the function, by the mangling scheme of its symbol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    Rust,
+    Cpp,
+    /// Only detected, the name stays mangled
+    Swift,
+    Go,
+    /// Not mangled, likely C
+    Unknown,
+}
+
+/// The function name as it appears in the report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Demangled {
+    pub name: String,
+    pub language: Language,
+    /// The path of the Rust function without the hashes, e.g. `tokio::runtime::Runtime::block_on`
+    pub crate_path: Option<String>,
+}
+
+/// The Swift 4 and 5 manglings and their variants with the underscore of Mach-O
+const SWIFT_PREFIXES: &[&str] = &["$s", "$S", "$e", "_$s", "_$S", "_$e", "_T0"];
+
+/// The suffixes of the clones made by gcc and llvm, `memcpy.cold` is not a Go function
+const CLONE_SUFFIXES: &[&str] = &[
+    "cold",
+    "part",
+    "isra",
+    "constprop",
+    "lto_priv",
+    "localalias",
+    "llvm",
+    "plt",
+];
+
+pub fn demangle(symbol: &str) -> Demangled {
+    // v0, the hashes are crate disambiguators, not needed to tell the function
+    if symbol.starts_with("_R") {
+        if let Ok(demangled) = rustc_demangle::try_demangle(symbol) {
+            let name = format!("{:#}", demangled);
+            return Demangled {
+                crate_path: Some(name.clone()),
+                name,
+                language: Language::Rust,
+            };
+        }
+    }
+    // legacy, looks like C++, but ends with the hash
+    if is_rust(symbol) {
+        let demangled = rustc_demangle::demangle(symbol);
+        return Demangled {
+            name: demangled.to_string(),
+            language: Language::Rust,
+            crate_path: Some(format!("{:#}", demangled)),
+        };
+    }
+    if SWIFT_PREFIXES.iter().any(|p| symbol.starts_with(p)) {
+        // no demangler at hand, the category is still known
+        return Demangled {
+            name: symbol.to_string(),
+            language: Language::Swift,
+            crate_path: None,
+        };
+    }
+    if let Some(name) = cpp_demangle(symbol) {
+        return Demangled {
+            name,
+            language: Language::Cpp,
+            crate_path: None,
+        };
+    }
+    if is_go(symbol) {
+        return Demangled {
+            name: go_unescape(symbol),
+            language: Language::Go,
+            crate_path: None,
+        };
+    }
+    Demangled {
+        name: symbol.to_string(),
+        language: Language::Unknown,
+        crate_path: None,
+    }
+}
+
+fn cpp_demangle(s: &str) -> Option<String> {
+    cpp_demangle::Symbol::new(s)
+        .ok()?
+        .demangle(&Default::default())
+        .ok()
+}
+
+fn is_rust(s: &str) -> bool {
+    fn inner(s: &str) -> bool {
+        let s = s.trim_end_matches('E');
+        let l = s.len();
+        if l < 17 {
+            return false;
+        }
+
+        let h = s.as_bytes()[l - 17] == b'h';
+        s.as_bytes()[(l - 16)..]
+            .iter()
+            .fold(h, |h, b| h && b.is_ascii_hexdigit())
+    }
+
+    s.split_whitespace().any(inner) || s.split(".llvm").any(inner)
+}
+
+/// Go does not mangle, the symbol is the import path of the package, a dot and the function,
+/// e.g. `runtime.mallocgc` or `github.com/foo/bar.(*Server).Serve`;
+/// the go tool skips the directories starting with `_`, such a name is the compiler's,
+/// e.g. `_GLOBAL__sub_I_main.cpp` or `__cxx_global_var_init.1`
+fn is_go(s: &str) -> bool {
+    let package_start = s.rfind('/').map(|i| i + 1).unwrap_or(0);
+    let (package, function) = match s[package_start..].split_once('.') {
+        Some((package, function)) => (&s[..(package_start + package.len())], function),
+        None => return false,
+    };
+    let is_path_char = |c: char| c.is_ascii_alphanumeric() || "_-.%~/".contains(c);
+    let is_function_char = |c: char| c.is_alphanumeric() || "_.()*[]·,-".contains(c);
+    let first = function.split('.').next().unwrap_or("");
+
+    !package.is_empty()
+        && !function.is_empty()
+        && !s[package_start..].starts_with('_')
+        && package.chars().all(is_path_char)
+        && function.chars().all(is_function_char)
+        && !CLONE_SUFFIXES.contains(&first)
+        && !first.chars().all(|c| c.is_ascii_digit())
+}
+
+/// The dots in the import path are escaped as `%2e`, the old toolchains wrote `·` for the dot
+fn go_unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find('%') {
+        out.push_str(&rest[..pos]);
+        let byte = rest
+            .get((pos + 1)..(pos + 3))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match byte {
+            Some(byte) if byte.is_ascii() => {
+                out.push(byte as char);
+                rest = &rest[(pos + 3)..];
+            }
+            _ => {
+                out.push('%');
+                rest = &rest[(pos + 1)..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out.replace('·', ".")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{demangle, Language};
+
+    #[test]
+    fn languages() {
+        let legacy = demangle("_ZN5tokio7runtime7Runtime8block_on17h0123456789abcdefE");
+        assert_eq!(legacy.language, Language::Rust);
+        assert_eq!(
+            legacy.name,
+            "tokio::runtime::Runtime::block_on::h0123456789abcdef"
+        );
+        assert_eq!(
+            legacy.crate_path.as_deref(),
+            Some("tokio::runtime::Runtime::block_on")
+        );
+
+        let v0 = demangle("_RNvMNtCs4fqI2P2rA04_5tokio7runtimeNtB2_7Runtime8block_on");
+        assert_eq!(v0.language, Language::Rust);
+        assert_eq!(v0.name, "<tokio::runtime::Runtime>::block_on");
+
+        let cpp = demangle("_ZN2v88internal4Heap14CollectGarbageEv");
+        assert_eq!(cpp.language, Language::Cpp);
+        assert_eq!(cpp.name, "v8::internal::Heap::CollectGarbage()");
+        assert_eq!(cpp.crate_path, None);
+
+        let swift = demangle("$s4main3fooyyF");
+        assert_eq!(
+            (swift.language, swift.name.as_str()),
+            (Language::Swift, "$s4main3fooyyF")
+        );
+
+        let go = demangle("gopkg.in/yaml%2ev3.(*parser).parse");
+        assert_eq!(go.language, Language::Go);
+        assert_eq!(go.name, "gopkg.in/yaml.v3.(*parser).parse");
+        assert_eq!(demangle("runtime.mallocgc").language, Language::Go);
+
+        for c in [
+            "malloc",
+            "memcpy.cold",
+            "_dl_relocate_object.part.0",
+            "foo.constprop.0",
+            "_GLOBAL__sub_I_main.cpp",
+            "_GLOBAL__sub_D_server.cc",
+            "__cxx_global_var_init.1",
+        ] {
+            assert_eq!(demangle(c).language, Language::Unknown, "{}", c);
+        }
+    }
+}
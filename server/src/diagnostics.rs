@@ -0,0 +1,243 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use event::Hex64;
+
+use super::kallsyms;
+
+/// The outermost functions of a thread, a complete user stack ends in one of them
+const ENTRY_POINTS: &[&str] = &[
+    "_start",
+    "__libc_start_main",
+    "__libc_start_call_main",
+    "start_thread",
+    "clone",
+    "__clone",
+    "clone3",
+    "__clone3",
+];
+
+/// What the resolver knows about the address of a frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// The code of a mapped file, the function name is `None` without symbols
+    Code {
+        executable: String,
+        function_name: Option<String>,
+    },
+    /// Mapped, but not executable, the unwinder has read some data as the return address
+    NonExec(String),
+    /// Not mapped, or the file is not loaded
+    Unknown,
+}
+
+/// How well the stacks are unwound, the objects which seem to break the unwinding,
+/// the values are in KiB
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnwindingReport {
+    /// The distinct stacks with user frames
+    stacks: u64,
+    /// End in an entry point like `_start` or `start_thread`
+    complete: u64,
+    /// End in some other function
+    truncated: u64,
+    /// Have fewer user frames than the `short` parameter
+    short: u64,
+    /// Have a frame in a non-executable mapping
+    non_exec: u64,
+    /// Sorted by the value, the largest first
+    objects: Vec<ObjectDiagnostics>,
+}
+
+/// The stacks broken by the object
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectDiagnostics {
+    executable: String,
+    /// The stacks which end in the object not at an entry point
+    truncated: u64,
+    /// The short stacks which end in the object
+    short: u64,
+    /// The stacks where the caller of the object's frame is not executable
+    non_exec: u64,
+    /// The memory allocated at these stacks
+    value: u64,
+    /// The functions where these stacks end, and how many stacks end there
+    functions: BTreeMap<String, u64>,
+}
+
+impl UnwindingReport {
+    /// The stacks are innermost frame first, the kernel frames are skipped,
+    /// a stack with fewer than `short` user frames is short
+    pub fn new<'a, I, F>(stacks: I, short: usize, frame: F) -> Self
+    where
+        I: IntoIterator<Item = (&'a [Hex64], u64)>,
+        F: Fn(u64) -> Frame,
+    {
+        let mut report = UnwindingReport::default();
+        // the object, the function where the stack ends, the value, the kind
+        let mut findings = vec![];
+        for (ips, value) in stacks {
+            let frames = ips
+                .iter()
+                .filter(|ip| !kallsyms::is_kernel_address(ip.0))
+                .map(|ip| frame(ip.0))
+                .collect::<Vec<_>>();
+            if frames.is_empty() {
+                continue;
+            }
+            report.stacks += 1;
+
+            // the unwinder went from the frame of this object to the non-executable address
+            let non_exec = frames.windows(2).find_map(|pair| match pair {
+                [Frame::Code { executable, .. }, Frame::NonExec(_)] => Some(executable.clone()),
+                _ => None,
+            });
+            let non_exec = non_exec.or_else(|| {
+                frames
+                    .iter()
+                    .any(|f| matches!(f, Frame::NonExec(_)))
+                    .then(String::new)
+            });
+            if let Some(executable) = non_exec {
+                report.non_exec += 1;
+                findings.push((executable, None, value, Finding::NonExec));
+                continue;
+            }
+
+            let is_short = frames.len() < short;
+            // the stack of the maximal depth is cut by the kernel, not by the unwinding
+            let is_full = ips.len() >= event::STACK_MAX_DEPTH;
+            match frames.last() {
+                Some(Frame::Code {
+                    function_name: Some(name),
+                    ..
+                }) if ENTRY_POINTS.contains(&name.as_str()) => {
+                    report.complete += 1;
+                    if is_short {
+                        report.short += 1;
+                    }
+                }
+                _ if is_full => report.complete += 1,
+                _ => {
+                    report.truncated += 1;
+                    if is_short {
+                        report.short += 1;
+                    }
+                    // the unknown frames after the last known one are likely garbage
+                    let last_code = frames.iter().rev().find_map(|f| match f {
+                        Frame::Code {
+                            executable,
+                            function_name,
+                        } => Some((executable.clone(), function_name.clone())),
+                        _ => None,
+                    });
+                    let (executable, function_name) = last_code.unwrap_or_default();
+                    let finding = if is_short {
+                        Finding::Short
+                    } else {
+                        Finding::Truncated
+                    };
+                    findings.push((executable, function_name, value, finding));
+                }
+            }
+        }
+
+        let mut objects = BTreeMap::<String, ObjectDiagnostics>::new();
+        for (executable, function_name, value, finding) in findings {
+            let object = objects
+                .entry(executable.clone())
+                .or_insert_with(|| ObjectDiagnostics {
+                    executable,
+                    ..Default::default()
+                });
+            match finding {
+                Finding::NonExec => object.non_exec += 1,
+                Finding::Short => {
+                    object.truncated += 1;
+                    object.short += 1;
+                }
+                Finding::Truncated => object.truncated += 1,
+            }
+            object.value += value;
+            if let Some(name) = function_name {
+                *object.functions.entry(name).or_default() += 1;
+            }
+        }
+        report.objects = objects.into_values().collect();
+        report
+            .objects
+            .sort_by_key(|o| std::cmp::Reverse((o.value, o.truncated + o.non_exec)));
+
+        report
+    }
+}
+
+enum Finding {
+    Truncated,
+    Short,
+    NonExec,
+}
+
+#[cfg(test)]
+mod test {
+    use event::Hex64;
+
+    use super::{Frame, UnwindingReport};
+
+    #[test]
+    fn broken_objects() {
+        let frame = |ip: u64| match ip {
+            0x1000..=0x1fff => Frame::Code {
+                executable: "node".to_string(),
+                function_name: Some(format!("f{:x}", ip)),
+            },
+            0x2000 => Frame::Code {
+                executable: "libc.so.6".to_string(),
+                function_name: Some("__libc_start_call_main".to_string()),
+            },
+            0x3000..=0x3fff => Frame::Code {
+                executable: "libcrypto.so.3".to_string(),
+                function_name: Some("bn_mul".to_string()),
+            },
+            0x4000 => Frame::NonExec("[heap]".to_string()),
+            _ => Frame::Unknown,
+        };
+        let stacks = [
+            vec![0x1000, 0x1001, 0x1002, 0x2000],
+            vec![0x1000, 0x2000],
+            vec![0x1000, 0x1001, 0x3000, 0x3001],
+            vec![0x3000],
+            vec![0x1000, 0x1003, 0x4000, 0x1005],
+            vec![0xffffffff81000000],
+        ];
+        let stacks = stacks
+            .iter()
+            .map(|s| s.iter().copied().map(Hex64).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let report = UnwindingReport::new(
+            stacks.iter().map(|s| (s.as_slice(), s.len() as u64 * 100)),
+            3,
+            frame,
+        );
+
+        assert_eq!(report.stacks, 5);
+        assert_eq!(report.complete, 2);
+        assert_eq!(report.truncated, 2);
+        assert_eq!(report.short, 2);
+        assert_eq!(report.non_exec, 1);
+
+        assert_eq!(report.objects.len(), 2);
+        let crypto = &report.objects[0];
+        assert_eq!(crypto.executable, "libcrypto.so.3");
+        assert_eq!((crypto.truncated, crypto.short, crypto.value), (2, 1, 500));
+        assert_eq!(crypto.functions.get("bn_mul"), Some(&2));
+        let node = &report.objects[1];
+        assert_eq!(node.executable, "node");
+        assert_eq!((node.non_exec, node.value), (1, 400));
+    }
+}
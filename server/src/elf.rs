@@ -0,0 +1,175 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::convert::TryFrom;
+
+/// Just enough of an ELF64 little endian file to find the probes and the unwind tables
+pub struct Elf<'a> {
+    data: &'a [u8],
+    shoff: usize,
+    shentsize: usize,
+    shnum: usize,
+}
+
+pub struct Section {
+    pub ty: u32,
+    pub address: u64,
+    pub offset: usize,
+    pub size: usize,
+    pub link: usize,
+    pub entsize: usize,
+}
+
+/// A note of a `SHT_NOTE` section, the name includes the terminating zero
+pub struct Note<'a> {
+    pub name: &'a [u8],
+    pub ty: u32,
+    pub desc_offset: usize,
+    pub desc: &'a [u8],
+}
+
+impl<'a> Elf<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, String> {
+        if data.get(..6) != Some(&[0x7f, b'E', b'L', b'F', 2, 1]) {
+            return Err("not an elf64 little endian file".to_string());
+        }
+        let mut elf = Elf {
+            data,
+            shoff: 0,
+            shentsize: 0,
+            shnum: 0,
+        };
+        elf.shoff = elf.u64_at(0x28)? as usize;
+        elf.shentsize = elf.u16_at(0x3a)? as usize;
+        elf.shnum = elf.u16_at(0x3c)? as usize;
+        Ok(elf)
+    }
+
+    pub fn bytes(&self, offset: usize, size: usize) -> Result<&'a [u8], String> {
+        self.data
+            .get(offset..(offset + size))
+            .ok_or_else(|| "bad elf".to_string())
+    }
+
+    pub fn u16_at(&self, offset: usize) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(
+            TryFrom::try_from(self.bytes(offset, 2)?).unwrap(),
+        ))
+    }
+
+    pub fn u32_at(&self, offset: usize) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(
+            TryFrom::try_from(self.bytes(offset, 4)?).unwrap(),
+        ))
+    }
+
+    pub fn u64_at(&self, offset: usize) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(
+            TryFrom::try_from(self.bytes(offset, 8)?).unwrap(),
+        ))
+    }
+
+    pub fn section(&self, i: usize) -> Result<Section, String> {
+        let o = self.shoff + i * self.shentsize;
+        Ok(Section {
+            ty: self.u32_at(o + 0x04)?,
+            address: self.u64_at(o + 0x10)?,
+            offset: self.u64_at(o + 0x18)? as usize,
+            size: self.u64_at(o + 0x20)? as usize,
+            link: self.u32_at(o + 0x28)? as usize,
+            entsize: self.u64_at(o + 0x38)? as usize,
+        })
+    }
+
+    pub fn section_number(&self) -> usize {
+        self.shnum
+    }
+
+    pub fn sections(&self) -> impl Iterator<Item = Result<Section, String>> + '_ {
+        (0..self.shnum).map(move |i| self.section(i))
+    }
+
+    fn name_of_section(&self, i: usize) -> Result<&'a str, String> {
+        let names = self.section(self.u16_at(0x3e)? as usize)?;
+        let name = self.u32_at(self.shoff + i * self.shentsize)? as usize;
+        Ok(c_str(self.bytes(names.offset, names.size)?, name))
+    }
+
+    pub fn section_by_name(&self, name: &str) -> Result<Option<Section>, String> {
+        for i in 0..self.shnum {
+            if self.name_of_section(i)? == name {
+                return self.section(i).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn notes(&self) -> Result<Vec<Note<'a>>, String> {
+        let mut notes = vec![];
+        for section in self.sections() {
+            let section = section?;
+            if section.ty != SHT_NOTE {
+                continue;
+            }
+            let mut o = section.offset;
+            let end = section.offset + section.size;
+            while o + 0x0c <= end {
+                let name_size = self.u32_at(o)? as usize;
+                let desc_size = self.u32_at(o + 0x04)? as usize;
+                let ty = self.u32_at(o + 0x08)?;
+                let name = self.bytes(o + 0x0c, name_size)?;
+                let desc_offset = o + 0x0c + ((name_size + 3) & !3);
+                let desc = self.bytes(desc_offset, desc_size)?;
+                o = desc_offset + ((desc_size + 3) & !3);
+                notes.push(Note {
+                    name,
+                    ty,
+                    desc_offset,
+                    desc,
+                });
+            }
+        }
+        Ok(notes)
+    }
+
+    /// The `NT_GNU_BUILD_ID` in hex, the same for the copies of the file
+    pub fn build_id(&self) -> Result<Option<String>, String> {
+        const NT_GNU_BUILD_ID: u32 = 3;
+
+        let id = self
+            .notes()?
+            .into_iter()
+            .find(|note| note.ty == NT_GNU_BUILD_ID && note.name == b"GNU\0")
+            .map(|note| note.desc.iter().map(|b| format!("{:02x}", b)).collect());
+        Ok(id)
+    }
+
+    /// The link time address to the offset in the file
+    pub fn file_offset(&self, address: u64) -> Result<Option<u64>, String> {
+        for section in self.sections() {
+            let section = section?;
+            // occupies the memory, but not the file
+            if section.ty == SHT_NOBITS || section.address == 0 {
+                continue;
+            }
+            if (section.address..(section.address + section.size as u64)).contains(&address) {
+                return Ok(Some(address - section.address + section.offset as u64));
+            }
+        }
+        Ok(None)
+    }
+}
+
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_NOTE: u32 = 7;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHT_DYNSYM: u32 = 11;
+
+/// The zero terminated string at the offset, empty if malformed
+pub fn c_str(strings: &[u8], offset: usize) -> &str {
+    strings
+        .get(offset..)
+        .and_then(|s| s.split(|c| *c == 0).next())
+        .and_then(|s| std::str::from_utf8(s).ok())
+        .unwrap_or("")
+}
@@ -0,0 +1,306 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::{
+    history::HeapEvent,
+    elf::{c_str, Elf, SHT_DYNSYM, SHT_SYMTAB},
+};
+
+/// The allocator functions worth a uprobe, the arguments are as in glibc and jemalloc
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapFunction {
+    /// `malloc(size)`, `mallocx(size, flags)`
+    Malloc,
+    /// `calloc(n, size)`
+    Calloc,
+    /// `realloc(ptr, size)`, `rallocx(ptr, size, flags)`
+    Realloc,
+    /// `aligned_alloc(alignment, size)`, `memalign(alignment, size)`
+    AlignedAlloc,
+    /// `free(ptr)`, `sdallocx(ptr, size, flags)`, `dallocx(ptr, flags)`
+    Free,
+    /// The USDT probe `alloc(ptr, size)` of `bpf-mem-alloc`, after the allocation
+    ProbeAlloc,
+    /// The USDT probe `dealloc(ptr, size)`, before the deallocation
+    ProbeDealloc,
+    /// The USDT probe `realloc(old_ptr, ptr, size)`, after a successful reallocation
+    ProbeRealloc,
+}
+
+impl HeapFunction {
+    /// The symbols to look for, jemalloc of the `tikv-jemallocator` crate has the `_rjem_` prefix
+    pub const SYMBOLS: &'static [(&'static str, HeapFunction)] = &[
+        ("malloc", HeapFunction::Malloc),
+        ("calloc", HeapFunction::Calloc),
+        ("realloc", HeapFunction::Realloc),
+        ("aligned_alloc", HeapFunction::AlignedAlloc),
+        ("memalign", HeapFunction::AlignedAlloc),
+        ("free", HeapFunction::Free),
+        ("mallocx", HeapFunction::Malloc),
+        ("rallocx", HeapFunction::Realloc),
+        ("sdallocx", HeapFunction::Free),
+        ("dallocx", HeapFunction::Free),
+        ("_rjem_malloc", HeapFunction::Malloc),
+        ("_rjem_calloc", HeapFunction::Calloc),
+        ("_rjem_realloc", HeapFunction::Realloc),
+        ("_rjem_aligned_alloc", HeapFunction::AlignedAlloc),
+        ("_rjem_memalign", HeapFunction::AlignedAlloc),
+        ("_rjem_free", HeapFunction::Free),
+        ("_rjem_mallocx", HeapFunction::Malloc),
+        ("_rjem_rallocx", HeapFunction::Realloc),
+        ("_rjem_sdallocx", HeapFunction::Free),
+        ("_rjem_dallocx", HeapFunction::Free),
+    ];
+
+    /// The provider of the USDT probes of `bpf-mem-alloc`
+    pub const USDT_PROVIDER: &'static str = "bpf_mem";
+
+    pub const USDT: &'static [(&'static str, HeapFunction)] = &[
+        ("alloc", HeapFunction::ProbeAlloc),
+        ("dealloc", HeapFunction::ProbeDealloc),
+        ("realloc", HeapFunction::ProbeRealloc),
+    ];
+
+    pub fn by_usdt(name: &str) -> Option<Self> {
+        Self::USDT
+            .iter()
+            .find(|(probe, _)| *probe == name)
+            .map(|(_, function)| *function)
+    }
+
+    pub fn by_symbol(name: &str) -> Option<Self> {
+        Self::SYMBOLS
+            .iter()
+            .find(|(symbol, _)| *symbol == name)
+            .map(|(_, function)| *function)
+    }
+
+    /// Needs a uretprobe, the result is only known at the return
+    pub fn returns_pointer(&self) -> bool {
+        matches!(
+            self,
+            HeapFunction::Malloc
+                | HeapFunction::Calloc
+                | HeapFunction::Realloc
+                | HeapFunction::AlignedAlloc
+        )
+    }
+
+    /// What the call did, the `ret` is meaningless for `free` and the USDT probes,
+    /// they are probed only at the entry
+    pub fn events(&self, args: [u64; 4], ret: u64) -> Vec<HeapEvent> {
+        let alloc = |size| HeapEvent::Alloc { ptr: ret, size };
+        match self {
+            HeapFunction::Malloc => vec![alloc(args[0])],
+            HeapFunction::Calloc => vec![alloc(args[0].saturating_mul(args[1]))],
+            // failed, the old allocation is intact
+            HeapFunction::Realloc if ret == 0 && args[1] != 0 => vec![],
+            HeapFunction::Realloc => vec![HeapEvent::Free { ptr: args[0] }, alloc(args[1])],
+            HeapFunction::AlignedAlloc => vec![alloc(args[1])],
+            HeapFunction::Free => vec![HeapEvent::Free { ptr: args[0] }],
+            HeapFunction::ProbeAlloc => vec![HeapEvent::Alloc {
+                ptr: args[0],
+                size: args[1],
+            }],
+            HeapFunction::ProbeDealloc => vec![HeapEvent::Free { ptr: args[0] }],
+            HeapFunction::ProbeRealloc => vec![
+                HeapEvent::Free { ptr: args[0] },
+                HeapEvent::Alloc {
+                    ptr: args[1],
+                    size: args[2],
+                },
+            ],
+        }
+    }
+}
+
+/// The addresses of the probed functions and USDT probes in the target process
+#[derive(Default)]
+pub struct HeapFunctions(BTreeMap<u64, HeapFunction>);
+
+impl HeapFunctions {
+    pub fn insert(&mut self, address: u64, function: HeapFunction) {
+        self.0.insert(address, function);
+    }
+
+    pub fn remove(&mut self, address: u64) {
+        self.0.remove(&address);
+    }
+
+    /// The uprobe might report the address of the breakpoint or the next byte
+    pub fn get(&self, ip: u64) -> Option<HeapFunction> {
+        let (address, function) = self.0.range(..=ip).next_back()?;
+        (ip - address <= 1).then_some(*function)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// The file offsets of the defined functions of an ELF64 little endian file,
+/// the uprobe is attached by the offset
+pub fn function_offsets<'a, I>(data: &[u8], names: I) -> Result<HashMap<String, u64>, String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    const STT_FUNC: u8 = 2;
+
+    let elf = Elf::new(data)?;
+    let names = names.into_iter().collect::<Vec<_>>();
+    let mut offsets = HashMap::new();
+    for section in elf.sections() {
+        let section = section?;
+        if (section.ty != SHT_SYMTAB && section.ty != SHT_DYNSYM) || section.entsize == 0 {
+            continue;
+        }
+        let strtab = elf.section(section.link)?;
+        let strings = elf.bytes(strtab.offset, strtab.size)?;
+        let symbols = section.offset..(section.offset + section.size);
+        for o in symbols.step_by(section.entsize) {
+            let name = elf.u32_at(o)? as usize;
+            let info = elf.bytes(o + 0x04, 1)?[0];
+            let shndx = elf.u16_at(o + 0x06)? as usize;
+            let value = elf.u64_at(o + 0x08)?;
+            // undefined, or absolute, or common
+            if info & 0xf != STT_FUNC || shndx == 0 || shndx >= elf.section_number() {
+                continue;
+            }
+            let name = c_str(strings, name);
+            if !names.contains(&name) {
+                continue;
+            }
+            let defined_in = elf.section(shndx)?;
+            if let Some(delta) = value.checked_sub(defined_in.address) {
+                offsets.insert(name.to_string(), delta + defined_in.offset as u64);
+            }
+        }
+    }
+
+    Ok(offsets)
+}
+
+/// The names and the file offsets of the USDT probes of the provider, from the `.note.stapsdt`
+pub fn usdt_offsets(data: &[u8], provider: &str) -> Result<Vec<(String, u64)>, String> {
+    const NT_STAPSDT: u32 = 3;
+
+    let elf = Elf::new(data)?;
+    // the note has the link time address of `.stapsdt.base`, it differs if the file is prelinked
+    let base = elf.section_by_name(".stapsdt.base")?.map(|s| s.address);
+
+    let mut probes = vec![];
+    for note in elf.notes()? {
+        if note.ty != NT_STAPSDT || note.name != b"stapsdt\0" {
+            continue;
+        }
+        let pc = elf.u64_at(note.desc_offset)?;
+        let note_base = elf.u64_at(note.desc_offset + 0x08)?;
+        // semaphore, then the provider, the name and the arguments
+        if c_str(note.desc, 0x18) != provider {
+            continue;
+        }
+        let probe = c_str(note.desc, 0x18 + provider.len() + 1);
+        let pc = match base {
+            Some(base) => pc.wrapping_add(base).wrapping_sub(note_base),
+            None => pc,
+        };
+        if let Some(offset) = elf.file_offset(pc)? {
+            probes.push((probe.to_string(), offset));
+        }
+    }
+
+    Ok(probes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{function_offsets, usdt_offsets, HeapFunction, HeapFunctions};
+    use crate::HeapEvent;
+
+    fn put(data: &mut [u8], offset: usize, bytes: &[u8]) {
+        data[offset..(offset + bytes.len())].copy_from_slice(bytes);
+    }
+
+    /// The headers, the symbols, the strings and the probes, no code
+    fn elf() -> Vec<u8> {
+        let mut data = vec![0; 0x300];
+        put(&mut data, 0x00, &[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+        put(&mut data, 0x28, &0x100u64.to_le_bytes());
+        put(&mut data, 0x3a, &0x40u16.to_le_bytes());
+        put(&mut data, 0x3c, &5u16.to_le_bytes());
+        put(&mut data, 0x40, b"\0malloc\0free\0");
+        // global functions, `free` is undefined
+        for (i, (name, shndx, value)) in [(1u32, 1u16, 0x401234u64), (8, 0, 0)].iter().enumerate() {
+            let o = 0x60 + (i + 1) * 0x18;
+            put(&mut data, o, &name.to_le_bytes());
+            put(&mut data, o + 0x04, &[0x12]);
+            put(&mut data, o + 0x06, &shndx.to_le_bytes());
+            put(&mut data, o + 0x08, &value.to_le_bytes());
+        }
+        // type, address, offset, size, link, entry size
+        let sections = [
+            (1u32, 0x401000u64, 0x1000u64, 0x1000u64, 0u32, 0u64),
+            (2, 0, 0x60, 0x48, 3, 0x18),
+            (3, 0, 0x40, 0x0d, 0, 0),
+            (7, 0, 0x240, 0x70, 0, 0),
+        ];
+        for (i, (ty, address, offset, size, link, entsize)) in sections.iter().enumerate() {
+            let o = 0x100 + (i + 1) * 0x40;
+            put(&mut data, o + 0x04, &ty.to_le_bytes());
+            put(&mut data, o + 0x10, &address.to_le_bytes());
+            put(&mut data, o + 0x18, &offset.to_le_bytes());
+            put(&mut data, o + 0x20, &size.to_le_bytes());
+            put(&mut data, o + 0x28, &link.to_le_bytes());
+            put(&mut data, o + 0x38, &entsize.to_le_bytes());
+        }
+        // the probe of another provider is skipped
+        for (o, provider, pc) in [
+            (0x240, &b"bpf_mem\0alloc\0"[..], 0x401300u64),
+            (0x27c, b"other\0x\0", 0),
+        ] {
+            put(&mut data, o, &8u32.to_le_bytes());
+            put(
+                &mut data,
+                o + 0x04,
+                &(0x18 + provider.len() as u32).to_le_bytes(),
+            );
+            put(&mut data, o + 0x08, &3u32.to_le_bytes());
+            put(&mut data, o + 0x0c, b"stapsdt\0");
+            put(&mut data, o + 0x14, &pc.to_le_bytes());
+            put(&mut data, o + 0x2c, provider);
+        }
+        data
+    }
+
+    #[test]
+    fn offsets() {
+        let offsets = function_offsets(&elf(), ["malloc", "free", "calloc"]).unwrap();
+        assert_eq!(offsets.len(), 1);
+        assert_eq!(offsets["malloc"], 0x1234);
+        assert!(function_offsets(b"#!/bin/sh", ["malloc"]).is_err());
+
+        let probes = usdt_offsets(&elf(), HeapFunction::USDT_PROVIDER).unwrap();
+        assert_eq!(probes, [("alloc".to_string(), 0x1300)]);
+    }
+
+    #[test]
+    fn events() {
+        let mut functions = HeapFunctions::default();
+        functions.insert(0x1000, HeapFunction::Realloc);
+        assert_eq!(functions.get(0x1001), Some(HeapFunction::Realloc));
+        assert_eq!(functions.get(0x1002), None);
+        assert_eq!(functions.get(0xfff), None);
+
+        let realloc = HeapFunction::by_symbol("_rjem_rallocx").unwrap();
+        let alloc = |ptr, size| HeapEvent::Alloc { ptr, size };
+        assert_eq!(
+            realloc.events([0x10, 64, 0, 0], 0x20),
+            [HeapEvent::Free { ptr: 0x10 }, alloc(0x20, 64)]
+        );
+        assert!(realloc.events([0x10, 64, 0, 0], 0).is_empty());
+        let calloc = HeapFunction::Calloc.events([4, 16, 0, 0], 0x30);
+        assert_eq!(calloc, [alloc(0x30, 64)]);
+    }
+}
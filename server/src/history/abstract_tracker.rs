@@ -1,22 +1,99 @@
 // Copyright (c) Viable Systems
 // SPDX-License-Identifier: MIT
 
-use std::ops::Deref;
+use std::{collections::HashMap, ops::Deref};
 
-use event::{Hex32, Stack};
+use event::{Hex32, Hex64, Stack};
 
-use super::{page::Page, report::FrameReport, stack::StackResolver};
+use super::{
+    page::Page, report::FrameReport, lost::LostEvents, consistency::Consistency,
+    regions::RegionReport, virtual_ranges::VirtualEvent, heap::HeapEvent, stack::StackResolver,
+    memory_map::ProcessMap,
+};
+use crate::Sampling;
 
 pub trait Tracker {
     fn track_alloc(&mut self, page: Page, stack: &Stack, flags: Hex32, pid: u32);
     fn track_free(&mut self, page: Page, pid: u32);
     fn mark_page_cache(&mut self, page: Page, b: bool);
+
+    fn track_rss_anon(&mut self, value: u32) {
+        let _ = value;
+    }
+
+    /// The page was allocated in the page fault at the virtual address
+    fn track_page_address(&mut self, page: Page, address: u64) {
+        let _ = (page, address);
+    }
+
+    /// The syscall at the stack changed the address space
+    fn track_virtual(&mut self, event: VirtualEvent, stack: &Stack) {
+        let _ = (event, stack);
+    }
+
+    /// The user space allocator function called at the stack
+    fn track_heap(&mut self, event: HeapEvent, stack: &Stack) {
+        let _ = (event, stack);
+    }
 }
 
 pub trait Reporter {
+    /// The kernel sends only a sample of page allocations, the values weight each one,
+    /// must be set before tracking
+    fn set_sampling(&mut self, sampling: Sampling) {
+        let _ = sampling;
+    }
+
     fn short_report(&self) -> (u64, u64);
 
     fn tree_report<R>(&self, resolver: R, threshold: u64, reverse: bool) -> FrameReport<R>
     where
         R: Deref<Target = StackResolver>;
+
+    /// The value and the cache value in KiB of each stack
+    fn stack_report(&self) -> HashMap<Vec<Hex64>, (u64, u64)>;
+
+    /// The events missing from the report, `None` if the reporter cannot know it
+    fn lost_events(&self) -> Option<&LostEvents> {
+        None
+    }
+
+    /// How several trackers fed with the same events agree, `None` if there is only one
+    fn consistency(&self) -> Option<Consistency> {
+        None
+    }
+
+    /// The usage per region of the process map, `None` if the addresses are not tracked
+    fn region_report(&self, map: &ProcessMap) -> Option<RegionReport> {
+        let _ = map;
+        None
+    }
+
+    /// The mapped virtual memory by the stack, `None` if the syscalls are not tracked
+    fn virtual_tree_report<R>(
+        &self,
+        resolver: R,
+        threshold: u64,
+        reverse: bool,
+    ) -> Option<FrameReport<R>>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        let _ = (resolver, threshold, reverse);
+        None
+    }
+
+    /// The live user space allocations by the stack, `None` if the allocator is not probed
+    fn heap_tree_report<R>(
+        &self,
+        resolver: R,
+        threshold: u64,
+        reverse: bool,
+    ) -> Option<FrameReport<R>>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        let _ = (resolver, threshold, reverse);
+        None
+    }
 }
@@ -4,7 +4,7 @@
 use std::{collections::HashMap, ops::Deref};
 
 use serde::Serialize;
-use event::{Hex32, Stack};
+use event::{Hex32, Hex64, Stack};
 
 use super::{
     page::Page,
@@ -13,14 +13,16 @@ use super::{
     history::StackShort,
     abstract_tracker::{Tracker, Reporter},
 };
+use crate::Sampling;
 
 #[derive(Serialize, Hash, PartialEq, Eq, Clone)]
 pub struct StackHash(u32);
 
+/// The values are in KiB, each page weighted by the sampling
 #[derive(Serialize)]
 pub struct Usage {
-    node: u32,
-    cache: u32,
+    node: u64,
+    cache: u64,
     stack: StackShort,
 }
 
@@ -33,26 +35,28 @@ impl Usage {
         }
     }
 
-    pub fn decrease(&mut self, page: &Page) {
-        if self.node < page.number() {
+    pub fn decrease(&mut self, page: &Page, sampling: Sampling) {
+        let value = sampling.scale(page.size_kib());
+        if self.node < value {
             panic!();
         }
-        self.node -= page.number();
+        self.node -= value;
     }
 
-    pub fn increase(&mut self, page: &Page) {
-        self.node += page.number();
+    pub fn increase(&mut self, page: &Page, sampling: Sampling) {
+        self.node += sampling.scale(page.size_kib());
     }
 
-    pub fn cache(&mut self, page: &Page, b: bool) {
+    pub fn cache(&mut self, page: &Page, b: bool, sampling: Sampling) {
+        let value = sampling.scale(page.size_kib());
         if b {
-            self.cache += page.number();
+            self.cache += value;
         } else {
-            if self.cache < page.number() {
+            if self.cache < value {
                 self.cache = 0;
                 log::warn!("page {} was not marked as cache by mistake", page);
             } else {
-                self.cache -= page.number();
+                self.cache -= value;
             }
         }
     }
@@ -81,6 +85,8 @@ pub struct Group {
     group: HashMap<StackHash, Usage>,
     collision_detector: HashMap<StackShort, StackHash>,
     counter: u32,
+    #[serde(skip)]
+    sampling: Sampling,
 }
 
 impl Group {
@@ -88,6 +94,7 @@ impl Group {
         let &mut Group {
             ref mut collision_detector,
             ref mut counter,
+            sampling,
             ..
         } = self;
         let stack_hash = collision_detector.entry(stack.clone()).or_insert_with(|| {
@@ -107,17 +114,17 @@ impl Group {
                 // double alloc in different stack, free in this stack and proceed
                 for_cache = state.for_cache;
                 let usage = self.group.get_mut(&state.stack_hash).unwrap();
-                usage.decrease(&page);
+                usage.decrease(&page, sampling);
                 self.last_stack.remove(&page);
             }
         }
 
         // ensure `self.group` contains usage, and insert the state into `self.last_stack`
         if let Some(usage) = self.group.get_mut(&stack_hash) {
-            usage.increase(&page);
+            usage.increase(&page, sampling);
         } else {
             let mut usage = Usage::new(stack);
-            usage.increase(&page);
+            usage.increase(&page, sampling);
             self.group.insert(stack_hash.clone(), usage);
         }
         self.last_stack.insert(
@@ -132,9 +139,9 @@ impl Group {
         if let Some(state) = self.last_stack.remove(page) {
             let usage = self.group.get_mut(&state.stack_hash).unwrap();
             if state.for_cache {
-                usage.cache(page, false);
+                usage.cache(page, false, self.sampling);
             }
-            usage.decrease(page);
+            usage.decrease(page, self.sampling);
         } else {
             log::trace!("double free, or free without alloc {}", page);
         }
@@ -148,10 +155,10 @@ impl Group {
                 let usage = self.group.get_mut(&state.stack_hash).unwrap();
                 let mut page = page;
                 page.set_order(state.order);
-                usage.cache(&page, b);
+                usage.cache(&page, b, self.sampling);
                 state.for_cache = b;
                 if !b {
-                    usage.decrease(&page);
+                    usage.decrease(&page, self.sampling);
                     self.last_stack.remove(&page);
                 }
             } else {
@@ -197,11 +204,15 @@ impl Tracker for AllocationState {
 }
 
 impl Reporter for AllocationState {
+    fn set_sampling(&mut self, sampling: Sampling) {
+        self.group.sampling = sampling;
+    }
+
     fn short_report(&self) -> (u64, u64) {
         let (mut node, mut cache) = (0, 0);
         for usage in self.group.iter() {
-            node += (usage.node * 4) as u64;
-            cache += (usage.cache * 4) as u64;
+            node += usage.node;
+            cache += usage.cache;
         }
 
         (node, cache)
@@ -213,8 +224,7 @@ impl Reporter for AllocationState {
     {
         let mut report = FrameReport::new(resolver);
         for usage in self.group.iter() {
-            let value = (usage.node as u64) * 4;
-            let cache_value = (usage.cache as u64) * 4;
+            let (value, cache_value) = (usage.node, usage.cache);
 
             if reverse {
                 report
@@ -230,4 +240,16 @@ impl Reporter for AllocationState {
 
         report
     }
+
+    fn stack_report(&self) -> HashMap<Vec<Hex64>, (u64, u64)> {
+        let mut report = HashMap::new();
+        for usage in self.group.iter() {
+            if usage.node != 0 {
+                let value = (usage.node, usage.cache);
+                report.insert(usage.stack.0.as_ref().clone(), value);
+            }
+        }
+
+        report
+    }
 }
@@ -0,0 +1,120 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::Serialize;
+use event::Hex64;
+
+use super::stack::{StackResolver, SymbolInfo};
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    value: u64,
+    cache_value: u64,
+}
+
+impl From<(u64, u64)> for Usage {
+    fn from((value, cache_value): (u64, u64)) -> Self {
+        Usage { value, cache_value }
+    }
+}
+
+/// Where the trackers fed with the same events disagree, the values are in KiB
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Consistency {
+    consistent: bool,
+    totals: BTreeMap<&'static str, Usage>,
+    stacks: Vec<StackDisagreement>,
+    /// The number of disagreeing stacks omitted from `stacks`
+    omitted: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StackDisagreement {
+    stack: Vec<Hex64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    symbols: Vec<Option<SymbolInfo>>,
+    usage: BTreeMap<&'static str, Usage>,
+}
+
+impl StackDisagreement {
+    fn spread(&self) -> u64 {
+        let values = self.usage.values().map(|u| u.value);
+        values.clone().max().unwrap_or(0) - values.min().unwrap_or(0)
+    }
+}
+
+impl Consistency {
+    const MAX_STACKS: usize = 0x100;
+
+    /// Takes the name of each tracker, its short report and its usage per stack,
+    /// the stacks are sorted by the difference, the largest first
+    pub fn new<I>(reports: I) -> Self
+    where
+        I: IntoIterator<Item = (&'static str, (u64, u64), HashMap<Vec<Hex64>, (u64, u64)>)>,
+    {
+        let mut totals = BTreeMap::new();
+        let mut per_stack = vec![];
+        for (name, short, stacks) in reports {
+            totals.insert(name, Usage::from(short));
+            per_stack.push((name, stacks));
+        }
+
+        let all_stacks = per_stack
+            .iter()
+            .flat_map(|(_, stacks)| stacks.keys())
+            .collect::<HashSet<_>>();
+        let mut stacks = vec![];
+        for stack in all_stacks {
+            let usage = per_stack
+                .iter()
+                .map(|(name, stacks)| {
+                    let usage = stacks.get(stack).copied().unwrap_or_default();
+                    (*name, Usage::from(usage))
+                })
+                .collect::<BTreeMap<_, _>>();
+            let mut values = usage.values();
+            let first = values.next().copied().unwrap_or_default();
+            if values.any(|u| *u != first) {
+                stacks.push(StackDisagreement {
+                    stack: stack.clone(),
+                    symbols: vec![],
+                    usage,
+                });
+            }
+        }
+        stacks.sort_by_key(|s| std::cmp::Reverse(s.spread()));
+        let omitted = stacks.len().saturating_sub(Self::MAX_STACKS);
+        stacks.truncate(Self::MAX_STACKS);
+
+        let mut values = totals.values();
+        let first = values.next().copied().unwrap_or_default();
+        let consistent = stacks.is_empty() && omitted == 0 && values.all(|u| *u == first);
+
+        Consistency {
+            consistent,
+            totals,
+            stacks,
+            omitted,
+        }
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        self.consistent
+    }
+
+    /// Attach the symbols to the stacks
+    pub fn resolve(&mut self, resolver: &StackResolver) {
+        for disagreement in &mut self.stacks {
+            disagreement.symbols = disagreement
+                .stack
+                .iter()
+                .map(|ip| resolver.resolve(ip.0))
+                .collect();
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use regex::Regex;
+
+/// How to fold the tree, like `ignore`, `focus` and `hide` of pprof,
+/// the patterns are regexes on the demangled function names
+#[derive(Clone, Debug, Default)]
+pub struct FoldRules {
+    /// Drop the matching frames, the rest of the stack stays
+    pub drop: Option<String>,
+    /// Merge the consecutive frames of the same function
+    pub collapse_recursion: bool,
+    /// Start the tree at the first matching frame, the stacks without one are dropped
+    pub focus: Option<String>,
+    /// Hide everything below the first matching frame, e.g. `^alloc::`
+    pub leaf: Option<String>,
+}
+
+/// The compiled `FoldRules`
+pub struct Folding {
+    drop: Option<Regex>,
+    collapse_recursion: bool,
+    focus: Option<Regex>,
+    leaf: Option<Regex>,
+}
+
+impl Folding {
+    pub fn new(rules: &FoldRules) -> Result<Self, String> {
+        let compile = |pattern: &Option<String>| {
+            pattern
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .map_err(|error| error.to_string())
+        };
+        Ok(Folding {
+            drop: compile(&rules.drop)?,
+            collapse_recursion: rules.collapse_recursion,
+            focus: compile(&rules.focus)?,
+            leaf: compile(&rules.leaf)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.drop.is_none()
+            && !self.collapse_recursion
+            && self.focus.is_none()
+            && self.leaf.is_none()
+    }
+
+    /// The frames are in the order of the tree, the name is `None` if the function is unknown,
+    /// returns `None` if the focus drops the stack
+    pub fn apply<K>(&self, frames: Vec<(K, Option<&str>)>) -> Option<Vec<K>> {
+        let matches = |pattern: &Option<Regex>, name: Option<&str>| match (pattern, name) {
+            (Some(pattern), Some(name)) => pattern.is_match(name),
+            _ => false,
+        };
+
+        let mut frames = frames;
+        frames.retain(|&(_, name)| !matches(&self.drop, name));
+        if self.focus.is_some() {
+            let start = frames
+                .iter()
+                .position(|&(_, name)| matches(&self.focus, name))?;
+            frames.drain(..start);
+        }
+        if let Some(end) = frames
+            .iter()
+            .position(|&(_, name)| matches(&self.leaf, name))
+        {
+            frames.truncate(end + 1);
+        }
+        if self.collapse_recursion {
+            frames.dedup_by(|(_, name), (_, previous)| name.is_some() && name == previous);
+        }
+
+        Some(frames.into_iter().map(|(key, _)| key).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FoldRules, Folding};
+
+    #[test]
+    fn apply() {
+        let stack = [
+            "std::rt::lang_start",
+            "main",
+            "tokio::runtime::park",
+            "handle",
+            "parse",
+            "parse",
+            "parse",
+            "alloc::vec::Vec::push",
+            "alloc::raw_vec::grow",
+            "malloc",
+        ];
+        let frames = || {
+            let mut frames = stack
+                .iter()
+                .map(|&name| (name, Some(name)))
+                .collect::<Vec<_>>();
+            frames.push(("unknown", None));
+            frames
+        };
+        let pattern = |p: &str| Some(p.to_string());
+
+        let folding = Folding::new(&FoldRules::default()).unwrap();
+        assert!(folding.is_empty());
+        assert_eq!(folding.apply(frames()).unwrap().len(), stack.len() + 1);
+
+        let rules = FoldRules {
+            drop: pattern("^tokio::"),
+            collapse_recursion: true,
+            focus: pattern("^main$"),
+            leaf: pattern("^alloc::"),
+        };
+        let folding = Folding::new(&rules).unwrap();
+        assert_eq!(
+            folding.apply(frames()).unwrap(),
+            ["main", "handle", "parse", "alloc::vec::Vec::push"]
+        );
+
+        let rules = FoldRules {
+            focus: pattern("^nonexistent$"),
+            ..Default::default()
+        };
+        assert!(Folding::new(&rules).unwrap().apply(frames()).is_none());
+
+        assert!(Folding::new(&FoldRules {
+            drop: pattern("("),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
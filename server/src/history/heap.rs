@@ -0,0 +1,112 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::{collections::HashMap, ops::Deref, sync::Arc};
+
+use event::{Hex64, Stack};
+
+use super::{report::FrameReport, stack::StackResolver};
+
+/// What the allocator function did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapEvent {
+    Alloc { ptr: u64, size: u64 },
+    Free { ptr: u64 },
+}
+
+/// The live allocations of the user space allocator and the stacks which made them
+#[derive(Default)]
+pub struct HeapAllocations {
+    allocations: HashMap<u64, (u64, Arc<[Hex64]>)>,
+    /// The live bytes by the stack
+    stacks: HashMap<Arc<[Hex64]>, u64>,
+}
+
+impl HeapAllocations {
+    pub fn track(&mut self, event: HeapEvent, stack: &Stack) {
+        match event {
+            HeapEvent::Alloc { ptr: 0, .. } => (),
+            HeapEvent::Alloc { ptr, size } => {
+                // the free is lost
+                self.free(ptr);
+                let stack = match self.stacks.get_key_value(stack.ips()) {
+                    Some((stack, _)) => stack.clone(),
+                    None => Arc::from(stack.ips()),
+                };
+                *self.stacks.entry(stack.clone()).or_default() += size;
+                self.allocations.insert(ptr, (size, stack));
+            }
+            HeapEvent::Free { ptr } => self.free(ptr),
+        }
+    }
+
+    fn free(&mut self, ptr: u64) {
+        if let Some((size, stack)) = self.allocations.remove(&ptr) {
+            if let Some(value) = self.stacks.get_mut(&stack) {
+                *value -= size;
+                if *value == 0 {
+                    self.stacks.remove(&stack);
+                }
+            }
+        }
+    }
+
+    /// In bytes
+    pub fn total(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    /// The live KiB by the stack, rounded up, so every stack holding memory is visible
+    pub fn tree_report<R>(&self, resolver: R, threshold: u64, reverse: bool) -> FrameReport<R>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        let mut report = FrameReport::new(resolver);
+        for (stack, bytes) in &self.stacks {
+            let value = bytes.div_ceil(0x400);
+            if reverse {
+                report.inner.insert(stack.iter().rev(), value, 0);
+            } else {
+                report.inner.insert(stack.iter(), value, 0);
+            }
+        }
+        report.inner.strip(threshold);
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use event::Stack;
+
+    use super::{HeapAllocations, HeapEvent};
+    use crate::StackResolver;
+
+    #[test]
+    fn alloc_free() {
+        let mut heap = HeapAllocations::default();
+        let a = Stack::from_frames(&[12, 34]);
+        let b = Stack::from_frames(&[12, 56]);
+        let alloc = |ptr, size| HeapEvent::Alloc { ptr, size };
+
+        heap.track(alloc(0x1000, 100), &a);
+        heap.track(alloc(0x2000, 3000), &a);
+        heap.track(alloc(0x3000, 50), &b);
+        heap.track(alloc(0, 50), &b);
+        assert_eq!(heap.total(), 3150);
+
+        heap.track(HeapEvent::Free { ptr: 0x2000 }, &b);
+        heap.track(HeapEvent::Free { ptr: 0x4000 }, &b);
+        assert_eq!(heap.total(), 150);
+
+        // the address is reused, the free was lost
+        heap.track(alloc(0x3000, 2048), &a);
+        assert_eq!(heap.total(), 2148);
+        assert_eq!(heap.stacks.len(), 1);
+
+        let resolver = StackResolver::mock();
+        let report = heap.tree_report(&resolver, 0, false);
+        assert_eq!(report.value(), 3);
+    }
+}
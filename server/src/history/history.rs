@@ -14,6 +14,7 @@ use super::{
     stack::StackResolver,
     abstract_tracker::{Tracker, Reporter},
 };
+use crate::Sampling;
 
 #[derive(Clone, Hash, PartialEq, Eq)]
 pub struct StackShort(pub Arc<Vec<Hex64>>);
@@ -42,6 +43,8 @@ pub struct History<H> {
     error_report: ErrorReport,
     group: HashMap<StackShort, HashMap<Page, H>>,
     last_stack: HashMap<Page, StackShort>,
+    #[serde(skip)]
+    sampling: Sampling,
 }
 
 impl<H> Tracker for History<H>
@@ -126,15 +129,19 @@ impl<H> Reporter for History<H>
 where
     H: PageHistory,
 {
+    fn set_sampling(&mut self, sampling: Sampling) {
+        self.sampling = sampling;
+    }
+
     fn short_report(&self) -> (u64, u64) {
         let mut value_kib = 0;
         let mut cache_value_kib = 0;
         for (_, group) in &self.group {
             for (page, history) in group {
                 if history.is_allocated(None) {
-                    value_kib += page.size_kib();
+                    value_kib += self.sampling.scale(page.size_kib());
                     if history.page_cache() {
-                        cache_value_kib += page.size_kib();
+                        cache_value_kib += self.sampling.scale(page.size_kib());
                     }
                 }
             }
@@ -153,9 +160,9 @@ where
             let mut cache_value = 0;
             for (page, history) in group {
                 if history.is_allocated(None) {
-                    value += page.size_kib();
+                    value += self.sampling.scale(page.size_kib());
                     if history.page_cache() {
-                        cache_value += page.size_kib();
+                        cache_value += self.sampling.scale(page.size_kib());
                     }
                 }
             }
@@ -171,6 +178,26 @@ where
 
         report
     }
+
+    fn stack_report(&self) -> HashMap<Vec<Hex64>, (u64, u64)> {
+        let mut report = HashMap::new();
+        for (stack, group) in &self.group {
+            let (mut value, mut cache_value) = (0, 0);
+            for (page, history) in group {
+                if history.is_allocated(None) {
+                    value += self.sampling.scale(page.size_kib());
+                    if history.page_cache() {
+                        cache_value += self.sampling.scale(page.size_kib());
+                    }
+                }
+            }
+            if value != 0 {
+                report.insert(stack.0.as_ref().clone(), (value, cache_value));
+            }
+        }
+
+        report
+    }
 }
 
 impl<H> History<H>
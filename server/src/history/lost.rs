@@ -0,0 +1,138 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+/// Events the kernel failed to deliver, the state of the tracker is degraded
+/// while the events are being lost and shortly after, the windows tell when it happened
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LostEvents {
+    total: BTreeMap<&'static str, u64>,
+    windows: VecDeque<LostWindow>,
+    #[serde(skip)]
+    last_observation: Option<u64>,
+    #[serde(skip)]
+    window_open: bool,
+}
+
+/// Timestamps are seconds since unix epoch
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LostWindow {
+    start: u64,
+    end: u64,
+    events: BTreeMap<&'static str, u64>,
+}
+
+impl LostEvents {
+    const MAX_WINDOWS: usize = 0x100;
+    /// Seconds after the last loss the tracker is still degraded
+    const RECENT: u64 = 60;
+
+    /// Takes the number of lost events of each kind since the previous observation,
+    /// losses in consecutive observations extend the same window
+    pub fn observe<I>(&mut self, now: SystemTime, lost: I)
+    where
+        I: IntoIterator<Item = (&'static str, u64)>,
+    {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let start = self.last_observation.unwrap_or(now);
+        self.last_observation = Some(now);
+
+        let lost = lost
+            .into_iter()
+            .filter(|(_, count)| *count != 0)
+            .collect::<Vec<_>>();
+        if lost.is_empty() {
+            self.window_open = false;
+            return;
+        }
+
+        if !self.window_open || self.windows.is_empty() {
+            if self.windows.len() == Self::MAX_WINDOWS {
+                self.windows.pop_front();
+            }
+            self.windows.push_back(LostWindow {
+                start,
+                end: now,
+                events: BTreeMap::new(),
+            });
+            self.window_open = true;
+        }
+        let window = self.windows.back_mut().expect("just inserted");
+        window.end = now;
+        for (kind, count) in lost {
+            *window.events.entry(kind).or_default() += count;
+            *self.total.entry(kind).or_default() += count;
+        }
+    }
+
+    /// A loss window is open or closed recently, relative to the last observation
+    pub fn is_degraded(&self) -> bool {
+        match (self.windows.back(), self.last_observation) {
+            (Some(window), Some(now)) => {
+                self.window_open || now.saturating_sub(window.end) < Self::RECENT
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::LostEvents;
+
+    #[test]
+    fn windows() {
+        let t = |s| UNIX_EPOCH + Duration::from_secs(s);
+        let mut lost = LostEvents::default();
+        lost.observe(t(10), []);
+        assert!(!lost.is_degraded());
+
+        lost.observe(t(11), [("page_alloc", 3), ("page_free", 0)]);
+        lost.observe(t(12), [("page_alloc", 1), ("page_free", 2)]);
+        lost.observe(t(13), [("page_alloc", 0)]);
+        lost.observe(t(14), [("page_free", 5)]);
+        assert!(lost.is_degraded());
+
+        let windows = lost
+            .windows
+            .iter()
+            .map(|w| (w.start, w.end, w.events.values().sum::<u64>()))
+            .collect::<Vec<_>>();
+        assert_eq!(windows, [(10, 12, 6), (13, 14, 5)]);
+        assert_eq!(lost.total["page_alloc"], 4);
+        assert_eq!(lost.total["page_free"], 7);
+    }
+
+    #[test]
+    fn degraded_clears() {
+        let t = |s| UNIX_EPOCH + Duration::from_secs(s);
+        let mut lost = LostEvents::default();
+        lost.observe(t(10), [("page_alloc", 3)]);
+        assert!(lost.is_degraded());
+
+        // the window is closed, but recently
+        lost.observe(t(11), []);
+        lost.observe(t(69), []);
+        assert!(lost.is_degraded());
+
+        lost.observe(t(70), []);
+        assert!(!lost.is_degraded());
+        assert_eq!(lost.total["page_alloc"], 3);
+
+        lost.observe(t(100), [("page_free", 1)]);
+        assert!(lost.is_degraded());
+    }
+}
@@ -1,7 +1,7 @@
 // Copyright (c) Viable Systems
 // SPDX-License-Identifier: MIT
 
-use super::stack;
+use super::{stack, memory_map};
 
 mod abstract_tracker;
 mod page;
@@ -10,6 +10,12 @@ mod error;
 mod allocation;
 mod history;
 mod report;
+mod fold;
+mod lost;
+mod consistency;
+mod regions;
+mod virtual_ranges;
+mod heap;
 
 pub use self::abstract_tracker::{Tracker, Reporter};
 pub use self::allocation::AllocationState;
@@ -18,6 +24,12 @@ pub use self::{
     page_history::{PageHistory, EventLast},
     history::History,
     report::FrameReport,
+    fold::{FoldRules, Folding},
+    lost::LostEvents,
+    consistency::Consistency,
+    regions::{PageAddresses, RegionReport},
+    virtual_ranges::{VirtualEvent, VirtualRanges},
+    heap::{HeapEvent, HeapAllocations},
 };
 
 #[cfg(test)]
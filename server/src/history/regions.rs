@@ -0,0 +1,119 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+use event::Hex64;
+
+use super::{page::Page, memory_map::ProcessMap};
+
+/// The virtual address at which each page was faulted in
+#[derive(Default)]
+pub struct PageAddresses(HashMap<u32, (u64, u64)>);
+
+/// The tracked memory grouped by the region of the process map, the values are in KiB
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegionReport {
+    regions: Vec<RegionUsage>,
+    /// The pages at the addresses outside of the current map, unmapped after the fault
+    unmapped: u64,
+    /// The pages allocated not in a page fault, or while the faults were not captured
+    unknown: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RegionUsage {
+    start: Hex64,
+    end: Hex64,
+    name: String,
+    value: u64,
+}
+
+impl PageAddresses {
+    pub fn insert(&mut self, page: Page, address: u64) {
+        self.0.insert(page.pfn(), (address, page.size_kib()));
+    }
+
+    pub fn remove(&mut self, page: Page) {
+        self.0.remove(&page.pfn());
+    }
+
+    /// The `total` is the tracked value, each page is scaled with `scale`,
+    /// the regions are sorted by the value, the largest first
+    pub fn report<F>(&self, map: &ProcessMap, total: u64, scale: F) -> RegionReport
+    where
+        F: Fn(u64) -> u64,
+    {
+        let mut regions = BTreeMap::<_, RegionUsage>::new();
+        let mut unmapped = 0;
+        for &(address, kib) in self.0.values() {
+            match map.region(address as usize) {
+                Some((range, name)) => {
+                    regions
+                        .entry(range.start)
+                        .or_insert_with(|| RegionUsage {
+                            start: Hex64(range.start as u64),
+                            end: Hex64(range.end as u64),
+                            name,
+                            value: 0,
+                        })
+                        .value += scale(kib);
+                }
+                None => unmapped += scale(kib),
+            }
+        }
+
+        let mut regions = regions.into_values().collect::<Vec<_>>();
+        regions.sort_by_key(|r| std::cmp::Reverse(r.value));
+        let attributed = regions.iter().map(|r| r.value).sum::<u64>() + unmapped;
+
+        RegionReport {
+            regions,
+            unmapped,
+            unknown: total.saturating_sub(attributed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use event::Hex64;
+
+    use super::PageAddresses;
+    use crate::{Page, ProcessMap};
+
+    #[test]
+    fn group_by_region() {
+        let map = "\
+55d0c0a00000-55d0c0c00000 r-xp 00000000 08:01 1234 /usr/bin/node
+55d0c1000000-55d0c2000000 rw-p 00000000 00:00 0 [heap]
+7f0000000000-7f0000100000 rw-p 00000000 00:00 0
+";
+        let map = map.parse::<ProcessMap>().unwrap();
+
+        let mut addresses = PageAddresses::default();
+        let page = |pfn, order| Page::new(Hex64(pfn), order);
+        addresses.insert(page(1, 0), 0x55d0c1000000);
+        addresses.insert(page(2, 0), 0x55d0c1001000);
+        addresses.insert(page(3, 9), 0x7f0000000000);
+        addresses.insert(page(4, 0), 0x7f0000200000);
+        addresses.insert(page(5, 0), 0x55d0c1002000);
+        addresses.remove(page(5, 0));
+
+        let report = addresses.report(&map, 2100, |v| v);
+        assert_eq!(report.regions.len(), 2);
+        assert_eq!(report.regions[0].value, 2048);
+        assert_eq!(report.regions[0].name, "");
+        assert_eq!(report.regions[1].value, 8);
+        assert_eq!(report.regions[1].name, "[heap]");
+        assert_eq!(report.unmapped, 4);
+        assert_eq!(report.unknown, 40);
+
+        let report = addresses.report(&map, 0, |v| v * 2);
+        assert_eq!(report.regions[1].value, 16);
+        assert_eq!(report.unknown, 0);
+    }
+}
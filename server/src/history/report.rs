@@ -10,7 +10,10 @@ use std::{
 use event::Hex64;
 use serde::ser::{self, SerializeSeq};
 
-use super::stack::{SymbolInfo, StackResolver};
+use super::{
+    stack::{SymbolInfo, StackResolver},
+    fold::Folding,
+};
 
 #[derive(Default)]
 pub struct FrameReportInner {
@@ -90,6 +93,51 @@ impl FrameReportInner {
         self.cache_under_threshold = cache_under_threshold;
     }
 
+    /// Calls `f` with every stack of the tree and the values of the stack ending at the node
+    fn for_each_stack<F>(&self, path: &mut Vec<Hex64>, f: &mut F)
+    where
+        F: FnMut(&[Hex64], u64, u64),
+    {
+        let (mut value, mut cache_value) = (self.value, self.cache_value);
+        for (key, frame) in &self.frames {
+            value -= frame.value;
+            cache_value -= frame.cache_value;
+            path.push(*key);
+            frame.for_each_stack(path, f);
+            path.pop();
+        }
+        if value != 0 || cache_value != 0 {
+            f(path, value, cache_value);
+        }
+    }
+
+    /// Builds the tree again with the stacks folded by the function names
+    pub fn fold(&self, resolver: &StackResolver, folding: &Folding) -> Self {
+        let mut names = HashMap::new();
+        let mut folded = FrameReportInner::default();
+        self.for_each_stack(&mut vec![], &mut |stack, value, cache_value| {
+            let frames = stack
+                .iter()
+                .map(|&key| {
+                    let name = names.entry(key).or_insert_with(|| {
+                        let info = resolver.resolve(key.0);
+                        info.and_then(|info| info.function_name().map(str::to_string))
+                    });
+                    (key, name.clone())
+                })
+                .collect::<Vec<_>>();
+            let frames = frames
+                .iter()
+                .map(|(key, name)| (*key, name.as_deref()))
+                .collect();
+            if let Some(stack) = folding.apply(frames) {
+                folded.insert(stack.iter(), value, cache_value);
+            }
+        });
+
+        folded
+    }
+
     pub fn sorted(&self, resolver: &StackResolver, name: Option<SymbolInfo>) -> FrameReportSorted {
         let mut frames = BTreeMap::new();
         let mut unknown = self.value - self.under_threshold;
@@ -141,6 +189,18 @@ impl<R> FrameReport<R> {
     }
 }
 
+impl<R> FrameReport<R>
+where
+    R: Deref<Target = StackResolver>,
+{
+    /// Folds the stacks, then merges the branches under the `threshold`,
+    /// the report must be taken with zero threshold
+    pub fn fold(&mut self, folding: &Folding, threshold: u64) {
+        self.inner = self.inner.fold(&self.resolver, folding);
+        self.inner.strip(threshold);
+    }
+}
+
 impl ser::Serialize for FrameReportSorted {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -6,7 +6,7 @@ use std::collections::HashSet;
 use event::{Stack, Hex64, Hex32};
 
 use super::{Page, AllocationState, History, EventLast, Tracker, Reporter};
-use crate::{StackResolver, Aggregator};
+use crate::{StackResolver, Aggregator, Sampling, Tracked, CrossCheck, FoldRules, Folding};
 
 fn allocate_sequence<T, I, F>(history: T, pages: I, stack: F) -> T
 where
@@ -392,3 +392,102 @@ fn alloc_in_different_stacks_history() {
 fn alloc_in_different_stacks_aggregator() {
     alloc_in_different_stacks::<Aggregator>()
 }
+
+#[test]
+fn alloc_sampled_aggregator() {
+    let mut aggregator = Tracked::<Aggregator>::default();
+    aggregator.set_sampling(Sampling::Every(0x10));
+    let history = allocate_sequence(aggregator, 0..0x100, |i| (i * 7) % 0x10);
+    let resolver = StackResolver::mock();
+
+    let (value, _) = history.short_report();
+    assert_eq!(value, 0x1000 * 4);
+    let tree = history.tree_report(&resolver, 0, false);
+    assert_eq!(tree.value(), 0x1000 * 4);
+}
+
+#[test]
+fn alloc_sampled_kib_cross_check() {
+    let mut history = Tracked::<CrossCheck>::default();
+    history.set_sampling(Sampling::Kib(0x40));
+    let stack = Stack::from_frames(&[1]);
+    // 4 KiB and 16 KiB stand for 64 KiB each, 256 KiB is always sampled
+    for (pfn, order) in [(0x10, 0), (0x20, 2), (0x40, 6)] {
+        history.track_alloc(Page::new(Hex64(pfn), order), &stack, Hex32(0), 0);
+    }
+    history.mark_page_cache(Page::new(Hex64(0x10), 0), true);
+    let resolver = StackResolver::mock();
+
+    assert_eq!(history.short_report(), (0x40 + 0x40 + 0x100, 0x40));
+    assert_eq!(history.tree_report(&resolver, 0, false).value(), 0x180);
+    assert!(history.consistency().unwrap().is_consistent());
+
+    history.track_free(Page::new(Hex64(0x20), 2), 0);
+    assert_eq!(history.short_report(), (0x140, 0x40));
+    assert!(history.consistency().unwrap().is_consistent());
+}
+
+#[test]
+fn alloc_free_cross_check() {
+    let history = allocate_sequence(CrossCheck::default(), 0..0x1000, |i| (i * 7) % 0x100);
+    let history = deallocate_sequence(history, 0x600..0xa00);
+    assert!(history.consistency().unwrap().is_consistent());
+}
+
+#[test]
+fn free_in_other_process_cross_check() {
+    let mut history = allocate_sequence(CrossCheck::default(), 0..0x100, |_| 1);
+    // the allocation state ignores it, the others do not
+    history.track_free(Page::new(Hex64(0x10), 0), 1);
+    let consistency = history.consistency().unwrap();
+    assert!(!consistency.is_consistent());
+
+    let value = serde_json::to_value(&consistency).unwrap();
+    assert_eq!(value["totals"]["aggregator"]["value"], 0xff * 4);
+    assert_eq!(value["totals"]["allocationState"]["value"], 0x100 * 4);
+    assert_eq!(value["stacks"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn fold_tree() {
+    let stacks: [&[u64]; 3] = [&[1, 2, 3], &[1, 2, 2, 4], &[5]];
+    let mut history = Aggregator::default();
+    for (i, frames) in [0, 0, 1, 2].iter().map(|&s| stacks[s]).enumerate() {
+        let page = Page::new(Hex64(i as u64), 0);
+        let stack = Stack::from_frames(frames);
+        Tracker::track_alloc(&mut history, page, &stack, Hex32(0), 0);
+    }
+    let resolver = StackResolver::mock();
+
+    let rules = FoldRules {
+        drop: Some("^func_1$".to_string()),
+        collapse_recursion: true,
+        ..Default::default()
+    };
+    let mut tree = history.tree_report(&resolver, 0, false);
+    tree.fold(&Folding::new(&rules).unwrap(), 0);
+    assert_eq!(tree.value(), 16);
+
+    let value = serde_json::to_value(&tree).unwrap();
+    let name = |frame: &serde_json::Value| frame["name"]["functionName"].clone();
+    let frames = value["frames"].as_array().unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(
+        (name(&frames[0]), frames[0]["value"].clone()),
+        ("func_2".into(), 12.into())
+    );
+    assert_eq!(
+        (name(&frames[1]), frames[1]["value"].clone()),
+        ("func_5".into(), 4.into())
+    );
+    let callees = frames[0]["frames"].as_array().unwrap();
+    assert_eq!(callees.len(), 2);
+    assert_eq!(
+        (name(&callees[0]), callees[0]["value"].clone()),
+        ("func_3".into(), 8.into())
+    );
+    assert_eq!(
+        (name(&callees[1]), callees[1]["value"].clone()),
+        ("func_4".into(), 4.into())
+    );
+}
@@ -0,0 +1,214 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::{Deref, Range},
+    sync::Arc,
+};
+
+use event::{Hex64, Stack};
+
+use super::{report::FrameReport, stack::StackResolver};
+
+/// The effect of a successful `mmap`, `munmap`, `mremap` or `brk` on the address space
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VirtualEvent {
+    Map(Range<u64>),
+    Unmap(Range<u64>),
+    /// The old range stays mapped if `keep` is set
+    Remap {
+        old: Range<u64>,
+        new: Range<u64>,
+        keep: bool,
+    },
+    /// The new program break
+    Brk(u64),
+}
+
+/// The live virtual ranges of the process and the stacks which mapped them
+#[derive(Default)]
+pub struct VirtualRanges {
+    ranges: BTreeMap<u64, VirtualRange>,
+    brk: Option<u64>,
+}
+
+struct VirtualRange {
+    end: u64,
+    stack: Arc<Vec<Hex64>>,
+}
+
+impl VirtualRanges {
+    pub fn track(&mut self, event: VirtualEvent, stack: &Stack) {
+        match event {
+            VirtualEvent::Map(range) => self.map(range, Arc::new(stack.ips().to_vec())),
+            VirtualEvent::Unmap(range) => self.unmap(range),
+            VirtualEvent::Remap { old, new, keep } => {
+                // the memory still belongs to the stack which mapped it first
+                let stack = self
+                    .ranges
+                    .range(..=old.start)
+                    .next_back()
+                    .filter(|(_, r)| r.end > old.start)
+                    .map(|(_, r)| r.stack.clone())
+                    .unwrap_or_else(|| Arc::new(stack.ips().to_vec()));
+                if !keep {
+                    self.unmap(old);
+                }
+                self.map(new, stack);
+            }
+            VirtualEvent::Brk(brk) => {
+                // the first call only tells where the heap is
+                match self.brk {
+                    Some(end) if brk > end => self.map(end..brk, Arc::new(stack.ips().to_vec())),
+                    Some(end) if brk < end => self.unmap(brk..end),
+                    _ => (),
+                }
+                self.brk = Some(brk);
+            }
+        }
+    }
+
+    fn map(&mut self, range: Range<u64>, stack: Arc<Vec<Hex64>>) {
+        if range.is_empty() {
+            return;
+        }
+        // `MAP_FIXED` replaces whatever was there
+        self.unmap(range.clone());
+        self.ranges.insert(
+            range.start,
+            VirtualRange {
+                end: range.end,
+                stack,
+            },
+        );
+    }
+
+    fn unmap(&mut self, range: Range<u64>) {
+        // the ranges don't overlap, so their ends are sorted as well as their starts
+        let overlapping = self
+            .ranges
+            .range(..range.end)
+            .rev()
+            .take_while(|(_, r)| r.end > range.start)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+        for start in overlapping {
+            let r = self.ranges.remove(&start).expect("just found");
+            if start < range.start {
+                let stack = r.stack.clone();
+                let end = range.start;
+                self.ranges.insert(start, VirtualRange { end, stack });
+            }
+            if r.end > range.end {
+                self.ranges.insert(range.end, r);
+            }
+        }
+    }
+
+    /// In KiB
+    pub fn total(&self) -> u64 {
+        self.ranges
+            .iter()
+            .map(|(start, r)| (r.end - start) / 1024)
+            .sum()
+    }
+
+    /// The mapped KiB by the stack, there is no cache value
+    pub fn tree_report<R>(&self, resolver: R, threshold: u64, reverse: bool) -> FrameReport<R>
+    where
+        R: Deref<Target = StackResolver>,
+    {
+        let mut per_stack = HashMap::<&[Hex64], u64>::new();
+        for (start, r) in &self.ranges {
+            *per_stack.entry(&r.stack).or_default() += (r.end - start) / 1024;
+        }
+
+        let mut report = FrameReport::new(resolver);
+        for (stack, value) in per_stack {
+            if reverse {
+                report.inner.insert(stack.iter().rev(), value, 0);
+            } else {
+                report.inner.insert(stack.iter(), value, 0);
+            }
+        }
+        report.inner.strip(threshold);
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use event::Stack;
+
+    use super::{VirtualEvent, VirtualRanges};
+    use crate::StackResolver;
+
+    #[test]
+    fn map_unmap() {
+        let mut ranges = VirtualRanges::default();
+        let a = Stack::from_frames(&[12, 34]);
+        let b = Stack::from_frames(&[12, 56]);
+        let starts = |ranges: &VirtualRanges| {
+            ranges
+                .ranges
+                .iter()
+                .map(|(start, r)| (*start, r.end))
+                .collect::<Vec<_>>()
+        };
+
+        ranges.track(VirtualEvent::Map(0x10000..0x20000), &a);
+        ranges.track(VirtualEvent::Map(0x30000..0x40000), &b);
+        assert_eq!(ranges.total(), 128);
+
+        // a hole in the middle of the first
+        ranges.track(VirtualEvent::Unmap(0x14000..0x18000), &a);
+        assert_eq!(
+            starts(&ranges),
+            [(0x10000, 0x14000), (0x18000, 0x20000), (0x30000, 0x40000)]
+        );
+
+        // over the end of the first and the start of the second
+        ranges.track(VirtualEvent::Map(0x1c000..0x34000), &b);
+        assert_eq!(
+            starts(&ranges),
+            [
+                (0x10000, 0x14000),
+                (0x18000, 0x1c000),
+                (0x1c000, 0x34000),
+                (0x34000, 0x40000)
+            ]
+        );
+        assert_eq!(ranges.total(), 176);
+
+        // moved, still belongs to `a`
+        let remap = VirtualEvent::Remap {
+            old: 0x10000..0x14000,
+            new: 0x50000..0x60000,
+            keep: false,
+        };
+        ranges.track(remap, &b);
+        assert_eq!(ranges.total(), 224);
+
+        ranges.track(VirtualEvent::Brk(0x100000), &b);
+        ranges.track(VirtualEvent::Brk(0x110000), &b);
+        ranges.track(VirtualEvent::Brk(0x108000), &b);
+        assert_eq!(ranges.total(), 256);
+
+        let resolver = StackResolver::mock();
+        let report = ranges.tree_report(&resolver, 0, false);
+        assert_eq!(report.value(), 256);
+        assert_eq!(report.cache_value(), 0);
+        let value = serde_json::to_value(ranges.tree_report(&resolver, 0, true)).unwrap();
+        let frames = value["frames"].as_array().unwrap();
+        let value_of = |name: &str| {
+            frames
+                .iter()
+                .find(|f| f["name"]["functionName"] == name)
+                .map(|f| f["value"].as_u64().unwrap())
+        };
+        assert_eq!(value_of("func_34"), Some(80));
+        assert_eq!(value_of("func_56"), Some(176));
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::{fs, io};
+
+/// The lowest address of the kernel half of the address space on x86_64
+const KERNEL_SPACE_START: u64 = 0xffff_8000_0000_0000;
+
+pub fn is_kernel_address(address: u64) -> bool {
+    address >= KERNEL_SPACE_START
+}
+
+/// Text symbols of the running kernel and its modules, read from `/proc/kallsyms`
+pub struct KernelSymbols {
+    // index 0 is the kernel itself, the rest are modules
+    executables: Vec<String>,
+    // the lowest symbol address of each executable
+    bases: Vec<u64>,
+    symbols: Vec<KernelSymbol>,
+}
+
+struct KernelSymbol {
+    address: u64,
+    executable: usize,
+    name: String,
+}
+
+impl KernelSymbols {
+    pub fn load() -> io::Result<Self> {
+        let s = fs::read_to_string("/proc/kallsyms")?;
+        let table = Self::parse(&s);
+        if table.is_empty() {
+            // the addresses are all zero without `CAP_SYSLOG` or with `kptr_restrict`
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "kallsyms addresses are hidden",
+            ));
+        }
+
+        Ok(table)
+    }
+
+    fn parse(s: &str) -> Self {
+        let mut executables = vec!["linux".to_string()];
+        let mut symbols = vec![];
+        for line in s.lines() {
+            let mut columns = line.split_ascii_whitespace();
+            let (address, ty, name) = match (columns.next(), columns.next(), columns.next()) {
+                (Some(address), Some(ty), Some(name)) => (address, ty, name),
+                _ => continue,
+            };
+            if !matches!(ty, "t" | "T" | "w" | "W") {
+                continue;
+            }
+            let address = match u64::from_str_radix(address, 16) {
+                Ok(address) if address != 0 => address,
+                _ => continue,
+            };
+            let executable = match columns.next() {
+                Some(module) => {
+                    let module = format!("linux/{}", module.trim_matches(|c| c == '[' || c == ']'));
+                    match executables.iter().position(|e| *e == module) {
+                        Some(index) => index,
+                        None => {
+                            executables.push(module);
+                            executables.len() - 1
+                        }
+                    }
+                }
+                None => 0,
+            };
+            symbols.push(KernelSymbol {
+                address,
+                executable,
+                name: name.to_string(),
+            });
+        }
+        symbols.sort_by_key(|s| s.address);
+
+        let mut bases = vec![u64::MAX; executables.len()];
+        for symbol in &symbols {
+            bases[symbol.executable] = bases[symbol.executable].min(symbol.address);
+        }
+
+        KernelSymbols {
+            executables,
+            bases,
+            symbols,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the offset of the address from the beginning of the executable,
+    /// the name of the executable and the name of the function
+    pub fn find(&self, address: u64) -> Option<(usize, &str, String)> {
+        let pos = self.symbols.partition_point(|s| s.address <= address);
+        let symbol = self.symbols.get(pos.checked_sub(1)?)?;
+        let base = *self.bases.get(symbol.executable)?;
+        let executable = self.executables.get(symbol.executable)?;
+
+        Some((
+            (address - base) as usize,
+            executable.as_str(),
+            symbol.name.clone(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KernelSymbols, is_kernel_address};
+
+    #[test]
+    fn parse_and_find() {
+        let table = KernelSymbols::parse(
+            "\
+0000000000000000 A fixed_percpu_data
+ffffffff81000000 T _stext
+ffffffff81001000 t do_page_fault
+ffffffff81002000 D some_data
+ffffffffc0001000 t nv_alloc [nvidia]
+",
+        );
+        assert_eq!(table.len(), 3);
+        assert!(is_kernel_address(0xffffffff81001010));
+        assert!(!is_kernel_address(0x7fff00001000));
+
+        let (offset, executable, name) = table.find(0xffffffff81001010).unwrap();
+        assert_eq!(
+            (offset, executable, name.as_str()),
+            (0x1010, "linux", "do_page_fault")
+        );
+
+        let (offset, executable, name) = table.find(0xffffffffc0001004).unwrap();
+        assert_eq!(
+            (offset, executable, name.as_str()),
+            (4, "linux/nvidia", "nv_alloc")
+        );
+
+        assert!(table.find(0xffffffff80000000).is_none());
+    }
+}
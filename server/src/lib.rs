@@ -4,6 +4,9 @@
 #![forbid(unsafe_code)]
 
 mod memory_map;
+pub use self::memory_map::ProcessMap;
+
+mod proc_memory;
 
 mod state;
 pub use self::state::{AtomicState, Reporter as StateReporter};
@@ -11,14 +14,50 @@ pub use self::state::{AtomicState, Reporter as StateReporter};
 mod history;
 pub use self::history::{
     Page, History, AllocationState, FrameReport, EventLast, Tracker, Reporter, PageHistory,
+    LostEvents, Consistency, PageAddresses, RegionReport, VirtualEvent, VirtualRanges, HeapEvent,
+    HeapAllocations, FoldRules, Folding,
 };
 
 mod stack;
-pub use self::stack::StackResolver;
+pub use self::stack::{StackResolver, MapRefresh};
+
+mod diagnostics;
+pub use self::diagnostics::UnwindingReport;
 
 mod table;
 
+mod demangle;
+
+mod category;
+pub use self::category::{Categories, CategoryRule};
+
+mod symbolize;
+pub use self::symbolize::Symbolizer;
+
+mod kallsyms;
+
+mod perf_map;
+
+mod elf;
+
+mod unwind;
+
+mod heap_probes;
+pub use self::heap_probes::{HeapFunction, HeapFunctions, function_offsets, usdt_offsets};
+
+mod sampling;
+pub use self::sampling::Sampling;
+
 pub mod server;
 
+mod timeline;
+pub use self::timeline::TimelineConfig;
+
+mod stream;
+pub use self::stream::{EventStream, StreamEvent};
+
+mod recording;
+pub use self::recording::{Recorder, Recording};
+
 mod collector;
-pub use self::collector::{Consumer, Aggregator, RawEvent};
+pub use self::collector::{Consumer, Aggregator, RawEvent, Snapshot, Tracked, CrossCheck};
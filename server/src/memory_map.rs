@@ -8,14 +8,20 @@ use std::{
     io::{self, Read},
     fs::File,
     path::PathBuf,
+    collections::BTreeMap,
 };
 
+use serde::Serialize;
+use event::Hex64;
+
 #[derive(Default, Clone, PartialEq, Eq)]
 pub struct ProcessMap(Vec<MemoryMapEntry>);
 
 impl ProcessMap {
     pub fn new(pid: u32) -> io::Result<Self> {
-        MemoryMapEntry::new(pid).map(ProcessMap)
+        let mut entries = String::new();
+        File::open(&format!("/proc/{}/maps", pid))?.read_to_string(&mut entries)?;
+        entries.parse()
     }
 
     pub fn files(&self) -> Vec<String> {
@@ -25,24 +31,88 @@ impl ProcessMap {
             .collect()
     }
 
+    /// Only the executable regions of the files
+    pub fn code(&self) -> Self {
+        let code = self
+            .0
+            .iter()
+            .filter(|entry| entry.exec() && matches!(entry.name, EntryName::FileName(_)));
+        ProcessMap(code.cloned().collect())
+    }
+
+    /// The mapped files with their ids, each file once
+    pub fn file_ids(&self) -> BTreeMap<String, FileId> {
+        self.0
+            .iter()
+            .filter_map(|entry| Some((entry.name.string()?, entry.id)))
+            .collect()
+    }
+
+    /// The range of the first mapping of the file
+    pub fn file_range(&self, file: &str) -> Option<Range<usize>> {
+        self.0
+            .iter()
+            .find(|entry| entry.name.string().as_deref() == Some(file))
+            .map(|entry| entry.range.clone())
+    }
+
     pub fn find(&self, ip: usize) -> Option<(String, usize)> {
         self.0.iter().find_map(|entry| {
             if !entry.range.contains(&ip) {
                 return None;
             }
 
-            let name = entry.name.clone();
+            // see `non_exec`
             if !entry.exec() {
-                //log::warn!("have non-exec pointer in stacktrace {:016x}@{:?}", ip, name);
                 return None;
             }
 
-            let s = name.string()?;
+            let s = entry.name.string()?;
 
             let Wrapping(ptr) = Wrapping(entry.offset) + Wrapping(ip) - Wrapping(entry.range.start);
             Some((s, ptr))
         })
     }
+
+    /// The name of the mapping if the address is mapped, but not executable,
+    /// such an address in a stack means the unwinding went wrong
+    pub fn non_exec(&self, ip: usize) -> Option<String> {
+        let entry = self.0.iter().find(|entry| entry.range.contains(&ip))?;
+        (!entry.exec()).then(|| entry.name.display())
+    }
+
+    /// Where the code at the offset of the file is mapped, the inverse of `find`
+    pub fn address(&self, file: &str, offset: usize) -> Option<usize> {
+        self.0.iter().find_map(|entry| {
+            if !entry.exec() || entry.name.string().as_deref() != Some(file) {
+                return None;
+            }
+            let size = entry.range.end - entry.range.start;
+            let offsets = entry.offset..(entry.offset + size);
+            offsets
+                .contains(&offset)
+                .then(|| entry.range.start + offset - entry.offset)
+        })
+    }
+
+    /// The range of the region containing the address and its name,
+    /// the name is empty for anonymous mappings
+    pub fn region(&self, address: usize) -> Option<(Range<usize>, String)> {
+        let entry = self.0.iter().find(|entry| entry.range.contains(&address))?;
+        Some((entry.range.clone(), entry.name.display()))
+    }
+}
+
+impl FromStr for ProcessMap {
+    type Err = io::Error;
+
+    /// Parses the content of `/proc/<pid>/maps`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.lines()
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(ProcessMap)
+    }
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -50,9 +120,18 @@ struct MemoryMapEntry {
     range: Range<usize>,
     flags: String,
     offset: usize,
+    id: FileId,
     name: EntryName,
 }
 
+/// The device and the inode of the mapped file, a file replaced at the same path
+/// gets another inode, zero for anonymous mappings
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId {
+    pub dev: (u32, u32),
+    pub inode: u64,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum EntryName {
     Nothing,
@@ -75,20 +154,18 @@ impl EntryName {
             _ => None,
         }
     }
-}
-
-impl MemoryMapEntry {
-    fn new(pid: u32) -> Result<Vec<Self>, io::Error> {
-        let mut entries = String::new();
-        File::open(&format!("/proc/{}/maps", pid))?.read_to_string(&mut entries)?;
 
-        let mut map = vec![];
-        for line in entries.lines() {
-            map.push(line.parse()?);
+    /// The path or the remark like `[heap]`, empty for anonymous mappings
+    fn display(&self) -> String {
+        match self {
+            EntryName::Nothing => String::new(),
+            EntryName::FileName(path) => path.display().to_string(),
+            EntryName::Remark(remark) => remark.clone(),
         }
-        Ok(map)
     }
+}
 
+impl MemoryMapEntry {
     fn exec(&self) -> bool {
         self.flags.contains('x')
     }
@@ -116,8 +193,17 @@ impl FromStr for MemoryMapEntry {
         let offset_str = columns.next().ok_or(io::ErrorKind::Other)?;
         let offset = usize::from_str_radix(offset_str, 16).map_err(|_| io::ErrorKind::Other)?;
 
-        let _ = columns.next().ok_or(io::ErrorKind::Other)?;
-        let _ = columns.next().ok_or(io::ErrorKind::Other)?;
+        let dev_str = columns.next().ok_or(io::ErrorKind::Other)?;
+        let dev = {
+            let mut dev_items = dev_str.split(':');
+            let major = dev_items.next().ok_or(io::ErrorKind::Other)?;
+            let minor = dev_items.next().ok_or(io::ErrorKind::Other)?;
+            let major = u32::from_str_radix(major, 16).map_err(|_| io::ErrorKind::Other)?;
+            let minor = u32::from_str_radix(minor, 16).map_err(|_| io::ErrorKind::Other)?;
+            (major, minor)
+        };
+        let inode_str = columns.next().ok_or(io::ErrorKind::Other)?;
+        let inode = inode_str.parse().map_err(|_| io::ErrorKind::Other)?;
 
         let name = match columns.next() {
             None => EntryName::Nothing,
@@ -139,7 +225,204 @@ impl FromStr for MemoryMapEntry {
             range,
             flags,
             offset,
+            id: FileId { dev, inode },
             name,
         })
     }
 }
+
+/// A mapping with the memory the kernel accounts to it, values are in KiB
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MappingUsage {
+    start: Hex64,
+    end: Hex64,
+    flags: String,
+    name: String,
+    kind: MappingKind,
+    rss: u64,
+    pss: u64,
+    swap: u64,
+    anonymous: u64,
+    private_dirty: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MappingKind {
+    Heap,
+    Stack,
+    Anon,
+    File,
+    Other,
+}
+
+/// The sum over the mappings of the same kind
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KindUsage {
+    count: usize,
+    rss: u64,
+    pss: u64,
+    swap: u64,
+    anonymous: u64,
+}
+
+impl KindUsage {
+    pub fn anonymous(&self) -> u64 {
+        self.anonymous
+    }
+}
+
+impl MappingUsage {
+    /// Reads `/proc/<pid>/smaps`, it walks the page tables, so it is not cheap
+    pub fn read(pid: u32) -> io::Result<Vec<Self>> {
+        let mut smaps = String::new();
+        File::open(format!("/proc/{}/smaps", pid))?.read_to_string(&mut smaps)?;
+        Self::parse(&smaps)
+    }
+
+    pub fn parse(smaps: &str) -> io::Result<Vec<Self>> {
+        let mut mappings = vec![];
+        for line in smaps.lines() {
+            let mut columns = line.split_ascii_whitespace();
+            let first = match columns.next() {
+                Some(first) => first,
+                None => continue,
+            };
+            // the fields are like `Rss:  12 kB`, the header of the mapping is like in `maps`
+            if let Some(key) = first.strip_suffix(':') {
+                let mapping: &mut MappingUsage = match mappings.last_mut() {
+                    Some(mapping) => mapping,
+                    None => return Err(io::ErrorKind::InvalidData.into()),
+                };
+                let value = columns.next().and_then(|v| v.parse().ok()).unwrap_or(0);
+                match key {
+                    "Rss" => mapping.rss = value,
+                    "Pss" => mapping.pss = value,
+                    "Swap" => mapping.swap = value,
+                    "Anonymous" => mapping.anonymous = value,
+                    "Private_Dirty" => mapping.private_dirty = value,
+                    _ => (),
+                }
+            } else {
+                let entry = line.parse::<MemoryMapEntry>()?;
+                let (kind, name) = match entry.name {
+                    EntryName::Nothing => (MappingKind::Anon, String::new()),
+                    EntryName::FileName(path) => (MappingKind::File, path.display().to_string()),
+                    EntryName::Remark(remark) => {
+                        let kind = if remark == "[heap]" {
+                            MappingKind::Heap
+                        } else if remark.starts_with("[stack") {
+                            MappingKind::Stack
+                        } else if remark.starts_with("[anon") {
+                            MappingKind::Anon
+                        } else {
+                            MappingKind::Other
+                        };
+                        (kind, remark)
+                    }
+                };
+                mappings.push(MappingUsage {
+                    start: Hex64(entry.range.start as u64),
+                    end: Hex64(entry.range.end as u64),
+                    flags: entry.flags,
+                    name,
+                    kind,
+                    rss: 0,
+                    pss: 0,
+                    swap: 0,
+                    anonymous: 0,
+                    private_dirty: 0,
+                });
+            }
+        }
+
+        Ok(mappings)
+    }
+
+    pub fn rss(&self) -> u64 {
+        self.rss
+    }
+
+    pub fn summary(mappings: &[Self]) -> BTreeMap<MappingKind, KindUsage> {
+        let mut summary = BTreeMap::<_, KindUsage>::new();
+        for mapping in mappings {
+            let usage = summary.entry(mapping.kind).or_default();
+            usage.count += 1;
+            usage.rss += mapping.rss;
+            usage.pss += mapping.pss;
+            usage.swap += mapping.swap;
+            usage.anonymous += mapping.anonymous;
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MappingUsage, MappingKind, ProcessMap};
+
+    #[test]
+    fn address() {
+        let map = "\
+7f0000000000-7f0000028000 r--p 00000000 08:01 1234 /usr/lib/libc.so.6
+7f0000028000-7f00001bd000 r-xp 00028000 08:01 1234 /usr/lib/libc.so.6
+";
+        let map = map.parse::<ProcessMap>().unwrap();
+        let address = map.address("/usr/lib/libc.so.6", 0x9a0c0).unwrap();
+        assert_eq!(address, 0x7f000009a0c0);
+        assert_eq!(
+            map.find(address),
+            Some(("/usr/lib/libc.so.6".to_string(), 0x9a0c0))
+        );
+        assert_eq!(map.address("/usr/lib/libc.so.6", 0x100), None);
+        assert_eq!(map.address("/usr/lib/libm.so.6", 0x9a0c0), None);
+        assert_eq!(map.non_exec(address), None);
+        assert_eq!(
+            map.non_exec(0x7f0000001000),
+            Some("/usr/lib/libc.so.6".to_string())
+        );
+
+        assert_eq!(
+            map.file_range("/usr/lib/libc.so.6"),
+            Some(0x7f0000000000..0x7f0000028000)
+        );
+
+        let code = map.code();
+        assert_eq!(code.files(), ["/usr/lib/libc.so.6"]);
+        assert_eq!(code.find(address), map.find(address));
+        assert_eq!(code.region(0x7f0000001000), None);
+    }
+
+    #[test]
+    fn parse_smaps() {
+        let smaps = "\
+55d0c0a00000-55d0c0c00000 r-xp 00000000 08:01 1234 /usr/bin/node
+Rss:                1024 kB
+Pss:                 512 kB
+Anonymous:             0 kB
+VmFlags: rd ex mr mw me dw
+55d0c1000000-55d0c2000000 rw-p 00000000 00:00 0 [heap]
+Rss:                4096 kB
+Anonymous:          4096 kB
+Private_Dirty:      4096 kB
+7f0000000000-7f0000100000 rw-p 00000000 00:00 0
+Rss:                 256 kB
+Anonymous:           256 kB
+Swap:                 16 kB
+7ffd2d3d1000-7ffd2d3f2000 rw-p 00000000 00:00 0 [stack]
+Rss:                  12 kB
+";
+        let mappings = MappingUsage::parse(smaps).unwrap();
+        assert_eq!(mappings.len(), 4);
+        assert_eq!(mappings[0].name, "/usr/bin/node");
+        assert_eq!(mappings[1].private_dirty, 4096);
+
+        let summary = MappingUsage::summary(&mappings);
+        assert_eq!(summary[&MappingKind::File].pss, 512);
+        assert_eq!(summary[&MappingKind::Heap].anonymous, 4096);
+        assert_eq!(summary[&MappingKind::Anon].swap, 16);
+        assert_eq!(summary[&MappingKind::Stack].rss, 12);
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::{fs, io};
+
+/// The executable of the symbols from the perf map
+const JIT_EXECUTABLE: &str = "jit";
+
+/// The symbols of the code generated at runtime, the JIT writes them to
+/// `/tmp/perf-<pid>.map` for perf, a line is `START SIZE name` in hex;
+/// in a container both the `/tmp` and the pid are of the container
+pub struct JitSymbols {
+    // the lowest symbol address
+    base: u64,
+    symbols: Vec<JitSymbol>,
+}
+
+struct JitSymbol {
+    start: u64,
+    end: u64,
+    name: String,
+}
+
+impl JitSymbols {
+    /// The map in the root of the process, named by the pid the process sees
+    pub fn path(pid: u32) -> String {
+        let ns_pid = fs::read_to_string(format!("/proc/{}/status", pid))
+            .ok()
+            .and_then(|status| ns_pid(&status))
+            .unwrap_or(pid);
+        format!("/proc/{}/root/tmp/perf-{}.map", pid, ns_pid)
+    }
+
+    pub fn load(pid: u32) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(Self::path(pid))?))
+    }
+
+    fn parse(s: &str) -> Self {
+        let mut symbols = vec![];
+        for line in s.lines() {
+            let mut columns = line.splitn(3, ' ');
+            let (start, size, name) = match (columns.next(), columns.next(), columns.next()) {
+                (Some(start), Some(size), Some(name)) => (start, size, name),
+                _ => continue,
+            };
+            let hex = |s: &str| u64::from_str_radix(s.trim_start_matches("0x"), 16);
+            let (start, size) = match (hex(start), hex(size)) {
+                (Ok(start), Ok(size)) if size != 0 => (start, size),
+                _ => continue,
+            };
+            symbols.push(JitSymbol {
+                start,
+                end: start + size,
+                name: name.trim().to_string(),
+            });
+        }
+        // the code might be regenerated at the same address, the later line wins
+        symbols.reverse();
+        symbols.sort_by_key(|s| s.start);
+        symbols.dedup_by_key(|s| s.start);
+
+        JitSymbols {
+            base: symbols.first().map(|s| s.start).unwrap_or(0),
+            symbols,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Returns the offset of the address from the lowest symbol,
+    /// the name of the executable and the name of the function
+    pub fn find(&self, address: u64) -> Option<(usize, &str, String)> {
+        let pos = self.symbols.partition_point(|s| s.start <= address);
+        let symbol = self.symbols.get(pos.checked_sub(1)?)?;
+        if address >= symbol.end {
+            return None;
+        }
+
+        Some((
+            (address - self.base) as usize,
+            JIT_EXECUTABLE,
+            symbol.name.clone(),
+        ))
+    }
+}
+
+/// The pid in the innermost pid namespace, the last of `NSpid` in `/proc/<pid>/status`
+fn ns_pid(status: &str) -> Option<u32> {
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("NSpid:"))?;
+    line.split_whitespace().last()?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ns_pid, JitSymbols};
+
+    #[test]
+    fn container_pid() {
+        let status = "Name:\tnode\nTgid:\t41230\nPid:\t41230\nNSpid:\t41230\t1877\t7\n";
+        assert_eq!(ns_pid(status), Some(7));
+        assert_eq!(ns_pid("Pid:\t41230\nNSpid:\t41230\n"), Some(41230));
+        // before linux 4.1
+        assert_eq!(ns_pid("Pid:\t41230\n"), None);
+    }
+
+    #[test]
+    fn parse_and_find() {
+        let table = JitSymbols::parse(
+            "\
+7f3a40001000 40 LazyCompile:~main /app/index.js:1
+7f3a40001100 0x80 Builtin:ArgumentsAdaptorTrampoline
+7f3a40001000 20 LazyCompile:*main /app/index.js:1
+garbage
+",
+        );
+        assert_eq!(table.len(), 2);
+
+        let (offset, executable, name) = table.find(0x7f3a40001010).unwrap();
+        assert_eq!(
+            (offset, executable, name.as_str()),
+            (0x10, "jit", "LazyCompile:*main /app/index.js:1")
+        );
+        assert!(table.find(0x7f3a40001020).is_none());
+        assert!(table.find(0x7f3a40001180).is_none());
+        assert_eq!(
+            table.find(0x7f3a4000117f).unwrap().2,
+            "Builtin:ArgumentsAdaptorTrampoline"
+        );
+        assert!(table.find(0x7f3a40000fff).is_none());
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::{collections::HashMap, fs, io};
+
+use serde::Serialize;
+
+/// The kernel's view of the memory of the process, all values are in KiB
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcMemory {
+    pub status: Status,
+    /// Absent if the kernel is older than 4.14
+    pub smaps_rollup: Option<SmapsRollup>,
+    pub statm: Statm,
+}
+
+/// From `/proc/<pid>/status`
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Status {
+    pub vm_peak: u64,
+    pub vm_size: u64,
+    pub vm_hwm: u64,
+    pub vm_rss: u64,
+    pub rss_anon: u64,
+    pub rss_file: u64,
+    pub rss_shmem: u64,
+    pub vm_data: u64,
+    pub vm_stk: u64,
+    pub vm_exe: u64,
+    pub vm_lib: u64,
+    pub vm_pte: u64,
+    pub vm_swap: u64,
+}
+
+/// From `/proc/<pid>/smaps_rollup`
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SmapsRollup {
+    pub rss: u64,
+    pub pss: u64,
+    pub pss_anon: u64,
+    pub pss_file: u64,
+    pub pss_shmem: u64,
+    pub shared_clean: u64,
+    pub shared_dirty: u64,
+    pub private_clean: u64,
+    pub private_dirty: u64,
+    pub anonymous: u64,
+    pub swap: u64,
+    pub swap_pss: u64,
+}
+
+/// From `/proc/<pid>/statm`, converted from pages
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Statm {
+    pub size: u64,
+    pub resident: u64,
+    pub shared: u64,
+    pub text: u64,
+    pub data: u64,
+}
+
+/// Parses lines like `VmRSS:  123456 kB`
+fn fields(s: &str) -> HashMap<&str, u64> {
+    s.lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            let value = value.split_whitespace().next()?.parse().ok()?;
+            Some((key.trim(), value))
+        })
+        .collect()
+}
+
+impl Status {
+    pub fn parse(s: &str) -> Self {
+        let f = fields(s);
+        let get = |key| f.get(key).copied().unwrap_or(0);
+        Status {
+            vm_peak: get("VmPeak"),
+            vm_size: get("VmSize"),
+            vm_hwm: get("VmHWM"),
+            vm_rss: get("VmRSS"),
+            rss_anon: get("RssAnon"),
+            rss_file: get("RssFile"),
+            rss_shmem: get("RssShmem"),
+            vm_data: get("VmData"),
+            vm_stk: get("VmStk"),
+            vm_exe: get("VmExe"),
+            vm_lib: get("VmLib"),
+            vm_pte: get("VmPTE"),
+            vm_swap: get("VmSwap"),
+        }
+    }
+}
+
+impl SmapsRollup {
+    pub fn parse(s: &str) -> Self {
+        let f = fields(s);
+        let get = |key| f.get(key).copied().unwrap_or(0);
+        SmapsRollup {
+            rss: get("Rss"),
+            pss: get("Pss"),
+            pss_anon: get("Pss_Anon"),
+            pss_file: get("Pss_File"),
+            pss_shmem: get("Pss_Shmem"),
+            shared_clean: get("Shared_Clean"),
+            shared_dirty: get("Shared_Dirty"),
+            private_clean: get("Private_Clean"),
+            private_dirty: get("Private_Dirty"),
+            anonymous: get("Anonymous"),
+            swap: get("Swap"),
+            swap_pss: get("SwapPss"),
+        }
+    }
+}
+
+impl Statm {
+    pub fn parse(s: &str) -> Self {
+        // size resident shared text lib data dt, in pages
+        let mut pages = s
+            .split_whitespace()
+            .map(|n| n.parse::<u64>().unwrap_or(0) * 4);
+        let mut next = || pages.next().unwrap_or(0);
+        let (size, resident, shared, text, _lib, data) =
+            (next(), next(), next(), next(), next(), next());
+        Statm {
+            size,
+            resident,
+            shared,
+            text,
+            data,
+        }
+    }
+}
+
+impl ProcMemory {
+    pub fn read(pid: u32) -> io::Result<Self> {
+        let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+        let smaps_rollup = fs::read_to_string(format!("/proc/{}/smaps_rollup", pid)).ok();
+        let statm = fs::read_to_string(format!("/proc/{}/statm", pid))?;
+        Ok(ProcMemory {
+            status: Status::parse(&status),
+            smaps_rollup: smaps_rollup.as_deref().map(SmapsRollup::parse),
+            statm: Statm::parse(&statm),
+        })
+    }
+
+    /// How much anonymous memory the kernel accounts to the process beyond what we track,
+    /// negative if we track more
+    pub fn unattributed(&self, anon: u64) -> i64 {
+        self.status.rss_anon as i64 - anon as i64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Status, SmapsRollup, Statm};
+
+    #[test]
+    fn parse() {
+        let status = "Name:\tnode\nVmHWM:\t  204800 kB\nVmRSS:\t  102400 kB\n\
+            RssAnon:\t   81920 kB\nRssFile:\t   20480 kB\nVmSwap:\t       0 kB\nThreads:\t8\n";
+        let status = Status::parse(status);
+        assert_eq!(status.vm_hwm, 204800);
+        assert_eq!(status.rss_anon, 81920);
+        assert_eq!(status.rss_file, 20480);
+        assert_eq!(status.rss_shmem, 0);
+
+        let rollup = "55d0c0a00000-7ffd2d3f2000 ---p 00000000 00:00 0    [rollup]\n\
+            Rss:              102400 kB\nPss:               90000 kB\n\
+            Pss_Anon:          81920 kB\nPrivate_Dirty:     81000 kB\n";
+        let rollup = SmapsRollup::parse(rollup);
+        assert_eq!(rollup.pss, 90000);
+        assert_eq!(rollup.pss_anon, 81920);
+        assert_eq!(rollup.private_dirty, 81000);
+
+        let statm = Statm::parse("1000 500 100 10 0 300 0\n");
+        assert_eq!(statm.size, 4000);
+        assert_eq!(statm.resident, 2000);
+        assert_eq!(statm.data, 1200);
+    }
+}
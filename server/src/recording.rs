@@ -0,0 +1,92 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::io::{self, Read, Write};
+
+/// Identifies the file format, the last two bytes are the version
+const MAGIC: [u8; 8] = *b"BPFMEM01";
+
+/// Writes the records of the ring buffer as they come from the kernel,
+/// each is prefixed with its length as little endian `u32`
+pub struct Recorder<W> {
+    inner: W,
+}
+
+impl<W> Recorder<W>
+where
+    W: Write,
+{
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(&MAGIC)?;
+        Ok(Recorder { inner })
+    }
+
+    pub fn record(&mut self, data: &[u8]) -> io::Result<()> {
+        self.inner.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.inner.write_all(data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the records written by `Recorder`
+pub struct Recording<R> {
+    inner: R,
+    buffer: Vec<u8>,
+}
+
+impl<R> Recording<R>
+where
+    R: Read,
+{
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        inner.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a bpf-mem recording",
+            ));
+        }
+        Ok(Recording {
+            inner,
+            buffer: vec![],
+        })
+    }
+
+    /// Returns `None` at the end of the file, a truncated last record is an error
+    pub fn next_record(&mut self) -> io::Result<Option<&[u8]>> {
+        let mut len = [0; 4];
+        match self.inner.read_exact(&mut len) {
+            Ok(()) => (),
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error),
+        }
+        self.buffer.resize(u32::from_le_bytes(len) as usize, 0);
+        self.inner.read_exact(&mut self.buffer)?;
+        Ok(Some(&self.buffer))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Recorder, Recording};
+
+    #[test]
+    fn write_read() {
+        let mut recorder = Recorder::new(vec![]).unwrap();
+        recorder.record(b"first").unwrap();
+        recorder.record(b"").unwrap();
+        recorder.record(&[7; 0x100]).unwrap();
+
+        let mut recording = Recording::new(recorder.inner.as_slice()).unwrap();
+        assert_eq!(recording.next_record().unwrap(), Some(&b"first"[..]));
+        assert_eq!(recording.next_record().unwrap(), Some(&[][..]));
+        assert_eq!(recording.next_record().unwrap(), Some(&[7; 0x100][..]));
+        assert_eq!(recording.next_record().unwrap(), None);
+
+        assert!(Recording::new(&b"something else"[..]).is_err());
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+/// How the bpf program samples page allocations, the reports extrapolate the usage
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum Sampling {
+    #[default]
+    All,
+    /// One in `n` allocations
+    Every(u32),
+    /// One allocation per `n` KiB allocated
+    Kib(u32),
+}
+
+impl Sampling {
+    /// How many KiB the sampled allocation of `size_kib` KiB stands for, the weight of
+    /// the sample is `max(n, s) / s` in KiB mode, the allocations of `n` KiB or more
+    /// are always sampled
+    pub fn scale(&self, size_kib: u64) -> u64 {
+        match *self {
+            Sampling::All => size_kib,
+            Sampling::Every(n) => size_kib * (n as u64).max(1),
+            Sampling::Kib(n) => size_kib.max(n as u64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Sampling;
+
+    #[test]
+    fn scale() {
+        assert_eq!(Sampling::All.scale(16), 16);
+        assert_eq!(Sampling::Every(0x10).scale(4), 0x40);
+        assert_eq!(Sampling::Every(0).scale(4), 4);
+
+        // one page in 10 KiB stands for 10 KiB, not truncated to 8
+        assert_eq!(Sampling::Kib(10).scale(4), 10);
+        // not flat across the orders
+        assert_eq!(Sampling::Kib(64).scale(4), 64);
+        assert_eq!(Sampling::Kib(64).scale(16), 64);
+        assert_eq!(Sampling::Kib(64).scale(64), 64);
+        assert_eq!(Sampling::Kib(64).scale(256), 256);
+    }
+}
@@ -7,8 +7,13 @@ use std::{
         atomic::{Ordering, AtomicU32},
         Mutex, RwLock,
     },
-    fs::File,
+    fmt,
+    fs::{self, File},
     io::{Error, BufReader, BufRead},
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime},
 };
 use warp::{
     Filter, Rejection, Reply,
@@ -16,42 +21,212 @@ use warp::{
     http::StatusCode,
 };
 use serde::{Serialize, Deserialize};
-use super::{StackResolver, Reporter};
+use super::{
+    StackResolver, Reporter, LostEvents, FoldRules, Folding,
+    stream::{EventStream, StreamFilter},
+    timeline::{Timeline, TimelineConfig},
+    proc_memory::ProcMemory,
+    memory_map::{MappingUsage, ProcessMap},
+};
+
+/// Where and how to serve the http api
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub listen: Listen,
+    pub tls: Option<Tls>,
+    /// If set, every request must carry `Authorization: Bearer <token>` header
+    pub token: Option<String>,
+    pub timeline: TimelineConfig,
+    /// The default folding of `/v1/tree`, the query overrides it
+    pub tree: FoldRules,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Listen::Tcp(([0, 0, 0, 0], 17832).into())
+    }
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    /// Either `unix:<path>`, or an ipv4 or ipv6 socket address like `127.0.0.1:17832`, `[::1]:17832`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Listen::Unix(path.into())),
+            None => s
+                .parse()
+                .map(Listen::Tcp)
+                .map_err(|error| format!("bad listen address {}: {}", s, error)),
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "{}", addr),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The paths to pem files
+#[derive(Clone, Debug)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
 
 pub fn run<T>(
+    config: &Config,
     reporter: Arc<Mutex<T>>,
     resolver: Arc<RwLock<StackResolver>>,
     pid: Arc<AtomicU32>,
-) -> (tokio::task::JoinHandle<()>, tokio::runtime::Runtime)
+    stream: EventStream,
+) -> Result<(tokio::task::JoinHandle<()>, tokio::runtime::Runtime), String>
 where
     T: Reporter + Send + 'static,
 {
     let runtime = tokio::runtime::Runtime::new().unwrap();
-    let server = routes(reporter, resolver, pid.clone());
-    let handler = runtime.spawn(warp::serve(server).run(([0, 0, 0, 0], 17832)));
-    (handler, runtime)
+    let timeline = Arc::new(Mutex::new(Timeline::new(config.timeline.capacity)));
+    runtime.spawn(sample_timeline(
+        config.timeline.interval,
+        timeline.clone(),
+        reporter.clone(),
+        pid.clone(),
+    ));
+    let server = warp::serve(routes(
+        config.token.clone(),
+        config.tree.clone(),
+        reporter,
+        resolver,
+        pid.clone(),
+        stream,
+        timeline,
+    ));
+    let handler = match (&config.listen, &config.tls) {
+        (&Listen::Tcp(addr), None) => runtime.spawn(server.run(addr)),
+        (&Listen::Tcp(addr), Some(tls)) => {
+            let read = |path: &PathBuf| {
+                fs::read(path)
+                    .map_err(|error| format!("failed to read {}: {}", path.display(), error))
+            };
+            let (cert, key) = (read(&tls.cert)?, read(&tls.key)?);
+            // parse the pem files and bind here, the server must not fail in the task
+            let _guard = runtime.enter();
+            let (_, server) = server
+                .tls()
+                .cert(cert)
+                .key(key)
+                .try_bind_with_graceful_shutdown(addr, std::future::pending())
+                .map_err(|error| format!("failed to serve tls on {}: {}", addr, error))?;
+            runtime.spawn(server)
+        }
+        (Listen::Unix(path), None) => {
+            use std::os::unix::fs::FileTypeExt;
+            use tokio::net::UnixListener;
+            use tokio_stream::wrappers::UnixListenerStream;
+
+            // the socket file is left from the previous run, never remove anything else
+            if let Ok(metadata) = fs::symlink_metadata(path) {
+                if !metadata.file_type().is_socket() {
+                    return Err(format!("{} exists and is not a socket", path.display()));
+                }
+                fs::remove_file(path)
+                    .map_err(|error| format!("failed to remove {}: {}", path.display(), error))?;
+            }
+            let _guard = runtime.enter();
+            let listener = UnixListener::bind(path)
+                .map_err(|error| format!("failed to bind {}: {}", path.display(), error))?;
+            runtime.spawn(server.run_incoming(UnixListenerStream::new(listener)))
+        }
+        (Listen::Unix(_), Some(_)) => return Err("tls is not supported on unix socket".to_string()),
+    };
+    Ok((handler, runtime))
 }
 
 fn routes<T>(
+    token: Option<String>,
+    tree_rules: FoldRules,
     reporter: Arc<Mutex<T>>,
     resolver: Arc<RwLock<StackResolver>>,
     pid: Arc<AtomicU32>,
+    stream: EventStream,
+    timeline: Arc<Mutex<Timeline>>,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone + Sync + Send + 'static
 where
     T: Reporter + Send + 'static,
 {
     use warp::reply::with;
 
+    let json = tree(reporter.clone(), resolver.clone(), pid.clone(), tree_rules)
+        .or(consistency(reporter.clone(), resolver.clone()))
+        .or(get_timeline(timeline))
+        .or(mappings(reporter.clone(), pid.clone()))
+        .or(regions(reporter.clone(), pid.clone()))
+        .or(unwinding(reporter.clone(), resolver.clone()))
+        .or(get_pid(pid))
+        .or(openapi())
+        .with(with::header("Content-Type", "application/json"));
+
     warp::get()
-        .and(
-            tree(reporter, resolver, pid.clone())
-                .or(get_pid(pid))
-                .or(openapi()),
-        )
-        .with(with::header("Content-Type", "application/json"))
+        .and(authorization(token))
+        .and(events_stream(reporter, resolver, stream).or(json))
+        .recover(unauthorized)
         .with(with::header("Access-Control-Allow-Origin", "*"))
 }
 
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+fn authorization(
+    token: Option<String>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone + Sync + Send + 'static {
+    // compare in constant time, not to reveal the token by timing
+    fn eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let authorized = match &token {
+                None => true,
+                Some(token) => header
+                    .as_deref()
+                    .and_then(|h| h.strip_prefix("Bearer "))
+                    .is_some_and(|h| eq(h.as_bytes(), token.as_bytes())),
+            };
+            async move {
+                if authorized {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+async fn unauthorized(rejection: Rejection) -> Result<WithStatus<Json>, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(reply::with_status(
+            reply::json(&"unauthorized"),
+            StatusCode::UNAUTHORIZED,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
 fn get_pid(
     p: Arc<AtomicU32>,
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
@@ -80,56 +255,406 @@ fn rss_anon(p: Arc<AtomicU32>) -> Result<u64, Error> {
     Ok(v)
 }
 
+async fn sample_timeline<T>(
+    interval: Duration,
+    timeline: Arc<Mutex<Timeline>>,
+    reporter: Arc<Mutex<T>>,
+    pid: Arc<AtomicU32>,
+) where
+    T: Reporter,
+{
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let short = reporter.lock().unwrap().short_report();
+        let system_report_anon = rss_anon(pid.clone()).unwrap_or(0);
+        timeline
+            .lock()
+            .unwrap()
+            .push(SystemTime::now(), short, system_report_anon);
+    }
+}
+
+fn get_timeline(
+    timeline: Arc<Mutex<Timeline>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
+    #[derive(Deserialize)]
+    struct Params {
+        since: Option<u64>,
+    }
+
+    warp::path!("v1" / "timeline")
+        .and(warp::query::query())
+        .map(move |params: Params| -> WithStatus<Json> {
+            let samples = timeline.lock().unwrap().since(params.since.unwrap_or(0));
+            reply::with_status(reply::json(&samples), StatusCode::OK)
+        })
+}
+
+/// The memory of each mapping next to the memory we track
+fn mappings<T>(
+    reporter: Arc<Mutex<T>>,
+    pid: Arc<AtomicU32>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    T: Reporter + Send + 'static,
+{
+    use std::{collections::BTreeMap, time::Instant};
+    use super::memory_map::{MappingKind, KindUsage};
+
+    // reading smaps is expensive, do it at most this often
+    const MAX_AGE: Duration = Duration::from_secs(5);
+
+    #[derive(Deserialize)]
+    struct Params {
+        threshold: Option<u64>,
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Mappings<'a> {
+        summary: BTreeMap<MappingKind, KindUsage>,
+        tracked: Tracked,
+        /// The `Anonymous` of all mappings minus our `anon`
+        unattributed: i64,
+        mappings: Vec<&'a MappingUsage>,
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Tracked {
+        total: u64,
+        cache: u64,
+        anon: u64,
+    }
+
+    let last = Arc::new(Mutex::new(None::<(Instant, u32, Vec<MappingUsage>)>));
+
+    warp::path!("v1" / "mappings")
+        .and(warp::query::query())
+        .map(move |params: Params| -> WithStatus<Json> {
+            let pid = pid.load(Ordering::Relaxed);
+            let mut last = last.lock().unwrap();
+            let fresh =
+                matches!(&*last, Some((time, p, _)) if *p == pid && time.elapsed() < MAX_AGE);
+            if !fresh {
+                match MappingUsage::read(pid) {
+                    Ok(mappings) => *last = Some((Instant::now(), pid, mappings)),
+                    Err(error) => {
+                        let error = format!("failed to read smaps of {}: {}", pid, error);
+                        return reply::with_status(reply::json(&error), StatusCode::NOT_FOUND);
+                    }
+                }
+            }
+            let (_, _, mappings) = last.as_ref().expect("just read");
+
+            let (total, cache) = reporter.lock().unwrap().short_report();
+            let summary = MappingUsage::summary(mappings);
+            let anonymous = summary.values().map(|u| u.anonymous()).sum::<u64>();
+            let threshold = params.threshold.unwrap_or(0);
+            let mut mappings = mappings
+                .iter()
+                .filter(|m| m.rss() >= threshold)
+                .collect::<Vec<_>>();
+            mappings.sort_by_key(|m| std::cmp::Reverse(m.rss()));
+            let report = Mappings {
+                summary,
+                tracked: Tracked {
+                    total,
+                    cache,
+                    anon: total - cache,
+                },
+                unattributed: anonymous as i64 - (total - cache) as i64,
+                mappings,
+            };
+            reply::with_status(reply::json(&report), StatusCode::OK)
+        })
+}
+
+/// The tracked memory by the region of the process map where the page was faulted in
+fn regions<T>(
+    reporter: Arc<Mutex<T>>,
+    pid: Arc<AtomicU32>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    T: Reporter + Send + 'static,
+{
+    warp::path!("v1" / "regions")
+        .and(warp::query::query())
+        .map(move |()| -> WithStatus<Json> {
+            let pid = pid.load(Ordering::Relaxed);
+            let map = match ProcessMap::new(pid) {
+                Ok(map) => map,
+                Err(error) => {
+                    let error = format!("failed to read maps of {}: {}", pid, error);
+                    return reply::with_status(reply::json(&error), StatusCode::NOT_FOUND);
+                }
+            };
+            match reporter.lock().unwrap().region_report(&map) {
+                Some(report) => reply::with_status(reply::json(&report), StatusCode::OK),
+                None => reply::with_status(
+                    reply::json(&"the tracker does not know the addresses"),
+                    StatusCode::NOT_FOUND,
+                ),
+            }
+        })
+}
+
+/// The objects which seem to break the unwinding of the tracked stacks
+fn unwinding<T>(
+    reporter: Arc<Mutex<T>>,
+    resolver: Arc<RwLock<StackResolver>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    T: Reporter + Send + 'static,
+{
+    #[derive(Deserialize)]
+    struct Params {
+        short: Option<usize>,
+    }
+
+    warp::path!("v1" / "diagnostics" / "unwinding")
+        .and(warp::query::query())
+        .map(move |params: Params| -> WithStatus<Json> {
+            let stacks = reporter.lock().unwrap().stack_report();
+            let short = params.short.unwrap_or(3);
+            let report = resolver.read().unwrap().unwinding_report(&stacks, short);
+            reply::with_status(reply::json(&report), StatusCode::OK)
+        })
+}
+
 fn tree<T>(
     history: Arc<Mutex<T>>,
     resolver: Arc<RwLock<StackResolver>>,
     pid: Arc<AtomicU32>,
+    rules: FoldRules,
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
 where
     T: Reporter + Send + 'static,
 {
     #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
     struct Params {
         threshold: Option<u64>,
         reverse: Option<bool>,
         short: Option<bool>,
+        kind: Option<Kind>,
+        drop: Option<String>,
+        collapse_recursion: Option<bool>,
+        focus: Option<String>,
+        leaf: Option<String>,
+    }
+
+    #[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+    #[serde(rename_all = "camelCase")]
+    enum Kind {
+        /// The pages
+        Physical,
+        /// The ranges mapped by the syscalls
+        Virtual,
+        /// The live allocations of the user space allocator
+        Heap,
     }
 
     #[derive(Serialize)]
     #[serde(rename_all = "camelCase")]
-    struct ShortReport {
+    struct ShortReport<'a> {
         total: u64,
         cache: u64,
         anon: u64,
         system_report_anon: u64,
+        /// The kernel's `RssAnon` minus our `anon`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        unattributed: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        system: Option<ProcMemory>,
+        degraded: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        lost_events: Option<&'a LostEvents>,
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct TreeReport<'a, T> {
+        #[serde(flatten)]
+        tree: T,
+        degraded: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        lost_events: Option<&'a LostEvents>,
     }
 
     warp::path!("v1" / "tree").and(warp::query::query()).map(
         move |params: Params| -> WithStatus<Json> {
             let resolver = resolver.read().unwrap();
             let history = history.lock().unwrap();
-            if params.short.unwrap_or(false) {
+            let lost_events = history.lost_events().filter(|l| l.is_degraded());
+            let degraded = lost_events.is_some();
+            let kind = params.kind.unwrap_or(Kind::Physical);
+            // the short report is only about the pages
+            if params.short.unwrap_or(false) && kind == Kind::Physical {
                 let (total, cache) = history.short_report();
                 let system_report_anon = rss_anon(pid.clone()).unwrap_or(0);
+                let system = ProcMemory::read(pid.load(Ordering::Relaxed)).ok();
                 let report = ShortReport {
                     total,
                     cache,
                     anon: total - cache,
                     system_report_anon,
+                    unattributed: system.as_ref().map(|s| s.unattributed(total - cache)),
+                    system,
+                    degraded,
+                    lost_events,
                 };
                 reply::with_status(reply::json(&report), StatusCode::OK)
             } else {
-                let report = history.tree_report(
-                    resolver,
-                    params.threshold.unwrap_or(512),
-                    params.reverse.unwrap_or(false),
-                );
-                reply::with_status(reply::json(&report), StatusCode::OK)
+                let threshold = params.threshold.unwrap_or(512);
+                let reverse = params.reverse.unwrap_or(false);
+                // the query overrides the config
+                let rules = FoldRules {
+                    drop: params.drop.or_else(|| rules.drop.clone()),
+                    collapse_recursion: params
+                        .collapse_recursion
+                        .unwrap_or(rules.collapse_recursion),
+                    focus: params.focus.or_else(|| rules.focus.clone()),
+                    leaf: params.leaf.or_else(|| rules.leaf.clone()),
+                };
+                let folding = match Folding::new(&rules) {
+                    Ok(folding) => folding,
+                    Err(error) => {
+                        return reply::with_status(reply::json(&error), StatusCode::BAD_REQUEST)
+                    }
+                };
+                // the folded tree is stripped after folding
+                let strip = if folding.is_empty() { threshold } else { 0 };
+                let tree = match kind {
+                    Kind::Physical => Some(history.tree_report(resolver, strip, reverse)),
+                    Kind::Virtual => history.virtual_tree_report(resolver, strip, reverse),
+                    Kind::Heap => history.heap_tree_report(resolver, strip, reverse),
+                };
+                let tree = tree.map(|mut tree| {
+                    if !folding.is_empty() {
+                        tree.fold(&folding, threshold);
+                    }
+                    tree
+                });
+                match tree {
+                    Some(tree) => {
+                        let report = TreeReport {
+                            tree,
+                            degraded,
+                            lost_events,
+                        };
+                        reply::with_status(reply::json(&report), StatusCode::OK)
+                    }
+                    None => reply::with_status(
+                        reply::json(&"the tracker does not know this kind of memory"),
+                        StatusCode::NOT_FOUND,
+                    ),
+                }
             }
         },
     )
 }
 
+fn consistency<T>(
+    reporter: Arc<Mutex<T>>,
+    resolver: Arc<RwLock<StackResolver>>,
+) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    T: Reporter + Send + 'static,
+{
+    warp::path!("v1" / "consistency")
+        .and(warp::query::query())
+        .map(move |()| -> WithStatus<Json> {
+            let consistency = reporter.lock().unwrap().consistency();
+            match consistency {
+                Some(mut consistency) => {
+                    consistency.resolve(&resolver.read().unwrap());
+                    reply::with_status(reply::json(&consistency), StatusCode::OK)
+                }
+                None => reply::with_status(
+                    reply::json(&"not running with `--tracker cross-check`"),
+                    StatusCode::NOT_FOUND,
+                ),
+            }
+        })
+}
+
+/// Server-sent events, the page events and the change of the short report every second
+fn events_stream<T>(
+    reporter: Arc<Mutex<T>>,
+    resolver: Arc<RwLock<StackResolver>>,
+    stream: EventStream,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone + Sync + Send + 'static
+where
+    T: Reporter + Send + 'static,
+{
+    use std::convert::Infallible;
+    use tokio_stream::{
+        StreamExt,
+        wrappers::{BroadcastStream, IntervalStream, errors::BroadcastStreamRecvError},
+    };
+    use warp::sse;
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Usage {
+        total: u64,
+        cache: u64,
+        delta_total: i64,
+        delta_cache: i64,
+    }
+
+    warp::path!("v1" / "events" / "stream")
+        .and(warp::query::query())
+        .map(move |filter: StreamFilter| {
+            let filter = Arc::new(filter);
+
+            let events = {
+                let resolver = resolver.clone();
+                let filter = filter.clone();
+                BroadcastStream::new(stream.subscribe()).filter_map(move |event| match event {
+                    Ok(event) => {
+                        let event = filter.apply(&event, &resolver.read().unwrap())?;
+                        sse::Event::default()
+                            .event(event.kind())
+                            .json_data(&event)
+                            .ok()
+                    }
+                    // the client is too slow
+                    Err(BroadcastStreamRecvError::Lagged(count)) => Some(
+                        sse::Event::default()
+                            .event("lagged")
+                            .data(count.to_string()),
+                    ),
+                })
+            };
+
+            let usage = {
+                let reporter = reporter.clone();
+                let mut last = (0, 0);
+                let interval = tokio::time::interval(Duration::from_secs(1));
+                IntervalStream::new(interval).filter_map(move |_| {
+                    if !filter.accepts_kind("usage") {
+                        return None;
+                    }
+                    let (total, cache) = reporter.lock().unwrap().short_report();
+                    let usage = Usage {
+                        total,
+                        cache,
+                        delta_total: total as i64 - last.0 as i64,
+                        delta_cache: cache as i64 - last.1 as i64,
+                    };
+                    last = (total, cache);
+                    sse::Event::default().event("usage").json_data(&usage).ok()
+                })
+            };
+
+            let events = events.merge(usage).map(Ok::<_, Infallible>);
+            sse::reply(sse::keep_alive().stream(events))
+        })
+}
+
 pub fn openapi(
 ) -> impl Filter<Extract = (WithStatus<Json>,), Error = Rejection> + Clone + Sync + Send + 'static {
     warp::path!("openapi" / "memory-profiler-openapi.json")
@@ -140,3 +665,63 @@ pub fn openapi(
             reply::with_status(reply::json(&d), StatusCode::OK)
         })
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env, fs,
+        sync::{Arc, Mutex, RwLock},
+    };
+
+    use crate::{EventStream, Snapshot, StackResolver};
+    use super::{run, Config, Listen, Tls};
+
+    #[test]
+    fn parse_listen() {
+        let tcp = |s: &str| Listen::Tcp(s.parse().unwrap());
+        assert_eq!("127.0.0.1:8080".parse(), Ok(tcp("127.0.0.1:8080")));
+        assert_eq!("[::1]:8080".parse(), Ok(tcp("[::1]:8080")));
+        assert_eq!(
+            "unix:/run/bpf-mem.sock".parse(),
+            Ok(Listen::Unix("/run/bpf-mem.sock".into()))
+        );
+        assert!("localhost".parse::<Listen>().is_err());
+    }
+
+    #[test]
+    fn tls_errors() {
+        let dir = env::temp_dir().join(format!("bpf-mem-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let run = || {
+            let config = Config {
+                listen: Listen::Tcp("127.0.0.1:0".parse().unwrap()),
+                tls: Some(Tls {
+                    cert: cert.clone(),
+                    key: key.clone(),
+                }),
+                ..Default::default()
+            };
+            let reporter = Arc::new(Mutex::new(Snapshot::default()));
+            let resolver = Arc::new(RwLock::new(StackResolver::mock()));
+            run(
+                &config,
+                reporter,
+                resolver,
+                Default::default(),
+                EventStream::default(),
+            )
+            .map(drop)
+        };
+
+        let error = run().unwrap_err();
+        assert!(error.starts_with("failed to read"), "{}", error);
+
+        fs::write(&cert, "not a certificate").unwrap();
+        fs::write(&key, "not a key").unwrap();
+        let error = run().unwrap_err();
+        assert!(error.starts_with("failed to serve tls"), "{}", error);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,16 +2,28 @@
 // SPDX-License-Identifier: MIT
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, VecDeque},
     sync::{
-        Arc, RwLock,
+        Arc, Condvar, Mutex, RwLock,
         atomic::{AtomicU32, Ordering},
     },
-    path::PathBuf,
+    path::{Path, PathBuf},
+    fs,
+    time::Duration,
 };
-use event::Hex32;
+use event::{Hex32, Hex64, RawStack, Stack, Unwinder};
 use serde::Serialize;
-use super::{memory_map::ProcessMap, table::SymbolTable};
+use super::{
+    memory_map::{FileId, ProcessMap},
+    table::SymbolTable,
+    kallsyms::{self, KernelSymbols},
+    perf_map::JitSymbols,
+    elf::Elf,
+    unwind::{self, UnwindTable, Rule},
+    diagnostics::{Frame, UnwindingReport},
+    demangle::demangle,
+    category::Categories,
+};
 
 #[derive(Serialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(rename_all = "camelCase")]
@@ -20,122 +32,282 @@ pub struct SymbolInfo {
     executable: String,
     function_name: Option<String>,
     function_category: String,
+    /// Only if the function is not known, to find the symbols later
+    #[serde(skip_serializing_if = "Option::is_none")]
+    build_id: Option<String>,
+}
+
+impl SymbolInfo {
+    /// Demangles the `name` and tells the category of the function
+    pub fn new(
+        offset: u32,
+        executable: &str,
+        name: Option<String>,
+        build_id: Option<String>,
+        categories: &Categories,
+    ) -> Self {
+        let function = name.as_deref().map(demangle);
+        SymbolInfo {
+            offset: Hex32(offset),
+            executable: executable.to_string(),
+            function_category: categories.category(executable, function.as_ref()),
+            function_name: function.map(|f| f.name),
+            build_id: build_id.filter(|_| name.is_none()),
+        }
+    }
+
+    pub fn function_name(&self) -> Option<&str> {
+        self.function_name.as_deref()
+    }
 }
 
+/// The code at the address, before demangling
+struct Resolved<'a> {
+    offset: usize,
+    executable: &'a str,
+    function_name: Option<String>,
+    build_id: Option<&'a str>,
+}
+
+/// The file mapped by the process, when its symbols are not loaded
+struct RawFile {
+    name: String,
+    build_id: Option<String>,
+}
+
+/// How many previous maps to keep, only the code of each
+const PREVIOUS_MAPS: usize = 64;
+
 #[derive(Default)]
 pub struct StackResolver {
     files: HashMap<String, SymbolTable>,
+    /// Without the symbol tables, the frames are the offsets in the files of these builds
+    raw: Option<HashMap<String, RawFile>>,
+    unwind_tables: HashMap<String, UnwindTable>,
     map: Option<ProcessMap>,
+    /// The code of the maps before, the newest first, the stacks captured back then
+    /// might have the addresses of the files unmapped since
+    previous_maps: VecDeque<ProcessMap>,
+    kernel: Option<KernelSymbols>,
+    jit: Option<JitSymbols>,
+    refresh: MapRefresh,
+    categories: Categories,
     mock: Option<()>,
 }
 
-fn copy_binary(filename: &str) -> Result<PathBuf, ()> {
-    use std::{env, process::Command, path::Path, fs};
+/// Wakes the loader before the interval, when new code is mapped
+#[derive(Clone, Default)]
+pub struct MapRefresh(Arc<(Mutex<bool>, Condvar)>);
 
-    let node_name = match env::var("CONTAINER_NAME") {
-        Err(_) => return Ok(PathBuf::from(filename)),
-        Ok(v) => v,
-    };
+impl MapRefresh {
+    pub fn trigger(&self) {
+        let (triggered, condvar) = &*self.0;
+        *triggered.lock().unwrap() = true;
+        condvar.notify_one();
+    }
+
+    /// Sleeps for the `timeout` or until triggered
+    fn wait(&self, timeout: Duration) {
+        let (triggered, condvar) = &*self.0;
+        let guard = triggered.lock().unwrap();
+        let (mut guard, _) = condvar
+            .wait_timeout_while(guard, timeout, |triggered| !*triggered)
+            .unwrap();
+        *guard = false;
+    }
+}
+
+/// Where to read the file mapped by the process, works in any container;
+/// `map_files` is the very file which is mapped, even if it is deleted or replaced since,
+/// `root` is the file at the path as the process sees it
+fn binary_path(pid: u32, map: &ProcessMap, filename: &str) -> Option<PathBuf> {
+    let map_file = map
+        .file_range(filename)
+        .map(|range| format!("/proc/{}/map_files/{:x}-{:x}", pid, range.start, range.end));
+    let root = format!("/proc/{}/root{}", pid, filename);
+    map_file
+        .into_iter()
+        .chain(Some(root))
+        .map(PathBuf::from)
+        .find(|path| fs::File::open(path).is_ok())
+}
+
+/// The name of the file without the directory
+fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("")
+}
+
+/// Follows the process map and loads symbols of the files appearing in it
+#[derive(Default)]
+struct Loader {
+    /// Only the unwind tables and the build ids, see `StackResolver::spawn`
+    raw: bool,
+    last_map: Option<ProcessMap>,
+    files: HashMap<String, LoadedFile>,
+    /// The size of the perf map when it was loaded, the JIT appends to it
+    jit_size: Option<u64>,
+}
+
+/// The identity of the file the tables are loaded from
+struct LoadedFile {
+    id: FileId,
+    build_id: Option<String>,
+}
+
+fn build_id(path: &Path) -> Option<String> {
+    let data = fs::read(path).ok()?;
+    Elf::new(&data).ok()?.build_id().ok()?
+}
+
+impl Loader {
+    fn load_kernel(resolver: &RwLock<StackResolver>) {
+        match KernelSymbols::load() {
+            Ok(table) => {
+                log::info!("loaded {} kernel symbols", table.len());
+                resolver.write().unwrap().kernel = Some(table);
+            }
+            Err(error) => log::warn!("failed to load kernel symbols: {}", error),
+        }
+    }
 
-    let res = Command::new("docker")
-        .args(&["ps", "-qf"])
-        .arg(format!("name={}", node_name))
-        .output()
-        .map_err(|_| ())?;
-    if !res.status.success() {
-        return Err(());
-    }
-    let node_image_name = String::from_utf8(res.stdout).map_err(|_| ())?;
-    if node_image_name.lines().count() > 1 {
-        log::error!("multiple node containers");
-        Err(())
-    } else {
-        log::info!("copying: {}", filename);
-        let path = format!("/tmp{}", filename);
-        let path = Path::new(&path);
-        let prefix = path.parent().ok_or(())?;
-        let _ = fs::create_dir_all(prefix);
-        let output = Command::new("docker")
-            .arg("cp")
-            .arg(format!(
-                "{}:{}",
-                node_image_name.trim_end_matches('\n'),
-                filename
-            ))
-            .arg(path)
-            .output()
-            .map_err(|_| ())?;
-        if !output.status.success() {
-            log::error!("{:?}", String::from_utf8(output.stderr));
+    fn refresh_jit(&mut self, pid: u32, resolver: &RwLock<StackResolver>) {
+        let size = fs::metadata(JitSymbols::path(pid)).ok().map(|m| m.len());
+        if size == self.jit_size {
+            return;
+        }
+        self.jit_size = size;
+        if size.is_none() {
+            resolver.write().unwrap().jit = None;
+            return;
+        }
+        match JitSymbols::load(pid) {
+            Ok(table) => {
+                log::info!(
+                    "loaded {} jit symbols from: {}",
+                    table.len(),
+                    JitSymbols::path(pid)
+                );
+                resolver.write().unwrap().jit = Some(table);
+            }
+            Err(error) => log::warn!("failed to load jit symbols: {}", error),
+        }
+    }
+
+    fn refresh(&mut self, pid: u32, resolver: &RwLock<StackResolver>) {
+        self.refresh_jit(pid, resolver);
+
+        let map = match ProcessMap::new(pid) {
+            Ok(map) => map,
+            Err(error) => {
+                if self.last_map.is_none() {
+                    log::error!("cannot get process map: {}", error);
+                }
+                return;
+            }
+        };
+        if Some(&map) == self.last_map.as_ref() {
+            return;
+        }
+        self.last_map = Some(map.clone());
+        for (initial_filename, id) in map.file_ids() {
+            let loaded = self.files.get(&initial_filename);
+            let loaded = loaded.map(|f| (f.id, f.build_id.clone()));
+            if loaded.as_ref().map(|&(id, _)| id) != Some(id) {
+                let filename = match binary_path(pid, &map, &initial_filename) {
+                    None => {
+                        log::error!("cannot access binary {:?}", initial_filename);
+                        continue;
+                    }
+                    Some(filename) => filename,
+                };
+                // another inode, but the same build, e.g. the file is copied over, the tables are valid
+                let build_id = build_id(&filename);
+                if let Some((_, loaded_build_id)) = loaded {
+                    if loaded_build_id.is_some() && loaded_build_id == build_id {
+                        log::info!("same build of: {}, keep the symbols", initial_filename);
+                        self.files
+                            .insert(initial_filename, LoadedFile { id, build_id });
+                        continue;
+                    }
+                    log::info!("changed: {}, reload the symbols", initial_filename);
+                }
+                if self.raw {
+                    let file = RawFile {
+                        name: file_name(&initial_filename).to_string(),
+                        build_id: build_id.clone(),
+                    };
+                    let mut guard = resolver.write().unwrap();
+                    let files = guard.raw.get_or_insert_with(HashMap::new);
+                    files.insert(initial_filename.clone(), file);
+                    drop(guard);
+                } else {
+                    log::info!("try load symbols for: {}", initial_filename);
+                    match SymbolTable::load(&filename, file_name(&initial_filename)) {
+                        Ok(table) => {
+                            log::info!("loaded {} symbols from: {}", table.len(), initial_filename);
+                            let mut guard = resolver.write().unwrap();
+                            guard.files.insert(initial_filename.clone(), table);
+                            drop(guard);
+                        }
+                        Err(error) => {
+                            log::info!("failed to load symbols for: {:?}, {}", filename, error);
+                            // the tables of the previous file are stale
+                            resolver.write().unwrap().files.remove(&initial_filename);
+                        }
+                    }
+                }
+                match UnwindTable::load(&filename) {
+                    Ok(table) => {
+                        log::info!(
+                            "loaded {} unwind rules from: {}",
+                            table.len(),
+                            initial_filename
+                        );
+                        let mut guard = resolver.write().unwrap();
+                        guard.unwind_tables.insert(initial_filename.clone(), table);
+                        drop(guard);
+                    }
+                    Err(error) => {
+                        log::info!("failed to load unwind rules for: {:?}, {}", filename, error);
+                        let mut guard = resolver.write().unwrap();
+                        guard.unwind_tables.remove(&initial_filename);
+                    }
+                }
+                self.files
+                    .insert(initial_filename, LoadedFile { id, build_id });
+            }
         }
-        Ok(path.to_path_buf())
+        resolver.write().unwrap().set_map(map);
     }
 }
 
 impl StackResolver {
-    pub fn spawn(pid: Arc<AtomicU32>) -> Arc<RwLock<Self>> {
-        use std::{time::Duration, thread};
+    /// Reloads the process map every `interval` in a separate thread,
+    /// or sooner if the `map_refresh` is triggered;
+    /// without `symbols` the frames are only the offsets in the files and the build ids,
+    /// the report is symbolized later, see `Symbolizer`
+    pub fn spawn(pid: Arc<AtomicU32>, interval: Duration, symbols: bool) -> Arc<RwLock<Self>> {
+        use std::thread;
 
         let resolver = Arc::new(RwLock::new(StackResolver::default()));
         let resolver_ref = resolver.clone();
+        let refresh = resolver.read().unwrap().map_refresh();
         thread::spawn(move || {
-            let mut last_map = None::<ProcessMap>;
-            let mut files = HashSet::new();
+            Loader::load_kernel(&resolver_ref);
 
+            let mut loader = Loader {
+                raw: !symbols,
+                ..Default::default()
+            };
             loop {
-                let delay = Duration::from_secs(5);
-                thread::sleep(delay);
+                refresh.wait(interval);
 
                 let pid = pid.load(Ordering::Relaxed);
                 if pid != 0 {
-                    match ProcessMap::new(pid) {
-                        Ok(map) => {
-                            if Some(&map) != last_map.as_ref() {
-                                last_map = Some(map.clone());
-                                for initial_filename in map.files() {
-                                    if !files.contains(&initial_filename) {
-                                        let filename = match copy_binary(&initial_filename) {
-                                            Err(()) => {
-                                                log::error!(
-                                                    "failed to copy fresh binary {:?}",
-                                                    initial_filename
-                                                );
-                                                continue;
-                                            }
-                                            Ok(filename) => filename,
-                                        };
-                                        log::info!("try load symbols for: {}", initial_filename);
-                                        match SymbolTable::load(&filename) {
-                                            Ok(table) => {
-                                                log::info!(
-                                                    "loaded {} symbols from: {}",
-                                                    table.len(),
-                                                    initial_filename
-                                                );
-                                                let mut guard = resolver_ref.write().unwrap();
-                                                guard.files.insert(initial_filename.clone(), table);
-                                                drop(guard);
-                                            }
-                                            Err(error) => {
-                                                log::info!(
-                                                    "failed to load symbols for: {:?}, {}",
-                                                    filename,
-                                                    error,
-                                                );
-                                            }
-                                        }
-                                        files.insert(initial_filename);
-                                    }
-                                }
-                                resolver_ref.write().unwrap().map = Some(map);
-                            }
-                        }
-                        Err(error) => {
-                            if last_map.is_none() {
-                                log::error!("cannot get process map: {}", error);
-                            }
-                        }
-                    }
+                    loader.refresh(pid, &resolver_ref);
                 }
             }
         });
@@ -143,77 +315,262 @@ impl StackResolver {
         resolver
     }
 
+    /// Loads the symbols once, the process must be still running
+    pub fn load(pid: u32) -> Self {
+        let resolver = RwLock::new(StackResolver::default());
+        Loader::load_kernel(&resolver);
+        Loader::default().refresh(pid, &resolver);
+        resolver.into_inner().unwrap()
+    }
+
     pub fn mock() -> Self {
         StackResolver {
             files: HashMap::new(),
+            raw: None,
+            unwind_tables: HashMap::new(),
             map: None,
+            previous_maps: VecDeque::new(),
+            kernel: None,
+            jit: None,
+            refresh: MapRefresh::default(),
+            categories: Categories::default(),
             mock: Some(()),
         }
     }
 
-    fn try_resolve(&self, address: u64) -> Option<((usize, &str), Option<String>)> {
-        let map = self.map.as_ref()?;
-        let (filename, offset) = map.find(address as usize)?;
-        let table = self.files.get(&filename)?;
-        Some(((offset, table.name()), table.find(offset as u64)))
+    pub fn set_categories(&mut self, categories: Categories) {
+        self.categories = categories;
     }
 
-    fn try_mock(&self, address: u64) -> Option<((usize, &str), Option<String>)> {
-        self.mock
-            .as_ref()
-            .map(|&()| ((0, "mock"), Some(format!("func_{}", address))))
+    /// Trigger it when the process maps new code
+    pub fn map_refresh(&self) -> MapRefresh {
+        self.refresh.clone()
     }
 
-    pub fn resolve(&self, address: u64) -> Option<SymbolInfo> {
-        let ((offset, filename), name) = self
-            .try_resolve(address)
-            .or_else(|| self.try_mock(address))?;
+    /// Keeps the code of the replaced map, unless the code is the same
+    fn set_map(&mut self, map: ProcessMap) {
+        let code = map.code();
+        if let Some(previous) = self.map.replace(map).map(|map| map.code()) {
+            if previous != code && self.previous_maps.front() != Some(&previous) {
+                self.previous_maps.push_front(previous);
+                self.previous_maps.truncate(PREVIOUS_MAPS);
+            }
+        }
+    }
 
-        fn cpp_demangle(s: &str) -> Option<String> {
-            cpp_demangle::Symbol::new(s)
-                .ok()?
-                .demangle(&Default::default())
-                .ok()
+    /// The file and the offset of the code, if the address is not mapped anymore,
+    /// the previous maps are searched, the newest first
+    fn find_code(&self, address: u64) -> Option<(String, usize)> {
+        let address = address as usize;
+        let map = self.map.as_ref()?;
+        if map.region(address).is_some() {
+            return map.find(address);
         }
+        self.previous_maps
+            .iter()
+            .find_map(|previous| previous.find(address))
+    }
 
-        let function_category = if filename.starts_with("lib") || filename.starts_with("linux") {
-            "systemLib".to_string()
-        } else {
-            if name.as_ref().map(|n| is_rust(n)).unwrap_or(false) {
-                "nodeRust".to_string()
-            } else {
-                "nodeCpp".to_string()
-            }
+    fn try_resolve(&self, address: u64) -> Option<Resolved<'_>> {
+        let named = |(offset, executable, name)| Resolved {
+            offset,
+            executable,
+            function_name: Some(name),
+            build_id: None,
         };
 
-        Some(SymbolInfo {
-            offset: Hex32(offset as _),
-            executable: filename.to_string(),
-            function_name: name.map(|n| {
-                if is_rust(&n) {
-                    rustc_demangle::demangle(&n).to_string()
-                } else {
-                    cpp_demangle(&n).unwrap_or(n)
+        if kallsyms::is_kernel_address(address) {
+            return self.kernel.as_ref()?.find(address).map(named);
+        }
+
+        match self.find_code(address) {
+            Some((filename, offset)) => {
+                if let Some(table) = self.files.get(&filename) {
+                    return Some(Resolved {
+                        offset,
+                        executable: table.name(),
+                        function_name: table.find(offset as u64),
+                        build_id: None,
+                    });
                 }
-            }),
-            function_category,
+                let file = self.raw.as_ref()?.get(&filename)?;
+                Some(Resolved {
+                    offset,
+                    executable: &file.name,
+                    function_name: None,
+                    build_id: file.build_id.as_deref(),
+                })
+            }
+            // not a file, might be the code generated at runtime
+            None => self.jit.as_ref()?.find(address).map(named),
+        }
+    }
+
+    fn unwind_rule(&self, address: u64) -> Option<Rule> {
+        let (filename, offset) = self.find_code(address)?;
+        self.unwind_tables.get(&filename)?.find(offset as u64)
+    }
+
+    fn try_mock(&self, address: u64) -> Option<Resolved<'_>> {
+        self.mock.as_ref().map(|&()| Resolved {
+            offset: 0,
+            executable: "mock",
+            function_name: Some(format!("func_{}", address)),
+            build_id: None,
         })
     }
+
+    pub fn resolve(&self, address: u64) -> Option<SymbolInfo> {
+        let resolved = self
+            .try_resolve(address)
+            .or_else(|| self.try_mock(address))?;
+
+        Some(SymbolInfo::new(
+            resolved.offset as _,
+            resolved.executable,
+            resolved.function_name,
+            resolved.build_id.map(str::to_string),
+            &self.categories,
+        ))
+    }
 }
 
-fn is_rust(s: &str) -> bool {
-    fn inner(s: &str) -> bool {
-        let s = s.trim_end_matches('E');
-        let l = s.bytes().len();
-        if l < 17 {
-            return false;
+impl StackResolver {
+    /// What is known about the address of a frame, even without the symbols
+    fn frame(&self, address: u64) -> Frame {
+        if let Some(info) = self.resolve(address) {
+            return Frame::Code {
+                executable: info.executable,
+                function_name: info.function_name,
+            };
+        }
+        if let Some((filename, _)) = self.find_code(address) {
+            return Frame::Code {
+                executable: file_name(&filename).to_string(),
+                function_name: None,
+            };
         }
+        self.map
+            .as_ref()
+            .and_then(|map| map.non_exec(address as usize))
+            .map(Frame::NonExec)
+            .unwrap_or(Frame::Unknown)
+    }
 
-        let h = s.as_bytes()[l - 17] == 'h' as u8;
-        s.as_bytes()[(l - 16)..]
+    /// Which mapped objects seem to break the unwinding of the stacks,
+    /// the stacks with fewer than `short` user frames are short
+    pub fn unwinding_report(
+        &self,
+        stacks: &HashMap<Vec<Hex64>, (u64, u64)>,
+        short: usize,
+    ) -> UnwindingReport {
+        let stacks = stacks
             .iter()
-            .fold(h, |h, b| h && b.is_ascii_hexdigit())
+            .map(|(stack, &(value, _))| (stack.as_slice(), value));
+        UnwindingReport::new(stacks, short, |address| self.frame(address))
     }
+}
+
+impl Unwinder for StackResolver {
+    fn unwind(&self, raw: &RawStack) -> Stack {
+        Stack::from_frames(&unwind::unwind(raw, |ip| self.unwind_rule(ip)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{MapRefresh, ProcessMap, StackResolver, PREVIOUS_MAPS};
+
+    fn map(lib: &str) -> ProcessMap {
+        format!(
+            "\
+7f0000000000-7f0000001000 r-xp 00000000 08:01 1234 {}
+7f0000100000-7f0000200000 rw-p 00000000 00:00 0
+",
+            lib
+        )
+        .parse()
+        .unwrap()
+    }
+
+    #[test]
+    fn map_refresh() {
+        let refresh = MapRefresh::default();
+
+        let start = Instant::now();
+        refresh.wait(Duration::from_millis(50));
+        assert!(start.elapsed() >= Duration::from_millis(50));
 
-    s.split_whitespace().any(inner) || s.split(".llvm").any(inner)
+        // the trigger before the wait is not lost
+        refresh.trigger();
+        let start = Instant::now();
+        refresh.wait(Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let trigger = refresh.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            trigger.trigger();
+        });
+        let start = Instant::now();
+        refresh.wait(Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn previous_maps() {
+        let mut resolver = StackResolver::default();
+        resolver.set_map(map("/lib/a.so"));
+        assert!(resolver.previous_maps.is_empty());
+
+        // the same code, only the data differs
+        let code_only = "7f0000000000-7f0000001000 r-xp 00000000 08:01 1234 /lib/a.so\n";
+        resolver.set_map(code_only.parse().unwrap());
+        assert!(resolver.previous_maps.is_empty());
+
+        resolver.set_map(map("/lib/b.so"));
+        resolver.set_map(map("/lib/c.so"));
+        let files = resolver
+            .previous_maps
+            .iter()
+            .map(ProcessMap::files)
+            .collect::<Vec<_>>();
+        assert_eq!(files, [["/lib/b.so"], ["/lib/a.so"]]);
+
+        for i in 0..(PREVIOUS_MAPS * 2) {
+            resolver.set_map(map(&format!("/lib/{}.so", i)));
+        }
+        assert_eq!(resolver.previous_maps.len(), PREVIOUS_MAPS);
+        let newest = format!("/lib/{}.so", PREVIOUS_MAPS * 2 - 2);
+        assert_eq!(resolver.previous_maps[0].files(), [newest]);
+    }
+
+    #[test]
+    fn find_code_in_previous_maps() {
+        let mut resolver = StackResolver::default();
+        assert_eq!(resolver.find_code(0x7f0000000100), None);
+
+        resolver.set_map(map("/lib/a.so"));
+        resolver.set_map(map("/lib/b.so"));
+        assert_eq!(
+            resolver.find_code(0x7f0000000100),
+            Some(("/lib/b.so".to_string(), 0x100))
+        );
+
+        // the code is unmapped, the stacks of before still have the address
+        let unmapped = "7f0000100000-7f0000200000 rw-p 00000000 00:00 0\n";
+        resolver.set_map(unmapped.parse().unwrap());
+        assert_eq!(
+            resolver.find_code(0x7f0000000100),
+            Some(("/lib/b.so".to_string(), 0x100))
+        );
+        // mapped, but not the code, the old maps are not searched
+        assert_eq!(resolver.find_code(0x7f0000100100), None);
+    }
 }
@@ -0,0 +1,217 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+use tokio::sync::broadcast;
+use event::{Hex32, Hex64};
+
+use super::stack::{StackResolver, SymbolInfo};
+
+/// The page event as the consumer sees it, before the symbols are resolved
+#[derive(Clone)]
+pub enum StreamEvent {
+    Alloc {
+        pid: u32,
+        pfn: u32,
+        order: u32,
+        flags: Hex32,
+        stack: Arc<Vec<Hex64>>,
+    },
+    Free {
+        pid: u32,
+        pfn: u32,
+        order: u32,
+    },
+}
+
+/// Sends the events to the http clients watching `/v1/events/stream`
+#[derive(Clone)]
+pub struct EventStream(broadcast::Sender<StreamEvent>);
+
+impl Default for EventStream {
+    fn default() -> Self {
+        EventStream(broadcast::channel(Self::CAPACITY).0)
+    }
+}
+
+impl EventStream {
+    /// The slow client misses the events beyond this
+    const CAPACITY: usize = 0x1000;
+
+    /// Nobody watches most of the time, no need to prepare the event then
+    pub fn is_watched(&self) -> bool {
+        self.0.receiver_count() != 0
+    }
+
+    pub fn send(&self, event: StreamEvent) {
+        // fails only if nobody watches
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<StreamEvent> {
+        self.0.subscribe()
+    }
+}
+
+/// The query of `/v1/events/stream`, everything passes by default
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamFilter {
+    /// Comma separated `alloc`, `free` and `usage`
+    kind: Option<String>,
+    min_order: Option<u32>,
+    /// Semicolon separated prefixes of function names, matched from the root
+    /// of the tree as `/v1/tree` builds it without `reverse`
+    stack: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DecodedEvent {
+    kind: &'static str,
+    pid: u32,
+    pfn: Hex32,
+    order: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flags: Option<Hex32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stack: Vec<Frame>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Frame {
+    Symbol(SymbolInfo),
+    Address(Hex64),
+}
+
+impl DecodedEvent {
+    pub fn kind(&self) -> &'static str {
+        self.kind
+    }
+}
+
+impl StreamFilter {
+    pub fn accepts_kind(&self, kind: &str) -> bool {
+        match &self.kind {
+            None => true,
+            Some(kinds) => kinds.split(',').any(|k| k.trim() == kind),
+        }
+    }
+
+    /// Resolves the symbols, `None` if the event does not pass
+    pub fn apply(&self, event: &StreamEvent, resolver: &StackResolver) -> Option<DecodedEvent> {
+        match event {
+            StreamEvent::Alloc {
+                pid,
+                pfn,
+                order,
+                flags,
+                stack,
+            } => {
+                if !self.accepts_kind("alloc") || self.min_order.is_some_and(|m| *order < m) {
+                    return None;
+                }
+                let symbols = stack
+                    .iter()
+                    .map(|ip| resolver.resolve(ip.0))
+                    .collect::<Vec<_>>();
+                if let Some(prefix) = &self.stack {
+                    let mut names = symbols
+                        .iter()
+                        .map(|s| s.as_ref().and_then(SymbolInfo::function_name));
+                    let matches = prefix.split(';').all(|p| {
+                        names
+                            .next()
+                            .flatten()
+                            .is_some_and(|name| name.starts_with(p.trim()))
+                    });
+                    if !matches {
+                        return None;
+                    }
+                }
+                let stack = stack
+                    .iter()
+                    .zip(symbols)
+                    .map(|(ip, symbol)| symbol.map(Frame::Symbol).unwrap_or(Frame::Address(*ip)))
+                    .collect();
+                Some(DecodedEvent {
+                    kind: "alloc",
+                    pid: *pid,
+                    pfn: Hex32(*pfn),
+                    order: *order,
+                    flags: Some(*flags),
+                    stack,
+                })
+            }
+            // the stack of the freed page is unknown, the stack filter drops it
+            StreamEvent::Free { pid, pfn, order } => {
+                if !self.accepts_kind("free")
+                    || self.min_order.is_some_and(|m| *order < m)
+                    || self.stack.is_some()
+                {
+                    return None;
+                }
+                Some(DecodedEvent {
+                    kind: "free",
+                    pid: *pid,
+                    pfn: Hex32(*pfn),
+                    order: *order,
+                    flags: None,
+                    stack: vec![],
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use event::{Hex32, Hex64};
+
+    use super::{StreamEvent, StreamFilter};
+    use crate::StackResolver;
+
+    #[test]
+    fn filter() {
+        let resolver = StackResolver::mock();
+        let alloc = |order| StreamEvent::Alloc {
+            pid: 1,
+            pfn: 0x100,
+            order,
+            flags: Hex32(0),
+            stack: Arc::new(vec![Hex64(12), Hex64(34)]),
+        };
+        let free = StreamEvent::Free {
+            pid: 1,
+            pfn: 0x100,
+            order: 0,
+        };
+        let filter = |f: &str| serde_json::from_str::<StreamFilter>(f).unwrap();
+
+        assert!(filter("{}").apply(&alloc(0), &resolver).is_some());
+        assert!(filter("{}").apply(&free, &resolver).is_some());
+        let only_free = filter(r#"{"kind":"free"}"#);
+        assert!(only_free.apply(&alloc(0), &resolver).is_none());
+        let both = filter(r#"{"kind":"alloc,free"}"#);
+        assert!(both.apply(&free, &resolver).is_some());
+        let min_order = filter(r#"{"minOrder":2}"#);
+        assert!(min_order.apply(&alloc(1), &resolver).is_none());
+        assert!(min_order.apply(&alloc(3), &resolver).is_some());
+        let stack = filter(r#"{"stack":"func_12"}"#);
+        assert!(stack.apply(&alloc(0), &resolver).is_some());
+        assert!(stack.apply(&free, &resolver).is_none());
+        let stack = filter(r#"{"stack":"func_1;func_3"}"#);
+        assert!(stack.apply(&alloc(0), &resolver).is_some());
+        let stack = filter(r#"{"stack":"func_34"}"#);
+        assert!(stack.apply(&alloc(0), &resolver).is_none());
+
+        let decoded = filter("{}").apply(&alloc(0), &resolver).unwrap();
+        let value = serde_json::to_value(decoded).unwrap();
+        assert_eq!(value["stack"][1]["functionName"], "func_34");
+    }
+}
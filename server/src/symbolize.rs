@@ -0,0 +1,196 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+
+use super::{category::Categories, elf::Elf, stack::SymbolInfo, table::SymbolTable};
+
+/// Fills the function names into the report of the resolver without symbols,
+/// the frames there are the offsets in the files and the build ids
+pub struct Symbolizer {
+    store: PathBuf,
+    /// By the build id, or by the name of the executable if the build id is unknown,
+    /// `None` if the binary is not in the store
+    tables: HashMap<String, Option<SymbolTable>>,
+    categories: Categories,
+}
+
+impl Symbolizer {
+    /// The `store` is the directory of the binaries or their debug files,
+    /// the layout is of the debuginfod cache, or the files are named as the executables
+    pub fn new<P>(store: P, categories: Categories) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Symbolizer {
+            store: store.as_ref().to_path_buf(),
+            tables: HashMap::new(),
+            categories,
+        }
+    }
+
+    /// Where the binary might be, the debug file first
+    fn candidates(&self, executable: &str, build_id: Option<&str>) -> Vec<PathBuf> {
+        let mut paths = vec![];
+        if let Some(id) = build_id.filter(|id| id.len() > 2) {
+            let (dir, rest) = id.split_at(2);
+            let build_id_dir = self.store.join(".build-id").join(dir);
+            paths.push(build_id_dir.join(format!("{}.debug", rest)));
+            paths.push(build_id_dir.join(rest));
+            paths.push(self.store.join(id).join("debuginfo"));
+            paths.push(self.store.join(id).join("executable"));
+        }
+        paths.push(self.store.join(executable));
+        paths
+    }
+
+    fn table(&mut self, executable: &str, build_id: Option<&str>) -> Option<&SymbolTable> {
+        let key = build_id.unwrap_or(executable).to_string();
+        if !self.tables.contains_key(&key) {
+            let table = self
+                .candidates(executable, build_id)
+                .into_iter()
+                .filter(|path| path.is_file())
+                // the file named as the executable might be of another build
+                .filter(|path| build_id.is_none() || file_build_id(path).as_deref() == build_id)
+                .find_map(|path| match SymbolTable::load(&path, executable) {
+                    Ok(table) => {
+                        log::info!("loaded {} symbols from: {:?}", table.len(), path);
+                        Some(table)
+                    }
+                    Err(error) => {
+                        log::warn!("failed to load symbols from: {:?}, {}", path, error);
+                        None
+                    }
+                });
+            if table.is_none() {
+                log::warn!("no symbols for: {} {}", executable, build_id.unwrap_or(""));
+            }
+            self.tables.insert(key.clone(), table);
+        }
+        self.tables.get(&key)?.as_ref()
+    }
+
+    /// Replaces every frame of the json report that has no function name,
+    /// returns how many frames are resolved
+    pub fn symbolize(&mut self, report: &mut Value) -> usize {
+        match report {
+            Value::Object(object) => {
+                let mut resolved = 0;
+                if let Some(name) = object.get_mut("name") {
+                    if self.symbolize_frame(name) {
+                        resolved += 1;
+                    }
+                }
+                for value in object.values_mut() {
+                    resolved += self.symbolize(value);
+                }
+                resolved
+            }
+            Value::Array(array) => array.iter_mut().map(|value| self.symbolize(value)).sum(),
+            _ => 0,
+        }
+    }
+
+    /// The frame is the serialized `SymbolInfo`
+    fn symbolize_frame(&mut self, frame: &mut Value) -> bool {
+        let object = match frame.as_object() {
+            Some(object) => object,
+            None => return false,
+        };
+        if object.get("functionName").and_then(Value::as_str).is_some() {
+            return false;
+        }
+        let executable = object.get("executable").and_then(Value::as_str);
+        let offset = object.get("offset").and_then(Value::as_str);
+        let offset = offset.and_then(|offset| u32::from_str_radix(offset, 16).ok());
+        let (executable, offset) = match (executable, offset) {
+            (Some(executable), Some(offset)) => (executable.to_string(), offset),
+            _ => return false,
+        };
+        let build_id = object.get("buildId").and_then(Value::as_str);
+        let build_id = build_id.map(str::to_string);
+
+        let name = self
+            .table(&executable, build_id.as_deref())
+            .and_then(|table| table.find(offset as u64));
+        let name = match name {
+            Some(name) => name,
+            None => return false,
+        };
+        let info = SymbolInfo::new(offset, &executable, Some(name), None, &self.categories);
+        match serde_json::to_value(info) {
+            Ok(info) => {
+                *frame = info;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+fn file_build_id(path: &Path) -> Option<String> {
+    let data = fs::read(path).ok()?;
+    Elf::new(&data).ok()?.build_id().ok()?
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use serde_json::json;
+
+    use super::{Categories, Symbolizer};
+
+    #[test]
+    fn candidates() {
+        let symbolizer = Symbolizer::new("/store", Categories::default());
+        let paths = symbolizer.candidates("libc.so.6", Some("0a1b2c"));
+        let expected = [
+            "/store/.build-id/0a/1b2c.debug",
+            "/store/.build-id/0a/1b2c",
+            "/store/0a1b2c/debuginfo",
+            "/store/0a1b2c/executable",
+            "/store/libc.so.6",
+        ];
+        assert_eq!(
+            paths,
+            expected.iter().map(PathBuf::from).collect::<Vec<_>>()
+        );
+        let paths = symbolizer.candidates("node", None);
+        assert_eq!(paths, vec![PathBuf::from("/store/node")]);
+    }
+
+    #[test]
+    fn not_in_store() {
+        let mut symbolizer = Symbolizer::new("/nonexistent", Categories::default());
+        let frame = json!({
+            "offset": "00001000",
+            "executable": "node",
+            "functionName": null,
+            "functionCategory": "nodeCpp",
+            "buildId": "0a1b2c",
+        });
+        let named = json!({
+            "offset": "00002000",
+            "executable": "libc.so.6",
+            "functionName": "malloc",
+            "functionCategory": "systemLib",
+        });
+        let mut report = json!({
+            "name": "root",
+            "value": 8,
+            "frames": [{ "name": frame, "value": 4 }, { "name": named, "value": 4 }],
+        });
+        let before = report.clone();
+        assert_eq!(symbolizer.symbolize(&mut report), 0);
+        assert_eq!(report, before);
+        assert!(symbolizer.tables.get("0a1b2c").unwrap().is_none());
+    }
+}
@@ -21,7 +21,9 @@ impl Symbol {
 }
 
 impl SymbolTable {
-    pub fn load<P>(path: P) -> Result<Self, String>
+    /// The `name` is of the executable in the report, the `path` might be
+    /// in `/proc/<pid>/map_files` and tell nothing
+    pub fn load<P>(path: P, name: &str) -> Result<Self, String>
     where
         P: AsRef<Path>,
     {
@@ -82,12 +84,7 @@ impl SymbolTable {
 
         Ok(SymbolTable {
             inner: symbols,
-            name: path
-                .as_ref()
-                .file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or("")
-                .to_string(),
+            name: name.to_string(),
             strings,
         })
     }
@@ -0,0 +1,104 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+/// How often to sample and how many samples to keep
+#[derive(Clone, Debug)]
+pub struct TimelineConfig {
+    pub interval: Duration,
+    pub capacity: usize,
+}
+
+impl Default for TimelineConfig {
+    fn default() -> Self {
+        // a day at 10 seconds
+        TimelineConfig {
+            interval: Duration::from_secs(10),
+            capacity: 8640,
+        }
+    }
+}
+
+/// The values are in KiB, the time is seconds since unix epoch
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Sample {
+    time: u64,
+    total: u64,
+    cache: u64,
+    system_report_anon: u64,
+}
+
+/// The short reports over time, the oldest samples are dropped
+pub struct Timeline {
+    capacity: usize,
+    samples: VecDeque<Sample>,
+}
+
+impl Timeline {
+    pub fn new(capacity: usize) -> Self {
+        Timeline {
+            capacity,
+            samples: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, now: SystemTime, (total, cache): (u64, u64), system_report_anon: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        let time = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.samples.push_back(Sample {
+            time,
+            total,
+            cache,
+            system_report_anon,
+        });
+    }
+
+    /// The samples taken at `since` or later
+    pub fn since(&self, since: u64) -> Vec<Sample> {
+        let start = self.samples.partition_point(|s| s.time < since);
+        self.samples.range(start..).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::Timeline;
+
+    #[test]
+    fn bounded() {
+        let t = |s| UNIX_EPOCH + Duration::from_secs(s);
+        let mut timeline = Timeline::new(3);
+        for i in 0..5 {
+            timeline.push(t(10 * i), (i, 0), i * 2);
+        }
+
+        let times = |since| {
+            timeline
+                .since(since)
+                .iter()
+                .map(|s| s.time)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(times(0), [20, 30, 40]);
+        assert_eq!(times(25), [30, 40]);
+        assert!(timeline.since(100).is_empty());
+        assert_eq!(timeline.since(40)[0].system_report_anon, 8);
+    }
+}
@@ -0,0 +1,586 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+//! Unwinding the copy of the user stack with the call frame information of `.eh_frame`,
+//! only what the compilers emit for x86_64, the expressions are not supported.
+
+use std::{fs, path::Path};
+
+use event::{RawStack, STACK_MAX_DEPTH};
+
+use super::elf::{Elf, Section, SHT_NOBITS};
+
+/// The DWARF numbers of the registers
+const RBP: u64 = 6;
+const RSP: u64 = 7;
+
+/// How to find the frame of the caller at some instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    pub cfa: Cfa,
+    /// Where `rbp` of the caller is saved relative to the CFA, `None` if not changed
+    pub bp: Option<i64>,
+}
+
+/// The canonical frame address, the value of `rsp` before the call,
+/// the return address is right below it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cfa {
+    Sp(i64),
+    Bp(i64),
+    /// An expression or another register
+    Unknown,
+}
+
+/// The rules by the file offset of the instruction they start at
+#[derive(Default)]
+pub struct UnwindTable {
+    /// The start, the end of the function and the rule, sorted by the start
+    rows: Vec<(u64, u64, Rule)>,
+}
+
+impl UnwindTable {
+    pub fn load<P>(path: P) -> Result<Self, String>
+    where
+        P: AsRef<Path>,
+    {
+        let data = fs::read(path).map_err(|e| e.to_string())?;
+        let elf = Elf::new(&data)?;
+        let eh_frame = elf
+            .section_by_name(".eh_frame")?
+            .ok_or_else(|| "no .eh_frame".to_string())?;
+        let sections = elf
+            .sections()
+            .filter(|s| !matches!(s, Ok(s) if s.ty == SHT_NOBITS || s.address == 0))
+            .collect::<Result<Vec<Section>, _>>()?;
+        // the rules are looked up by the file offset, the same as the symbols
+        let file_offset = |address: u64| {
+            sections.iter().find_map(|s| {
+                let range = s.address..(s.address + s.size as u64);
+                range
+                    .contains(&address)
+                    .then(|| address - s.address + s.offset as u64)
+            })
+        };
+        let data = elf.bytes(eh_frame.offset, eh_frame.size)?;
+        Ok(Self::parse(data, eh_frame.address, file_offset))
+    }
+
+    /// The `address` is where the section is loaded, the pointers are relative to it
+    pub fn parse<F>(data: &[u8], address: u64, file_offset: F) -> Self
+    where
+        F: Fn(u64) -> Option<u64>,
+    {
+        let mut rows = vec![];
+        let mut pos = 0;
+        while let Some(length) = Reader::new(data, address, pos).u32() {
+            // the terminator, or the 64 bit format which nobody emits
+            if length == 0 || length == u32::MAX {
+                break;
+            }
+            let end = pos + 4 + length as usize;
+            let mut r = Reader::new(data, address, pos + 4);
+            let id_pos = r.pos;
+            match r.u32() {
+                Some(0) | None => (),
+                Some(cie_pointer) => {
+                    let cie = id_pos
+                        .checked_sub(cie_pointer as usize)
+                        .and_then(|pos| Cie::parse(data, address, pos));
+                    if let Some(cie) = cie {
+                        r.end = end.min(data.len());
+                        cie.fde_rows(&mut r, &file_offset, &mut rows);
+                    }
+                }
+            }
+            pos = end;
+        }
+        rows.sort_by_key(|(start, _, _)| *start);
+
+        UnwindTable { rows }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn find(&self, offset: u64) -> Option<Rule> {
+        let i = self.rows.partition_point(|(start, _, _)| *start <= offset);
+        let &(_, end, rule) = self.rows.get(i.checked_sub(1)?)?;
+        (offset < end).then_some(rule)
+    }
+}
+
+/// Walk the frames using the rule at each instruction pointer, if there is no rule,
+/// follow the frame pointer; stops when the stack copy ends
+pub fn unwind<F>(raw: &RawStack, rule_at: F) -> Vec<u64>
+where
+    F: Fn(u64) -> Option<Rule>,
+{
+    let mut frames = vec![raw.ip];
+    let (mut sp, mut bp) = (raw.sp, raw.bp);
+    while frames.len() < STACK_MAX_DEPTH {
+        // the return address points after the call, it might be the next function
+        let ip = frames[frames.len() - 1];
+        let ip = if frames.len() == 1 { ip } else { ip - 1 };
+        let rule = rule_at(ip).unwrap_or(Rule {
+            cfa: Cfa::Bp(16),
+            bp: Some(-16),
+        });
+        let cfa = match rule.cfa {
+            Cfa::Sp(offset) => sp.wrapping_add(offset as u64),
+            Cfa::Bp(offset) => bp.wrapping_add(offset as u64),
+            Cfa::Unknown => break,
+        };
+        // the stack grows down, anything else is garbage
+        if cfa <= sp {
+            break;
+        }
+        let ra = match raw.read(cfa - 8) {
+            Some(v) if v != 0 => v,
+            _ => break,
+        };
+        if let Some(offset) = rule.bp {
+            match raw.read(cfa.wrapping_add(offset as u64)) {
+                Some(v) => bp = v,
+                None => break,
+            }
+        }
+        sp = cfa;
+        frames.push(ra);
+    }
+    frames
+}
+
+/// The common information entry, shared by the functions of a compilation unit
+struct Cie {
+    code_align: u64,
+    data_align: i64,
+    fde_encoding: u8,
+    augmented: bool,
+    initial: Vec<u8>,
+}
+
+impl Cie {
+    fn parse(data: &[u8], address: u64, pos: usize) -> Option<Self> {
+        let mut r = Reader::new(data, address, pos);
+        let length = r.u32()? as usize;
+        r.end = (pos + 4 + length).min(data.len());
+        if r.u32()? != 0 {
+            return None;
+        }
+        let version = r.u8()?;
+        let augmentation = r.c_str()?;
+        if augmentation.contains(&b'e') {
+            // the old `eh` augmentation has a pointer here
+            r.u64()?;
+        }
+        let code_align = r.uleb()?;
+        let data_align = r.sleb()?;
+        let _return_register = if version == 1 {
+            r.u8()? as u64
+        } else {
+            r.uleb()?
+        };
+        let mut fde_encoding = 0;
+        let augmented = augmentation.first() == Some(&b'z');
+        if augmented {
+            let size = r.uleb()? as usize;
+            let data_end = r.pos + size;
+            for c in &augmentation[1..] {
+                match c {
+                    b'R' => fde_encoding = r.u8()?,
+                    b'P' => {
+                        let encoding = r.u8()?;
+                        r.pointer(encoding)?;
+                    }
+                    b'L' => {
+                        r.u8()?;
+                    }
+                    _ => (),
+                }
+            }
+            r.pos = data_end;
+        }
+        let initial = data.get(r.pos..r.end)?.to_vec();
+
+        Some(Cie {
+            code_align,
+            data_align,
+            fde_encoding,
+            augmented,
+            initial,
+        })
+    }
+
+    /// Read the description of the function, the reader points after the CIE pointer
+    fn fde_rows<F>(&self, r: &mut Reader, file_offset: F, rows: &mut Vec<(u64, u64, Rule)>)
+    where
+        F: Fn(u64) -> Option<u64>,
+    {
+        let parsed = (|| {
+            let start = r.pointer(self.fde_encoding)?;
+            // only the format of the encoding applies to the size
+            let size = r.pointer(self.fde_encoding & 0x0f)?;
+            if self.augmented {
+                let size = r.uleb()? as usize;
+                r.pos += size;
+            }
+            Some((start, size))
+        })();
+        let (start, size) = match parsed {
+            Some(v) => v,
+            None => return,
+        };
+        // zero start is a discarded function
+        let offset = match file_offset(start) {
+            Some(v) if start != 0 => v,
+            _ => return,
+        };
+        let end = offset + size;
+
+        let initial = Rule {
+            cfa: Cfa::Unknown,
+            bp: None,
+        };
+        let mut state = State {
+            location: offset,
+            rule: initial,
+            stack: vec![],
+        };
+        let mut initial_r = Reader::new(&self.initial, 0, 0);
+        state.run(self, &mut initial_r, initial, &mut |_, _| ());
+        let initial = state.rule;
+        let first = rows.len();
+        state.run(self, r, initial, &mut |location, rule| {
+            rows.push((location, end, rule))
+        });
+        rows.push((state.location, end, state.rule));
+        // several rows at the same location, the last is effective
+        let mut i = first;
+        while i + 1 < rows.len() {
+            if rows[i].0 == rows[i + 1].0 {
+                rows.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+struct State {
+    location: u64,
+    rule: Rule,
+    stack: Vec<Rule>,
+}
+
+impl State {
+    /// Interpret the instructions, `emit` gets the row before each advance of the location
+    fn run<E>(&mut self, cie: &Cie, r: &mut Reader, initial: Rule, emit: &mut E)
+    where
+        E: FnMut(u64, Rule),
+    {
+        let _ = (|| {
+            while r.pos < r.end {
+                let op = r.u8()?;
+                let operand = u64::from(op & 0x3f);
+                match op >> 6 {
+                    1 => self.advance(operand * cie.code_align, emit),
+                    2 => {
+                        let offset = r.uleb()? as i64 * cie.data_align;
+                        self.set_offset(operand, Some(offset));
+                    }
+                    3 => self.set_offset(operand, initial.bp.filter(|_| operand == RBP)),
+                    _ => match op {
+                        0x00 => (),
+                        0x01 => {
+                            let location = r.pointer(cie.fde_encoding)?;
+                            let delta = location.checked_sub(self.location)?;
+                            self.advance(delta, emit);
+                        }
+                        0x02 => self.advance(r.u8()? as u64 * cie.code_align, emit),
+                        0x03 => self.advance(r.u16()? as u64 * cie.code_align, emit),
+                        0x04 => self.advance(r.u32()? as u64 * cie.code_align, emit),
+                        0x05 => {
+                            let register = r.uleb()?;
+                            let offset = r.uleb()? as i64 * cie.data_align;
+                            self.set_offset(register, Some(offset));
+                        }
+                        0x06 => {
+                            let register = r.uleb()?;
+                            self.set_offset(register, initial.bp.filter(|_| register == RBP));
+                        }
+                        0x07 | 0x08 => self.set_offset(r.uleb()?, None),
+                        0x09 => {
+                            self.set_offset(r.uleb()?, None);
+                            r.uleb()?;
+                        }
+                        0x0a => self.stack.push(self.rule),
+                        0x0b => self.rule = self.stack.pop()?,
+                        0x0c => {
+                            let register = r.uleb()?;
+                            let offset = r.uleb()? as i64;
+                            self.rule.cfa = cfa(register, offset);
+                        }
+                        0x0d => {
+                            let register = r.uleb()?;
+                            self.rule.cfa = match self.rule.cfa {
+                                Cfa::Sp(offset) | Cfa::Bp(offset) => cfa(register, offset),
+                                Cfa::Unknown => Cfa::Unknown,
+                            };
+                        }
+                        0x0e => self.set_cfa_offset(r.uleb()? as i64),
+                        0x0f => {
+                            let size = r.uleb()? as usize;
+                            r.pos += size;
+                            self.rule.cfa = Cfa::Unknown;
+                        }
+                        0x10 | 0x16 => {
+                            self.set_offset(r.uleb()?, None);
+                            let size = r.uleb()? as usize;
+                            r.pos += size;
+                        }
+                        0x11 => {
+                            let register = r.uleb()?;
+                            let offset = r.sleb()? * cie.data_align;
+                            self.set_offset(register, Some(offset));
+                        }
+                        0x12 => {
+                            let register = r.uleb()?;
+                            let offset = r.sleb()? * cie.data_align;
+                            self.rule.cfa = cfa(register, offset);
+                        }
+                        0x13 => self.set_cfa_offset(r.sleb()? * cie.data_align),
+                        0x14 => {
+                            self.set_offset(r.uleb()?, None);
+                            r.uleb()?;
+                        }
+                        0x15 => {
+                            self.set_offset(r.uleb()?, None);
+                            r.sleb()?;
+                        }
+                        0x2e => {
+                            r.uleb()?;
+                        }
+                        0x2f => {
+                            let register = r.uleb()?;
+                            let offset = -(r.uleb()? as i64) * cie.data_align;
+                            self.set_offset(register, Some(offset));
+                        }
+                        // unknown, the rest can't be interpreted
+                        _ => return None,
+                    },
+                }
+            }
+            Some(())
+        })();
+    }
+
+    fn advance<E>(&mut self, delta: u64, emit: &mut E)
+    where
+        E: FnMut(u64, Rule),
+    {
+        emit(self.location, self.rule);
+        self.location += delta;
+    }
+
+    /// Only `rbp` matters, the return address is always right below the CFA
+    fn set_offset(&mut self, register: u64, offset: Option<i64>) {
+        if register == RBP {
+            self.rule.bp = offset;
+        }
+    }
+
+    fn set_cfa_offset(&mut self, offset: i64) {
+        self.rule.cfa = match self.rule.cfa {
+            Cfa::Sp(_) => Cfa::Sp(offset),
+            Cfa::Bp(_) => Cfa::Bp(offset),
+            Cfa::Unknown => Cfa::Unknown,
+        };
+    }
+}
+
+fn cfa(register: u64, offset: i64) -> Cfa {
+    match register {
+        RSP => Cfa::Sp(offset),
+        RBP => Cfa::Bp(offset),
+        _ => Cfa::Unknown,
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    /// Where the data is loaded, for the relative pointers
+    address: u64,
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], address: u64, pos: usize) -> Self {
+        Reader {
+            data,
+            address,
+            pos,
+            end: data.len(),
+        }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let end = self.pos.checked_add(N)?;
+        if end > self.end {
+            return None;
+        }
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(self.data.get(self.pos..end)?);
+        self.pos = end;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes::<1>().map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= u64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= i64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+
+    fn c_str(&mut self) -> Option<&'a [u8]> {
+        let rest = self.data.get(self.pos..self.end)?;
+        let length = rest.iter().position(|c| *c == 0)?;
+        self.pos += length + 1;
+        Some(&rest[..length])
+    }
+
+    /// The `DW_EH_PE_*` encoded pointer, only absolute and relative to itself,
+    /// the indirect pointer is not dereferenced
+    fn pointer(&mut self, encoding: u8) -> Option<u64> {
+        let address = self.address + self.pos as u64;
+        let value = match encoding & 0x0f {
+            0x00 | 0x04 => self.u64()?,
+            0x01 => self.uleb()?,
+            0x02 => self.u16()? as u64,
+            0x03 => self.u32()? as u64,
+            0x09 => self.sleb()? as u64,
+            0x0a => self.u16()? as i16 as u64,
+            0x0b => self.u32()? as i32 as u64,
+            0x0c => self.u64()?,
+            _ => return None,
+        };
+        match encoding & 0x70 {
+            0x00 => Some(value),
+            0x10 => Some(address.wrapping_add(value)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use event::RawStack;
+
+    use super::{unwind, Cfa, Rule, UnwindTable};
+
+    /// A function at `0x1000` of size `0x20`: `push rbp; mov rbp, rsp; ...; pop rbp; ret`
+    fn eh_frame() -> Vec<u8> {
+        let mut cie = vec![0, 0, 0, 0, 1];
+        cie.extend_from_slice(b"zR\0");
+        // code align 1, data align -8, return address register 16
+        cie.extend_from_slice(&[0x01, 0x78, 0x10]);
+        // pcrel sdata4
+        cie.extend_from_slice(&[0x01, 0x1b]);
+        // def_cfa rsp+8, offset r16 at cfa-8
+        cie.extend_from_slice(&[0x0c, 0x07, 0x08, 0x90, 0x01, 0x00, 0x00]);
+
+        let mut data = (cie.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&cie);
+        let fde_pos = data.len();
+        let mut fde = ((fde_pos + 4) as u32).to_le_bytes().to_vec();
+        let start = 0x1000i32 - (0x400 + fde_pos as i32 + 8);
+        fde.extend_from_slice(&start.to_le_bytes());
+        fde.extend_from_slice(&0x20u32.to_le_bytes());
+        fde.push(0);
+        // advance 1, def_cfa_offset 16, offset rbp at cfa-16
+        fde.extend_from_slice(&[0x41, 0x0e, 0x10, 0x86, 0x02]);
+        // advance 3, def_cfa_register rbp
+        fde.extend_from_slice(&[0x43, 0x0d, 0x06]);
+        // advance 0x1a, def_cfa rsp+8
+        fde.extend_from_slice(&[0x5a, 0x0c, 0x07, 0x08, 0x00]);
+        data.extend_from_slice(&(fde.len() as u32).to_le_bytes());
+        data.extend_from_slice(&fde);
+        data.extend_from_slice(&[0; 4]);
+        data
+    }
+
+    #[test]
+    fn rules() {
+        // loaded at `0x400`, the code is at the same offset in the file
+        let table = UnwindTable::parse(&eh_frame(), 0x400, Some);
+        assert_eq!(table.len(), 4);
+        let rule = |cfa, bp| Some(Rule { cfa, bp });
+        assert_eq!(table.find(0xfff), None);
+        assert_eq!(table.find(0x1000), rule(Cfa::Sp(8), None));
+        assert_eq!(table.find(0x1001), rule(Cfa::Sp(16), Some(-16)));
+        assert_eq!(table.find(0x1010), rule(Cfa::Bp(16), Some(-16)));
+        assert_eq!(table.find(0x101e), rule(Cfa::Sp(8), Some(-16)));
+        assert_eq!(table.find(0x1020), None);
+    }
+
+    #[test]
+    fn frames() {
+        let table = UnwindTable::parse(&eh_frame(), 0x400, Some);
+        // in the body of the function called from `0x1005`, which is called from `0x2000`,
+        // then the frame of something without the rules and with the frame pointer
+        let words = [0x7020, 0x1005, 0, 0, 0x7040, 0x2000, 0, 0, 0, 0x3000];
+        let data = words.iter().flat_map(|w: &u64| w.to_ne_bytes()).collect();
+        let raw = RawStack {
+            ip: 0x1010,
+            sp: 0x7000,
+            bp: 0x7000,
+            data,
+        };
+        let frames = unwind(&raw, |ip| table.find(ip));
+        assert_eq!(frames, [0x1010, 0x1005, 0x2000, 0x3000]);
+    }
+}
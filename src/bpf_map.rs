@@ -0,0 +1,144 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+//! Access to bpf maps by file descriptor, it does not borrow the skeleton,
+//! so it can be moved into the ring buffer callback or into another thread.
+
+use std::{fs, io, mem};
+
+use event::{Stack, StackSource, EventKind, DISCRIMINANT_LIMIT};
+
+const BPF_MAP_LOOKUP_ELEM: libc::c_long = 1;
+const BPF_MAP_DELETE_ELEM: libc::c_long = 3;
+const BPF_MAP_GET_NEXT_KEY: libc::c_long = 4;
+
+#[repr(C)]
+struct MapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value_or_next_key: u64,
+    flags: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct MapFd(pub i32);
+
+impl MapFd {
+    fn call(&self, cmd: libc::c_long, key: *const u8, value: *mut u8) -> io::Result<()> {
+        let attr = MapElemAttr {
+            map_fd: self.0 as u32,
+            _pad: 0,
+            key: key as u64,
+            value_or_next_key: value as u64,
+            flags: 0,
+        };
+        let size = mem::size_of::<MapElemAttr>();
+        let c = unsafe { libc::syscall(libc::SYS_bpf, cmd, &attr as *const MapElemAttr, size) };
+        if c < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The `value` must be exactly the size of the map value
+    pub fn lookup(&self, key: &[u8], value: &mut [u8]) -> io::Result<()> {
+        self.call(BPF_MAP_LOOKUP_ELEM, key.as_ptr(), value.as_mut_ptr())
+    }
+
+    pub fn delete(&self, key: &[u8]) -> io::Result<()> {
+        self.call(BPF_MAP_DELETE_ELEM, key.as_ptr(), std::ptr::null_mut())
+    }
+
+    /// Iterate the keys, `None` gives the first key
+    pub fn next_key(&self, key: Option<&[u8]>, next_key: &mut [u8]) -> io::Result<()> {
+        let key = key.map(<[u8]>::as_ptr).unwrap_or(std::ptr::null());
+        self.call(BPF_MAP_GET_NEXT_KEY, key, next_key.as_mut_ptr())
+    }
+
+    /// Iterate the keys of size `N`, if the map is modified meanwhile
+    /// the iteration might skip or repeat some keys
+    pub fn keys<const N: usize>(&self) -> impl Iterator<Item = [u8; N]> + '_ {
+        let mut current = None::<[u8; N]>;
+        std::iter::from_fn(move || {
+            let mut next = [0; N];
+            self.next_key(current.as_ref().map(|k| &k[..]), &mut next)
+                .ok()?;
+            current = Some(next);
+            current
+        })
+    }
+}
+
+/// The `stacks` map of the bpf program
+pub struct StackMap(pub MapFd);
+
+impl StackSource for StackMap {
+    fn lookup(&mut self, stack_id: u64) -> Option<Stack> {
+        let mut value = [0; 0x400];
+        match self.0.lookup(&stack_id.to_ne_bytes(), &mut value) {
+            Ok(()) => Stack::from_slice(&value),
+            Err(error) => {
+                log::debug!("failed to lookup stack {:016x}: {}", stack_id, error);
+                None
+            }
+        }
+    }
+}
+
+/// The number of cpus the kernel may ever bring up, a per cpu map has a value for each
+fn possible_cpus() -> io::Result<usize> {
+    // the format is a list of ranges, like `0-3,5,7-8`
+    let s = fs::read_to_string("/sys/devices/system/cpu/possible")?;
+    let last = s
+        .trim()
+        .split([',', '-'])
+        .filter_map(|n| n.parse::<usize>().ok())
+        .max()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, s.clone()))?;
+    Ok(last + 1)
+}
+
+/// The `lost_events` map of the bpf program, per cpu counters indexed by the discriminant
+pub struct LostEventsMap {
+    fd: MapFd,
+    cpus: usize,
+    last: [u64; DISCRIMINANT_LIMIT],
+}
+
+impl LostEventsMap {
+    pub fn new(fd: MapFd) -> io::Result<Self> {
+        Ok(LostEventsMap {
+            fd,
+            cpus: possible_cpus()?,
+            last: [0; DISCRIMINANT_LIMIT],
+        })
+    }
+
+    /// The number of events of each kind lost since the previous call
+    pub fn delta(&mut self) -> Vec<(&'static str, u64)> {
+        let mut value = vec![0; self.cpus * 8];
+        let mut delta = vec![];
+        for (discriminant, last) in self.last.iter_mut().enumerate() {
+            if let Err(error) = self
+                .fd
+                .lookup(&(discriminant as u32).to_ne_bytes(), &mut value)
+            {
+                log::error!("failed to read lost events: {}", error);
+                break;
+            }
+            let total = value
+                .chunks(8)
+                .map(|c| u64::from_ne_bytes(c.try_into().unwrap()))
+                .sum::<u64>();
+            let count = total.wrapping_sub(*last);
+            *last = total;
+            if count != 0 {
+                let name = EventKind::name(discriminant as u32).unwrap_or("unknown");
+                delta.push((name, count));
+            }
+        }
+        delta
+    }
+}
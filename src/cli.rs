@@ -0,0 +1,493 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+//! The command line and the config file, the command line overrides the file.
+
+use std::{fs, path::PathBuf, time::Duration};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+use server::{
+    server::{self as http, Listen, Tls},
+    Categories, CategoryRule, FoldRules, Folding, Sampling,
+};
+
+/// The size of `App::event_queue`, the bpf object is built with it
+const RING_BUFFER_SIZE: usize = 0x8000000;
+
+#[derive(Parser)]
+#[command(version, about = "Memory profiler based on bpf")]
+pub struct Cli {
+    /// The config file in toml format
+    #[arg(long, short, global = true)]
+    pub config: Option<PathBuf>,
+    /// Default is `serve`
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Attach to the kernel and serve the reports over http
+    Serve {
+        #[command(flatten)]
+        bpf: BpfArgs,
+        #[command(flatten)]
+        tracker: TrackerArgs,
+        #[command(flatten)]
+        http: HttpArgs,
+        /// Store the page events in the file on exit, `target/dump` if the path is omitted
+        #[arg(long, num_args = 0..=1, default_missing_value = "target/dump")]
+        dump: Option<PathBuf>,
+        /// Do not load the symbol tables, the frames are the offsets in the files
+        /// and the build ids, fill the names later with `symbolize`
+        #[arg(long)]
+        no_symbols: bool,
+    },
+    /// Attach to the kernel and write the events into the file
+    Record {
+        #[command(flatten)]
+        bpf: BpfArgs,
+        /// Default is `target/recording`
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Read the recorded events and serve the reports over http
+    Replay {
+        input: PathBuf,
+        #[command(flatten)]
+        sampling: SamplingArgs,
+        #[command(flatten)]
+        tracker: TrackerArgs,
+        #[command(flatten)]
+        http: HttpArgs,
+    },
+    /// Read the recorded events and print the report in json
+    Report {
+        input: PathBuf,
+        #[command(flatten)]
+        sampling: SamplingArgs,
+        #[command(flatten)]
+        tracker: TrackerArgs,
+        /// Print only the totals
+        #[arg(long)]
+        short: bool,
+        /// In KiB, the smaller branches of the tree are merged
+        #[arg(long, default_value_t = 512)]
+        threshold: u64,
+        /// Reverse the tree
+        #[arg(long)]
+        reverse: bool,
+        /// Default is the standard output
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Fill the function names into the json report taken with `--no-symbols`
+    Symbolize {
+        input: PathBuf,
+        /// The directory of the binaries or their debug files, either named by the build id
+        /// like in the cache of debuginfod, `.build-id/xx/rest.debug` or `<build id>/debuginfo`,
+        /// or by the name of the executable
+        #[arg(long)]
+        store: PathBuf,
+        /// Default is the standard output
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Args)]
+pub struct BpfArgs {
+    /// Profile the process instead of waiting for one started with `BPF_MEM` environment variable
+    #[arg(long)]
+    pub pid: Option<u32>,
+    /// Capture the kernel stack in addition to the user stack
+    #[arg(long)]
+    pub kernel_stack: bool,
+    /// Store stacks in a bpf map and send only their ids
+    #[arg(long)]
+    pub stack_id: bool,
+    /// Count allocations per stack in the kernel instead of sending events
+    #[arg(long)]
+    pub aggregate_in_kernel: bool,
+    /// Copy the user stack and unwind it with `.eh_frame` in the user space,
+    /// for binaries built without frame pointers
+    #[arg(long)]
+    pub unwind: bool,
+    /// Capture the address of user page faults to report the usage per mapping region
+    #[arg(long)]
+    pub page_faults: bool,
+    /// Capture `mmap`, `munmap`, `mremap` and `brk` to report the virtual memory per stack
+    #[arg(long)]
+    pub vm_syscalls: bool,
+    /// Attach uprobes to `malloc`, `free` and friends of the mapped libraries
+    /// to report the live heap per stack
+    #[arg(long)]
+    pub heap_probes: bool,
+    /// Attach to the USDT probes of the `bpf-mem-alloc` global allocator of a Rust program
+    #[arg(long)]
+    pub alloc_probes: bool,
+    #[command(flatten)]
+    pub sampling: SamplingArgs,
+}
+
+#[derive(Args)]
+pub struct SamplingArgs {
+    /// Take one in `n` page allocations
+    #[arg(long, value_name = "N", conflicts_with = "sample_kib")]
+    pub sample_every: Option<u32>,
+    /// Take one page allocation per `n` KiB allocated
+    #[arg(long, value_name = "N")]
+    pub sample_kib: Option<u32>,
+}
+
+#[derive(Args)]
+pub struct TrackerArgs {
+    #[arg(long)]
+    pub tracker: Option<TrackerKind>,
+}
+
+#[derive(Args)]
+pub struct HttpArgs {
+    /// Either `ip:port` or `unix:<path>`, default is `0.0.0.0:17832`
+    #[arg(long)]
+    pub listen: Option<Listen>,
+    /// Pem file, serve https
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// Pem file, serve https
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// The file containing the token, every request must carry it as a bearer token
+    #[arg(long)]
+    pub token_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrackerKind {
+    /// Current usage per stack, the cheapest
+    #[default]
+    Aggregator,
+    /// Keeps the history of each page
+    History,
+    /// Current owner of each page
+    AllocationState,
+    /// All of the above, compares them at `/v1/consistency`
+    CrossCheck,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub target: TargetConfig,
+    pub bpf: BpfConfig,
+    pub tracker: TrackerKind,
+    pub resolver: ResolverConfig,
+    pub http: HttpConfig,
+    pub timeline: TimelineConfig,
+    pub tree: TreeConfig,
+    pub output: OutputConfig,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TargetConfig {
+    pub pid: Option<u32>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BpfConfig {
+    pub kernel_stack: bool,
+    pub stack_id: bool,
+    pub aggregate_in_kernel: bool,
+    pub unwind: bool,
+    pub page_faults: bool,
+    pub vm_syscalls: bool,
+    pub heap_probes: bool,
+    pub alloc_probes: bool,
+    pub sample_every: Option<u32>,
+    pub sample_kib: Option<u32>,
+    /// In bytes, the bpf object is built with a fixed size, any other size is rejected
+    pub ring_buffer_size: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolverConfig {
+    pub refresh_interval_secs: u64,
+    /// Load the symbol tables, otherwise the report has only the offsets and the build ids;
+    /// Rust, C++ and Go names are demangled, Swift names are shown mangled, as in the symbol table
+    pub symbols: bool,
+    /// The categories of the functions in the report, the first matching rule wins
+    pub categories: Vec<CategoryRule>,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            refresh_interval_secs: 5,
+            symbols: true,
+            categories: vec![],
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub listen: Option<String>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub token_file: Option<PathBuf>,
+}
+
+/// The short report sampled over time, served at `/v1/timeline`
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimelineConfig {
+    pub interval_secs: u64,
+    /// The number of samples to keep, the oldest are dropped
+    pub capacity: usize,
+}
+
+impl Default for TimelineConfig {
+    fn default() -> Self {
+        let default = server::TimelineConfig::default();
+        TimelineConfig {
+            interval_secs: default.interval.as_secs(),
+            capacity: default.capacity,
+        }
+    }
+}
+
+/// The default folding of `/v1/tree` and `report`, the patterns are regexes on the function names
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TreeConfig {
+    /// Drop the matching frames
+    pub drop: Option<String>,
+    /// Merge the consecutive frames of the same function
+    pub collapse_recursion: bool,
+    /// Start the tree at the first matching frame
+    pub focus: Option<String>,
+    /// Hide everything below the first matching frame
+    pub leaf: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// Store the page events on exit of `serve`
+    pub dump: Option<PathBuf>,
+    /// The file `record` writes
+    pub recording: PathBuf,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            dump: None,
+            recording: "target/recording".into(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file if any and applies the command line on top of it
+    pub fn load(cli: &Cli) -> Result<Self, String> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let s = fs::read_to_string(path)
+                    .map_err(|error| format!("failed to read {}: {}", path.display(), error))?;
+                toml::from_str::<Config>(&s)
+                    .map_err(|error| format!("bad config {}: {}", path.display(), error))?
+            }
+            None => Config::default(),
+        };
+
+        match &cli.command {
+            None => (),
+            Some(Command::Serve {
+                bpf,
+                tracker,
+                http,
+                dump,
+                no_symbols,
+            }) => {
+                config.apply_bpf(bpf);
+                config.apply_tracker(tracker);
+                config.apply_http(http);
+                if dump.is_some() {
+                    config.output.dump = dump.clone();
+                }
+                config.resolver.symbols &= !no_symbols;
+            }
+            Some(Command::Record { bpf, output }) => {
+                config.apply_bpf(bpf);
+                if let Some(output) = output {
+                    config.output.recording = output.clone();
+                }
+            }
+            Some(Command::Replay {
+                sampling,
+                tracker,
+                http,
+                ..
+            }) => {
+                config.apply_sampling(sampling);
+                config.apply_tracker(tracker);
+                config.apply_http(http);
+            }
+            Some(Command::Report {
+                sampling, tracker, ..
+            }) => {
+                config.apply_sampling(sampling);
+                config.apply_tracker(tracker);
+            }
+            Some(Command::Symbolize { .. }) => (),
+        }
+
+        if config.bpf.sample_every.is_some() && config.bpf.sample_kib.is_some() {
+            return Err("`sample_every` and `sample_kib` are mutually exclusive".to_string());
+        }
+        if config.bpf.unwind && (config.bpf.stack_id || config.bpf.aggregate_in_kernel) {
+            return Err("`unwind` needs the stacks in the events, not in the kernel".to_string());
+        }
+        // the loader cannot resize the map of the bpf object
+        match config.bpf.ring_buffer_size {
+            Some(size) if size != RING_BUFFER_SIZE => {
+                return Err(format!(
+                    "`ring_buffer_size` {:#x} is not supported, the bpf object is built with {:#x}",
+                    size, RING_BUFFER_SIZE,
+                ))
+            }
+            _ => (),
+        }
+        if config.resolver.refresh_interval_secs == 0 {
+            return Err("`refresh_interval_secs` must be positive".to_string());
+        }
+        if config.timeline.interval_secs == 0 {
+            return Err("`timeline.interval_secs` must be positive".to_string());
+        }
+        if let Err(error) = Folding::new(&config.tree_rules()) {
+            return Err(format!("bad `tree` pattern: {}", error));
+        }
+        config.token()?;
+
+        Ok(config)
+    }
+
+    fn apply_bpf(&mut self, args: &BpfArgs) {
+        if args.pid.is_some() {
+            self.target.pid = args.pid;
+        }
+        self.bpf.kernel_stack |= args.kernel_stack;
+        self.bpf.stack_id |= args.stack_id;
+        self.bpf.aggregate_in_kernel |= args.aggregate_in_kernel;
+        self.bpf.unwind |= args.unwind;
+        self.bpf.page_faults |= args.page_faults;
+        self.bpf.vm_syscalls |= args.vm_syscalls;
+        self.bpf.heap_probes |= args.heap_probes;
+        self.bpf.alloc_probes |= args.alloc_probes;
+        self.apply_sampling(&args.sampling);
+    }
+
+    fn apply_sampling(&mut self, args: &SamplingArgs) {
+        if args.sample_every.is_some() || args.sample_kib.is_some() {
+            self.bpf.sample_every = args.sample_every;
+            self.bpf.sample_kib = args.sample_kib;
+        }
+    }
+
+    fn apply_tracker(&mut self, args: &TrackerArgs) {
+        if let Some(tracker) = args.tracker {
+            self.tracker = tracker;
+        }
+    }
+
+    fn apply_http(&mut self, args: &HttpArgs) {
+        if let Some(listen) = &args.listen {
+            self.http.listen = Some(listen.to_string());
+        }
+        if args.tls_cert.is_some() {
+            self.http.tls_cert = args.tls_cert.clone();
+            self.http.tls_key = args.tls_key.clone();
+        }
+        if args.token_file.is_some() {
+            self.http.token_file = args.token_file.clone();
+        }
+    }
+
+    pub fn sampling(&self) -> Sampling {
+        match (self.bpf.sample_every, self.bpf.sample_kib) {
+            (Some(n), _) => Sampling::Every(n),
+            (None, Some(n)) => Sampling::Kib(n),
+            (None, None) => Sampling::All,
+        }
+    }
+
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.resolver.refresh_interval_secs)
+    }
+
+    pub fn tree_rules(&self) -> FoldRules {
+        FoldRules {
+            drop: self.tree.drop.clone(),
+            collapse_recursion: self.tree.collapse_recursion,
+            focus: self.tree.focus.clone(),
+            leaf: self.tree.leaf.clone(),
+        }
+    }
+
+    pub fn categories(&self) -> Result<Categories, String> {
+        Categories::new(&self.resolver.categories)
+            .map_err(|error| format!("bad category rule: {}", error))
+    }
+
+    /// The token is in a file, so it does not appear in the process list
+    fn token(&self) -> Result<Option<String>, String> {
+        let path = match &self.http.token_file {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let token = fs::read_to_string(path)
+            .map_err(|error| format!("failed to read {}: {}", path.display(), error))?;
+        let token = token.trim();
+        // an empty token would let anyone in
+        if token.is_empty() {
+            return Err(format!("the token file {} is empty", path.display()));
+        }
+        Ok(Some(token.to_string()))
+    }
+
+    pub fn http(&self) -> Result<http::Config, String> {
+        let listen = match &self.http.listen {
+            Some(listen) => listen.parse()?,
+            None => Listen::default(),
+        };
+        let tls = match (&self.http.tls_cert, &self.http.tls_key) {
+            (Some(cert), Some(key)) => Some(Tls {
+                cert: cert.clone(),
+                key: key.clone(),
+            }),
+            (None, None) => None,
+            _ => return Err("both `tls_cert` and `tls_key` are required".to_string()),
+        };
+        let token = self.token()?;
+
+        let timeline = server::TimelineConfig {
+            interval: Duration::from_secs(self.timeline.interval_secs),
+            capacity: self.timeline.capacity,
+        };
+
+        Ok(http::Config {
+            listen,
+            tls,
+            token,
+            timeline,
+            tree: self.tree_rules(),
+        })
+    }
+}
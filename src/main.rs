@@ -11,15 +11,44 @@ use ebpf_user as ebpf;
 #[cfg(feature = "kern")]
 ebpf::license!("Dual MIT/GPL");
 
+#[cfg(feature = "user")]
+mod bpf_map;
+
+#[cfg(feature = "user")]
+mod cli;
+
+#[cfg(feature = "user")]
+mod uprobe;
+
 #[cfg(any(feature = "kern", feature = "user"))]
 #[derive(ebpf::BpfApp)]
 pub struct App {
     #[hashmap(size = 1)]
     pub pid: ebpf::HashMapRef<4, 4>,
-    #[hashmap(size = 1)]
-    pub lost_events: ebpf::HashMapRef<4, 4>,
+    /// Indexed by the discriminant of the event, the size is `DISCRIMINANT_LIMIT`
+    #[array_percpu(size = 0x20)]
+    pub lost_events: ebpf::ArrayPerCpuRef<8>,
+    #[hashmap(size = 0x10)]
+    pub config: ebpf::HashMapRef<4, 4>,
     #[array_percpu(size = 1)]
     pub stack: ebpf::ArrayPerCpuRef<0x400>,
+    #[array_percpu(size = 1)]
+    pub kernel_stack: ebpf::ArrayPerCpuRef<0x100>,
+    #[array_percpu(size = 1)]
+    pub sample_counter: ebpf::ArrayPerCpuRef<8>,
+    #[array_percpu(size = 1)]
+    pub stack_value: ebpf::ArrayPerCpuRef<0x400>,
+    #[hashmap(size = 0x8000)]
+    pub stacks: ebpf::HashMapRef<8, 0x400>,
+    #[hashmap(size = 0x100000)]
+    pub pages: ebpf::HashMapRef<8, 0x10>,
+    /// The value is `0x10 * PAGE_ORDERS` bytes
+    #[hashmap(size = 0x8000)]
+    pub usage: ebpf::HashMapRef<8, 0xb0>,
+    /// The threads in the `mmap` of code, without `VM_SYSCALLS` only their exits are reported
+    #[hashmap(size = 0x1000)]
+    pub exec_mmap: ebpf::HashMapRef<4, 4>,
+    /// Must match `cli::RING_BUFFER_SIZE`
     #[ringbuf(size = 0x8000000)]
     pub event_queue: ebpf::RingBufferRef,
     #[prog("tracepoint/syscalls/sys_enter_execve")]
@@ -36,21 +65,135 @@ pub struct App {
     pub add_to_page_cache: ebpf::ProgRef,
     #[prog("tracepoint/filemap/mm_filemap_delete_from_page_cache")]
     pub remove_from_page_cache: ebpf::ProgRef,
+    #[prog("tracepoint/exceptions/page_fault_user")]
+    pub page_fault_user: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_enter_mmap")]
+    pub sys_enter_mmap: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_exit_mmap")]
+    pub sys_exit_mmap: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_enter_munmap")]
+    pub sys_enter_munmap: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_exit_munmap")]
+    pub sys_exit_munmap: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_enter_mremap")]
+    pub sys_enter_mremap: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_exit_mremap")]
+    pub sys_exit_mremap: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_enter_brk")]
+    pub sys_enter_brk: ebpf::ProgRef,
+    #[prog("tracepoint/syscalls/sys_exit_brk")]
+    pub sys_exit_brk: ebpf::ProgRef,
+    /// Attached by `uprobe::spawn` to the allocator functions, the target is known only at runtime
+    #[prog("uprobe")]
+    pub heap_call: ebpf::ProgRef,
+    #[prog("uretprobe")]
+    pub heap_return: ebpf::ProgRef,
+    /// Attached to the USDT probes of `bpf-mem-alloc`
+    #[prog("uprobe")]
+    pub alloc_probe: ebpf::ProgRef,
 }
 
+/// Keys of the `config` map, the user space writes them before attaching the program
+#[cfg(any(feature = "kern", feature = "user"))]
+mod config {
+    /// Non zero value means capture the kernel stack in addition to the user stack
+    pub const KERNEL_STACK: u32 = 0;
+    /// Non zero value means store stacks in the `stacks` map and send only their ids
+    pub const STACK_ID: u32 = 1;
+    /// Non zero value means count allocations per stack in the `usage` map instead of sending events
+    pub const AGGREGATE: u32 = 2;
+    /// Value `n` greater than one means send only one in `n` page allocations
+    pub const SAMPLE_EVERY: u32 = 3;
+    /// Value `n` greater than four means send one page allocation per `n` KiB allocated
+    pub const SAMPLE_KIB: u32 = 4;
+    /// Non zero value means send the address of each user page fault
+    pub const PAGE_FAULT: u32 = 5;
+    /// Non zero value means send the syscalls which change the address space
+    pub const VM_SYSCALLS: u32 = 6;
+    /// Non zero value means send the user registers and a copy of the user stack
+    /// instead of the frames
+    pub const UNWIND: u32 = 7;
+}
+
+/// Must match the size of `App::kernel_stack` array
+#[cfg(feature = "kern")]
+const KERNEL_STACK_MAX_DEPTH: usize = 0x20;
+
 #[cfg(feature = "kern")]
 use {
+    core::sync::atomic::{AtomicU64, Ordering},
     ebpf::helpers,
     event::{
-        AddToPageCache, CacheAlloc, CacheAllocNode, CacheFree, KFree, KMAlloc, KMAllocNode,
-        PageAlloc, PageFree, PageFreeBatched, PercpuAlloc, PercpuFree, RemoveFromPageCache,
-        RssStat,
+        AddToPageCache, AllocProbe, CacheAlloc, CacheAllocNode, CacheFree, HeapCall, HeapReturn,
+        KFree, KMAlloc, KMAllocNode, MmapExec, PageAlloc, PageFaultUser, PageFree, PageFreeBatched,
+        PercpuAlloc, PercpuFree, RemoveFromPageCache, RssStat, SysEnterBrk, SysEnterMmap,
+        SysEnterMremap, SysEnterMunmap, SysExit,
     },
-    event::{Pod, STACK_MAX_DEPTH},
+    event::{Pod, STACK_MAX_DEPTH, STACK_ID_FLAG, STACK_RAW_FLAG, RAW_STACK_SIZE, PAGE_ORDERS},
 };
 
+/// The helpers which `ebpf-kern` doesn't wrap, called by their numbers
+#[cfg(feature = "kern")]
+mod raw_helpers {
+    use core::mem;
+
+    pub unsafe fn get_current_task_btf() -> u64 {
+        let f: unsafe extern "C" fn() -> u64 = mem::transmute(158usize);
+        f()
+    }
+
+    /// Requires linux 5.15
+    pub unsafe fn task_pt_regs(task: u64) -> u64 {
+        let f: unsafe extern "C" fn(u64) -> u64 = mem::transmute(175usize);
+        f(task)
+    }
+
+    pub unsafe fn probe_read_user(dst: *mut u8, size: u32, src: u64) -> i64 {
+        let f: unsafe extern "C" fn(*mut u8, u32, u64) -> i64 = mem::transmute(112usize);
+        f(dst, size, src)
+    }
+
+    pub unsafe fn probe_read_kernel(dst: *mut u8, size: u32, src: u64) -> i64 {
+        let f: unsafe extern "C" fn(*mut u8, u32, u64) -> i64 = mem::transmute(113usize);
+        f(dst, size, src)
+    }
+
+    /// The flag of `map_update_elem`, fails with `EEXIST` if the key is in the map
+    pub const BPF_NOEXIST: u64 = 1;
+    pub const EEXIST: i64 = 17;
+
+    // ebpf-kern 0.2.0, `map.rs`: `pub struct HashMapRef<K, V> { inner: usize }`, the address
+    // of the map which the loader relocates, its own `insert` passes the very same value
+    const _: () =
+        assert!(mem::size_of::<super::ebpf::HashMapRef<8, 0x10>>() == mem::size_of::<usize>());
+
+    /// `HashMapRef` inserts only with `BPF_ANY`, it holds nothing but the address of the map,
+    /// see the assertion above, recheck the layout when updating `ebpf-kern`
+    pub unsafe fn map_insert_new<const K: usize, const V: usize>(
+        map: &super::ebpf::HashMapRef<K, V>,
+        key: &[u8; K],
+        value: &[u8; V],
+    ) -> i64 {
+        let map = mem::transmute_copy::<_, usize>(map);
+        let f: unsafe extern "C" fn(usize, *const u8, *const u8, u64) -> i64 =
+            mem::transmute(2usize);
+        match f(map, key.as_ptr(), value.as_ptr(), BPF_NOEXIST) {
+            c if c == -EEXIST => 0,
+            c => c,
+        }
+    }
+}
+
 #[cfg(feature = "kern")]
 impl App {
+    #[inline(always)]
+    fn config_value(&self, key: u32) -> u32 {
+        self.config
+            .get(&key.to_ne_bytes())
+            .map(|v| u32::from_ne_bytes(*v))
+            .unwrap_or(0)
+    }
+
     #[inline(always)]
     fn check_no_pid(&self) -> Result<(), i32> {
         if let Some(&pid_bytes) = self.pid.get(&0u32.to_ne_bytes()) {
@@ -210,27 +353,15 @@ impl App {
     }
 
     #[inline(always)]
-    fn inc_lost(&mut self) -> Result<(), i32> {
-        if let Some(cnt_bytes) = self.lost_events.get_mut(&0u32.to_ne_bytes()) {
-            if cnt_bytes[0] < u8::MAX {
-                cnt_bytes[0] += 1;
-            } else if cnt_bytes[1] < u8::MAX {
-                cnt_bytes[0] = 0;
-                cnt_bytes[1] += 1;
-            } else if cnt_bytes[2] < u8::MAX {
-                cnt_bytes[0] = 0;
-                cnt_bytes[1] = 0;
-                cnt_bytes[2] += 1;
-            } else if cnt_bytes[3] < u8::MAX {
-                cnt_bytes[0] = 0;
-                cnt_bytes[1] = 0;
-                cnt_bytes[2] = 0;
-                cnt_bytes[3] += 1;
-            }
-            Ok(())
-        } else {
-            self.lost_events
-                .insert(0u32.to_ne_bytes(), 1u32.to_le_bytes())
+    fn inc_lost<T>(&mut self)
+    where
+        T: Pod,
+    {
+        let index = T::DISCRIMINANT.unwrap_or(0);
+        if let Some(counter) = self.lost_events.get_mut(index) {
+            // the program might be interrupted on the same cpu by another one
+            let counter = counter.as_mut_ptr() as *const AtomicU64;
+            unsafe { (*counter).fetch_add(1, Ordering::Relaxed) };
         }
     }
 
@@ -263,6 +394,17 @@ impl App {
     where
         T: Pod,
     {
+        if need_stack && self.config_value(config::UNWIND) != 0 {
+            return self.output_raw_stack::<T>(ctx, pid);
+        }
+        if need_stack && self.config_value(config::STACK_ID) != 0 {
+            let kernel = self.config_value(config::KERNEL_STACK) != 0;
+            // if the map is full, fall back to sending the whole stack
+            if let Some(stack_id) = self.store_stack(&ctx, kernel) {
+                return self.output_stack_id::<T>(ctx, pid, stack_id);
+            }
+        }
+
         let stack_len = if need_stack {
             self.stack
                 .get_mut(0)
@@ -275,6 +417,20 @@ impl App {
             0
         };
 
+        // kernel frames go first, they are deeper than the user space frames
+        let kernel_stack_len = if need_stack && self.config_value(config::KERNEL_STACK) != 0 {
+            self.kernel_stack
+                .get_mut(0)
+                .map(|s| {
+                    let size = ctx.get_kernel_stack(s).unwrap_or(0);
+                    ((size / 8) as usize).min(KERNEL_STACK_MAX_DEPTH)
+                })
+                .unwrap_or(0)
+        } else {
+            0
+        };
+
+        let stack_len = stack_len + kernel_stack_len;
         let stack_len = if stack_len > 64 {
             STACK_MAX_DEPTH
         } else if stack_len > 32 {
@@ -297,22 +453,22 @@ impl App {
 
         let size = 0x10 + T::SIZE + 0x08 + stack_len * 8;
         let mut data = self.event_queue.reserve(size).map_err(|e| {
-            let _ = self.inc_lost();
+            self.inc_lost::<T>();
             e
         })?;
-        let data_mut = data.as_mut();
-        ctx.read_into(0x00, &mut data_mut[..0x08]);
-        data_mut[0x08..0x0c].clone_from_slice(&pid.to_ne_bytes());
-        data_mut[0x0c..0x10].clone_from_slice(&T::DISCRIMINANT.unwrap_or(0).to_ne_bytes());
-        let data_mut = &mut data_mut[0x10..];
-        ctx.read_into(0x08, &mut data_mut[..T::SIZE]);
-        let data_mut = &mut data_mut[T::SIZE..];
+        let data_mut = Self::write_header::<T>(&ctx, data.as_mut(), pid);
         data_mut[..0x08].clone_from_slice(&(stack_len as u64).to_ne_bytes());
         if !need_stack {
             data.submit();
             return Ok(());
         }
-        match ctx.get_user_stack(&mut data_mut[0x08..]) {
+        let data_mut = &mut data_mut[0x08..];
+        // the frames are already in the per cpu array, do not walk the kernel stack twice
+        if let Some(kernel_stack) = self.kernel_stack.get(0).filter(|_| kernel_stack_len != 0) {
+            let len = kernel_stack_len * 8;
+            data_mut[..len].clone_from_slice(&kernel_stack[..len]);
+        }
+        match ctx.get_user_stack(&mut data_mut[(kernel_stack_len * 8)..]) {
             Ok(_size) => {
                 data.submit();
                 Ok(())
@@ -324,6 +480,284 @@ impl App {
         }
     }
 
+    /// Write the header, the pid, the discriminant and the body, return the rest of the buffer;
+    /// the context of a uprobe is the registers, there is no tracepoint header, only the thread id
+    #[inline(always)]
+    fn write_header<'a, T>(ctx: &ebpf::Context, data_mut: &'a mut [u8], pid: u32) -> &'a mut [u8]
+    where
+        T: Pod,
+    {
+        if T::REGS {
+            let tid = unsafe { helpers::get_current_pid_tgid() } as u32;
+            data_mut[..0x04].clone_from_slice(&[0; 4]);
+            data_mut[0x04..0x08].clone_from_slice(&tid.to_ne_bytes());
+        } else {
+            ctx.read_into(0x00, &mut data_mut[..0x08]);
+        }
+        data_mut[0x08..0x0c].clone_from_slice(&pid.to_ne_bytes());
+        data_mut[0x0c..0x10].clone_from_slice(&T::DISCRIMINANT.unwrap_or(0).to_ne_bytes());
+        let data_mut = &mut data_mut[0x10..];
+        let offset = if T::REGS { 0x00 } else { 0x08 };
+        ctx.read_into(offset, &mut data_mut[..T::SIZE]);
+        &mut data_mut[T::SIZE..]
+    }
+
+    /// Copy the user registers and the top of the user stack, the user space unwinds it,
+    /// the copy is empty if the stack is shorter
+    #[inline(always)]
+    fn output_raw_stack<T>(&mut self, ctx: ebpf::Context, pid: u32) -> Result<(), i32>
+    where
+        T: Pod,
+    {
+        let size = 0x10 + T::SIZE + 0x20 + RAW_STACK_SIZE;
+        let mut data = self.event_queue.reserve(size).map_err(|e| {
+            self.inc_lost::<T>();
+            e
+        })?;
+        let data_mut = Self::write_header::<T>(&ctx, data.as_mut(), pid);
+        // the tracepoint is in the kernel, the user registers are saved at the entry
+        let mut regs = [0; 0xa8];
+        let read = unsafe {
+            let regs_ptr = raw_helpers::task_pt_regs(raw_helpers::get_current_task_btf());
+            raw_helpers::probe_read_kernel(regs.as_mut_ptr(), regs.len() as u32, regs_ptr)
+        };
+        if read != 0 {
+            data_mut[..0x08].clone_from_slice(&STACK_RAW_FLAG.to_ne_bytes());
+            data_mut[0x08..0x20].clone_from_slice(&[0; 0x18]);
+            data.submit();
+            return Ok(());
+        }
+        // `ip`, `sp` and `bp` of `struct pt_regs`
+        data_mut[0x08..0x10].clone_from_slice(&regs[0x80..0x88]);
+        data_mut[0x10..0x18].clone_from_slice(&regs[0x98..0xa0]);
+        data_mut[0x18..0x20].clone_from_slice(&regs[0x20..0x28]);
+        let mut sp = [0; 8];
+        sp.clone_from_slice(&regs[0x98..0xa0]);
+        let stack = &mut data_mut[0x20..(0x20 + RAW_STACK_SIZE)];
+        let read = unsafe {
+            let sp = u64::from_ne_bytes(sp);
+            raw_helpers::probe_read_user(stack.as_mut_ptr(), RAW_STACK_SIZE as u32, sp)
+        };
+        let copied = if read == 0 { RAW_STACK_SIZE as u64 } else { 0 };
+        data_mut[..0x08].clone_from_slice(&(STACK_RAW_FLAG | copied).to_ne_bytes());
+        data.submit();
+        Ok(())
+    }
+
+    /// Put the stack into the `stacks` map, the key is a FNV-1a hash of the frames,
+    /// the value has the same layout as the stack in the event.
+    /// Not a `BPF_MAP_TYPE_STACK_TRACE` on purpose, it stores either the kernel or the user
+    /// frames, here they are concatenated. The stacks of the recorded events must stay
+    /// resolvable, so the map is evicted only in the aggregate mode, by `poll_usage`,
+    /// otherwise when it is full the whole stack is sent instead.
+    /// Colliding stacks are not checked, with 63 bits it takes billions of distinct stacks.
+    #[inline(always)]
+    fn store_stack(&mut self, ctx: &ebpf::Context, kernel: bool) -> Option<u64> {
+        const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        let value = self.stack_value.get_mut(0)?;
+        let (len_bytes, ips) = value.split_at_mut(0x08);
+        let mut len = 0;
+        if kernel {
+            let size = ctx
+                .get_kernel_stack(&mut ips[..(KERNEL_STACK_MAX_DEPTH * 8)])
+                .unwrap_or(0);
+            len = ((size / 8) as usize).min(KERNEL_STACK_MAX_DEPTH);
+        }
+        let size = ctx.get_user_stack(&mut ips[(len * 8)..]).unwrap_or(0);
+        len = (len + (size / 8) as usize).min(STACK_MAX_DEPTH);
+        len_bytes.clone_from_slice(&(len as u64).to_ne_bytes());
+
+        let mut hash = FNV_OFFSET;
+        for i in 0..STACK_MAX_DEPTH {
+            if i >= len {
+                break;
+            }
+            let mut ip = [0; 8];
+            ip.clone_from_slice(&ips[(i * 8)..((i + 1) * 8)]);
+            hash = (hash ^ u64::from_ne_bytes(ip)).wrapping_mul(FNV_PRIME);
+        }
+        // the highest bit marks the stack id in the event
+        let stack_id = hash & !STACK_ID_FLAG;
+
+        if self.stacks.get(&stack_id.to_ne_bytes()).is_none() {
+            self.stacks.insert(stack_id.to_ne_bytes(), *value).ok()?;
+        }
+        Some(stack_id)
+    }
+
+    #[inline(always)]
+    fn output_stack_id<T>(&mut self, ctx: ebpf::Context, pid: u32, stack_id: u64) -> Result<(), i32>
+    where
+        T: Pod,
+    {
+        let size = 0x10 + T::SIZE + 0x10;
+        let mut data = self.event_queue.reserve(size).map_err(|e| {
+            self.inc_lost::<T>();
+            e
+        })?;
+        let data_mut = Self::write_header::<T>(&ctx, data.as_mut(), pid);
+        data_mut[..0x08].clone_from_slice(&STACK_ID_FLAG.to_ne_bytes());
+        data_mut[0x08..0x10].clone_from_slice(&stack_id.to_ne_bytes());
+        data.submit();
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn read_u64(s: &[u8]) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.clone_from_slice(&s[..8]);
+        u64::from_ne_bytes(bytes)
+    }
+
+    /// The value of `usage` map is the counters of allocations of each order, then the counters
+    /// of those in the page cache; the user space weights the allocations by their size,
+    /// as the sampling does, see `Sampling::scale`. The counters are shared between cpus,
+    /// so update them atomically; the failure counts as lost `T`
+    #[inline(always)]
+    fn usage_add<T>(&mut self, stack_id: u64, order: u32, count: i64, cache: i64) -> Result<(), i32>
+    where
+        T: Pod,
+    {
+        let key = stack_id.to_ne_bytes();
+        if self.usage.get(&key).is_none() {
+            // another cpu might insert the same stack at the same moment, keep its counters
+            let c = unsafe { raw_helpers::map_insert_new(&self.usage, &key, &[0; 0xb0]) };
+            if c < 0 {
+                self.inc_lost::<T>();
+                return Err(c as i32);
+            }
+        }
+        let value = match self.usage.get_mut(&key) {
+            Some(value) => value,
+            None => {
+                self.inc_lost::<T>();
+                return Err(0);
+            }
+        };
+        let counters = value.as_mut_ptr() as *const AtomicU64;
+        // the entry is not removed at zero, another cpu might be adding to it right now,
+        // the user space evicts the entries which stay at zero, see `poll_usage`
+        let order = (order as usize).min(PAGE_ORDERS - 1);
+        unsafe {
+            (*counters.add(order)).fetch_add(count as u64, Ordering::Relaxed);
+            (*counters.add(PAGE_ORDERS + order)).fetch_add(cache as u64, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Count the allocation in the per cpu counter and tell whether it is sampled,
+    /// the pages of not sampled allocations are unknown, so their free is ignored
+    #[inline(always)]
+    fn sample(&mut self, order: u32) -> bool {
+        let every = self.config_value(config::SAMPLE_EVERY) as u64;
+        let kib = self.config_value(config::SAMPLE_KIB) as u64;
+        if every <= 1 && kib <= 4 {
+            return true;
+        }
+
+        let counter = match self.sample_counter.get_mut(0) {
+            Some(counter) => counter,
+            None => return true,
+        };
+        let mut value = u64::from_ne_bytes(*counter);
+        let sampled = if every > 1 {
+            value += 1;
+            if value >= every {
+                value = 0;
+                true
+            } else {
+                false
+            }
+        } else {
+            value += 4 << order;
+            if value >= kib {
+                value %= kib;
+                true
+            } else {
+                false
+            }
+        };
+        *counter = value.to_ne_bytes();
+
+        sampled
+    }
+
+    /// The value of `pages` map is the stack id, the order and the page cache flag
+    #[inline(always)]
+    fn aggregate_alloc(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        let pfn = ctx.read_here::<u64>(0x08);
+        let order = ctx.read_here::<u32>(0x10);
+        if pfn == 0 {
+            return Ok(());
+        }
+
+        let kernel = self.config_value(config::KERNEL_STACK) != 0;
+        let stack_id = match self.store_stack(&ctx, kernel) {
+            Some(stack_id) => stack_id,
+            None => {
+                self.inc_lost::<PageAlloc>();
+                return Ok(());
+            }
+        };
+        // the page might be allocated again without being freed, release it first
+        self.aggregate_free::<PageAlloc>(pfn)?;
+
+        let mut value = [0; 0x10];
+        value[0x00..0x08].clone_from_slice(&stack_id.to_ne_bytes());
+        value[0x08..0x0c].clone_from_slice(&order.to_ne_bytes());
+        self.pages.insert(pfn.to_ne_bytes(), value).map_err(|e| {
+            self.inc_lost::<PageAlloc>();
+            e
+        })?;
+        self.usage_add::<PageAlloc>(stack_id, order, 1, 0)
+            .map_err(|e| {
+                // the free of the page must not subtract the pages which were never added
+                let _ = self.pages.remove(&pfn.to_ne_bytes());
+                e
+            })
+    }
+
+    #[inline(always)]
+    fn aggregate_free<T>(&mut self, pfn: u64) -> Result<(), i32>
+    where
+        T: Pod,
+    {
+        let (stack_id, order, cache) = match self.pages.get(&pfn.to_ne_bytes()) {
+            Some(value) => (
+                Self::read_u64(&value[0x00..0x08]),
+                value[0x08] as u32,
+                value[0x0c] != 0,
+            ),
+            None => return Ok(()),
+        };
+        self.pages.remove(&pfn.to_ne_bytes()).map_err(|e| {
+            self.inc_lost::<T>();
+            e
+        })?;
+
+        self.usage_add::<T>(stack_id, order, -1, -(cache as i64))
+    }
+
+    #[inline(always)]
+    fn aggregate_mark_cache(&mut self, ctx: ebpf::Context, b: bool) -> Result<(), i32> {
+        let pfn = ctx.read_here::<u64>(0x08);
+        let (stack_id, order) = match self.pages.get_mut(&pfn.to_ne_bytes()) {
+            Some(value) if (value[0x0c] != 0) != b => {
+                value[0x0c] = b as u8;
+                (Self::read_u64(&value[0x00..0x08]), value[0x08] as u32)
+            }
+            _ => return Ok(()),
+        };
+
+        if b {
+            self.usage_add::<AddToPageCache>(stack_id, order, 0, 1)
+        } else {
+            self.usage_add::<RemoveFromPageCache>(stack_id, order, 0, -1)
+        }
+    }
+
     // /sys/kernel/debug/tracing/events/kmem/mm_page_alloc/format
 
     #[allow(dead_code)]
@@ -364,11 +798,21 @@ impl App {
 
     #[inline(always)]
     pub fn page_alloc(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
-        self.output::<PageAlloc>(ctx, true)
+        let pid = self.check_pid()?;
+        if !self.sample(ctx.read_here::<u32>(0x10)) {
+            return Ok(());
+        }
+        if self.config_value(config::AGGREGATE) != 0 {
+            return self.aggregate_alloc(ctx);
+        }
+        self.output_generic::<PageAlloc>(ctx, pid, true)
     }
 
     #[inline(always)]
     pub fn page_free(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        if self.config_value(config::AGGREGATE) != 0 {
+            return self.aggregate_free::<PageFree>(ctx.read_here::<u64>(0x08));
+        }
         self.output_unconditional::<PageFree>(ctx)
     }
 
@@ -380,6 +824,9 @@ impl App {
 
     #[inline(always)]
     pub fn rss_stat(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        if self.config_value(config::AGGREGATE) != 0 {
+            return Ok(());
+        }
         self.output::<RssStat>(ctx, false)
     }
 
@@ -398,22 +845,174 @@ impl App {
     #[allow(dead_code)]
     #[inline(always)]
     pub fn add_to_page_cache(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        if self.config_value(config::AGGREGATE) != 0 {
+            return self.aggregate_mark_cache(ctx, true);
+        }
         self.output_unconditional::<AddToPageCache>(ctx)
     }
 
     #[allow(dead_code)]
     #[inline(always)]
     pub fn remove_from_page_cache(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        if self.config_value(config::AGGREGATE) != 0 {
+            return self.aggregate_mark_cache(ctx, false);
+        }
         self.output_unconditional::<RemoveFromPageCache>(ctx)
     }
+
+    #[inline(always)]
+    pub fn page_fault_user(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        if self.config_value(config::PAGE_FAULT) == 0 || self.config_value(config::AGGREGATE) != 0 {
+            return Ok(());
+        }
+        self.output::<PageFaultUser>(ctx, false)
+    }
+
+    #[inline(always)]
+    fn output_vm_syscall<T>(&mut self, ctx: ebpf::Context, need_stack: bool) -> Result<(), i32>
+    where
+        T: Pod,
+    {
+        if self.config_value(config::VM_SYSCALLS) == 0 || self.config_value(config::AGGREGATE) != 0
+        {
+            return Ok(());
+        }
+        self.output::<T>(ctx, need_stack)
+    }
+
+    /// Without `VM_SYSCALLS` only the code is reported, the process map is read again
+    #[inline(always)]
+    pub fn sys_enter_mmap(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        const PROT_EXEC: u64 = 0x4;
+
+        if self.config_value(config::AGGREGATE) != 0 {
+            return Ok(());
+        }
+        if self.config_value(config::VM_SYSCALLS) != 0 {
+            return self.output::<SysEnterMmap>(ctx, true);
+        }
+        if ctx.read_here::<u64>(0x20) & PROT_EXEC == 0 {
+            return Ok(());
+        }
+        self.output::<MmapExec>(ctx, false)?;
+        let tid = unsafe { helpers::get_current_pid_tgid() } as u32;
+        self.exec_mmap.insert(tid.to_ne_bytes(), [0; 4])
+    }
+
+    /// The region is mapped when the syscall returns, the exit follows `MmapExec` too,
+    /// but not the other `mmap` calls, unless `VM_SYSCALLS` reports all of them
+    #[inline(always)]
+    pub fn sys_exit_mmap(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        if self.config_value(config::AGGREGATE) != 0 {
+            return Ok(());
+        }
+        if self.config_value(config::VM_SYSCALLS) == 0 {
+            let tid = unsafe { helpers::get_current_pid_tgid() } as u32;
+            if !matches!(self.exec_mmap.remove(&tid.to_ne_bytes()), Ok(Some(_))) {
+                return Ok(());
+            }
+        }
+        self.output::<SysExit>(ctx, false)
+    }
+
+    #[inline(always)]
+    pub fn sys_enter_munmap(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output_vm_syscall::<SysEnterMunmap>(ctx, false)
+    }
+
+    #[inline(always)]
+    pub fn sys_exit_munmap(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output_vm_syscall::<SysExit>(ctx, false)
+    }
+
+    #[inline(always)]
+    pub fn sys_enter_mremap(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output_vm_syscall::<SysEnterMremap>(ctx, true)
+    }
+
+    #[inline(always)]
+    pub fn sys_exit_mremap(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output_vm_syscall::<SysExit>(ctx, false)
+    }
+
+    #[inline(always)]
+    pub fn sys_enter_brk(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output_vm_syscall::<SysEnterBrk>(ctx, true)
+    }
+
+    #[inline(always)]
+    pub fn sys_exit_brk(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output_vm_syscall::<SysExit>(ctx, false)
+    }
+
+    /// The probes are attached only if enabled, no need to check the config
+    #[inline(always)]
+    pub fn heap_call(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output::<HeapCall>(ctx, true)
+    }
+
+    #[inline(always)]
+    pub fn heap_return(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output::<HeapReturn>(ctx, false)
+    }
+
+    #[inline(always)]
+    pub fn alloc_probe(&mut self, ctx: ebpf::Context) -> Result<(), i32> {
+        self.output::<AllocProbe>(ctx, true)
+    }
 }
 
 #[cfg(feature = "user")]
-fn run_bpf() -> (ebpf::Skeleton<App>, i32) {
-    use ebpf::{
-        kind::{AppItem, AppItemKindMut},
-        Skeleton,
+fn bpf_config(config: &cli::Config) -> impl Iterator<Item = (u32, u32)> {
+    use server::Sampling;
+
+    let (every, kib) = match config.sampling() {
+        Sampling::All => (0, 0),
+        Sampling::Every(n) => (n, 0),
+        Sampling::Kib(n) => (0, n),
     };
+    [
+        (config::KERNEL_STACK, config.bpf.kernel_stack as u32),
+        (config::STACK_ID, config.bpf.stack_id as u32),
+        (config::AGGREGATE, config.bpf.aggregate_in_kernel as u32),
+        (config::UNWIND, config.bpf.unwind as u32),
+        (config::PAGE_FAULT, config.bpf.page_faults as u32),
+        (config::VM_SYSCALLS, config.bpf.vm_syscalls as u32),
+        (config::SAMPLE_EVERY, every),
+        (config::SAMPLE_KIB, kib),
+    ]
+    .into_iter()
+}
+
+#[cfg(feature = "user")]
+fn map_fd<T>(item: &mut T) -> i32
+where
+    T: ebpf::kind::AppItem,
+{
+    use ebpf::kind::AppItemKindMut;
+
+    match item.kind_mut() {
+        AppItemKindMut::Map(map) => map.fd(),
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(feature = "user")]
+fn prog_fd<T>(item: &mut T) -> i32
+where
+    T: ebpf::kind::AppItem,
+{
+    use ebpf::kind::AppItemKindMut;
+
+    match item.kind_mut() {
+        AppItemKindMut::Prog(prog) => prog.fd(),
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(feature = "user")]
+fn run_bpf(config: &cli::Config) -> ebpf::Skeleton<App> {
+    use ebpf::Skeleton;
     use std::io::Error;
 
     static CODE: &[u8] = include_bytes!(concat!("../", env!("BPF_MEM")));
@@ -439,34 +1038,53 @@ fn run_bpf() -> (ebpf::Skeleton<App>, i32) {
             }
         }
     }
+    // otherwise the kernel waits for a process started with `BPF_MEM` environment variable
+    if let Some(pid) = config.target.pid {
+        skeleton
+            .app
+            .pid
+            .insert(0u32.to_ne_bytes(), pid.to_ne_bytes())
+            .unwrap_or_else(|code| panic!("failed to set target pid: {}", code));
+    }
+    for (key, value) in bpf_config(config) {
+        skeleton
+            .app
+            .config
+            .insert(key.to_ne_bytes(), value.to_ne_bytes())
+            .unwrap_or_else(|code| panic!("failed to configure bpf: {}", code));
+    }
     skeleton
         .attach()
         .unwrap_or_else(|code| panic!("failed to attach bpf: {}", code));
     log::info!("attached bpf module");
 
-    let fd = match skeleton.app.event_queue.kind_mut() {
-        AppItemKindMut::Map(map) => map.fd(),
-        _ => unreachable!(),
-    };
-
-    (skeleton, fd)
+    skeleton
 }
 
+#[cfg(feature = "user")]
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32},
+        Arc,
+    },
+};
+
 #[cfg(feature = "user")]
 fn main() {
-    use ebpf::RingBufferRegistry;
-    use std::{
-        io,
-        sync::{
-            atomic::{AtomicBool, Ordering},
-            Arc,
-        },
-        time::Duration,
-    };
-    use server::{Consumer, StackResolver};
+    use std::{process, sync::atomic::Ordering};
+    use clap::Parser;
+    use server::{Aggregator, AllocationState, CrossCheck, EventLast, History};
+    use self::cli::{Cli, Command, Config, TrackerKind};
 
     env_logger::init();
 
+    let cli = Cli::parse();
+    let config = Config::load(&cli).unwrap_or_else(|error| {
+        log::error!("{}", error);
+        process::exit(1);
+    });
+
     // spawn a thread listening ctrl+c
     let running = Arc::new(AtomicBool::new(true));
     {
@@ -475,49 +1093,102 @@ fn main() {
             .expect("failed to setup ctrl+c handler");
     }
 
-    // attack bpf module and acquire fd of event stream
-    let (skeleton, fd) = run_bpf();
-
-    let cli = Consumer::default();
-    let aggregator = cli.reporter();
-
-    if std::env::args().find(|s| s == "--dump").is_some() {
-        aggregator.lock().unwrap().turn_on_dump();
+    // the tracker is a type parameter of the function
+    macro_rules! with_tracker {
+        ($f:ident($($arg:expr),* $(,)?)) => {
+            match config.tracker {
+                TrackerKind::Aggregator => $f::<Aggregator>($($arg),*),
+                TrackerKind::History => $f::<History<EventLast>>($($arg),*),
+                TrackerKind::AllocationState => $f::<AllocationState>($($arg),*),
+                TrackerKind::CrossCheck => $f::<CrossCheck>($($arg),*),
+            }
+        };
     }
 
-    // spawn a thread monitoring process map from `/proc/<pid>/maps` and loading symbol tables
-    let resolver = StackResolver::spawn(cli.pid());
+    match &cli.command {
+        None | Some(Command::Serve { .. }) => {
+            // attack bpf module
+            let skeleton = run_bpf(&config);
+            if config.bpf.aggregate_in_kernel {
+                poll_usage(&config, skeleton, running)
+            } else {
+                with_tracker!(consume_events(&config, skeleton, running))
+            }
+        }
+        Some(Command::Record { .. }) => {
+            // the stacks would remain in the kernel
+            if config.bpf.stack_id || config.bpf.aggregate_in_kernel {
+                log::error!("cannot record events with stack ids or aggregated in kernel");
+                process::exit(1);
+            }
+            // the unwind tables are of the running process
+            if config.bpf.unwind {
+                log::error!("cannot record events with the stacks unwound in the user space");
+                process::exit(1);
+            }
+            // the addresses of the probed functions are known only to the running profiler
+            if config.bpf.heap_probes || config.bpf.alloc_probes {
+                log::error!("cannot record events with the heap or the allocator probes");
+                process::exit(1);
+            }
+            record(&config, run_bpf(&config), running)
+        }
+        Some(Command::Replay { input, .. }) => with_tracker!(replay(&config, input, running)),
+        Some(Command::Report {
+            input,
+            short,
+            threshold,
+            reverse,
+            output,
+            ..
+        }) => with_tracker!(report(
+            &config,
+            input,
+            *short,
+            *threshold,
+            *reverse,
+            output.as_deref()
+        )),
+        Some(Command::Symbolize {
+            input,
+            store,
+            output,
+        }) => symbolize(&config, input, store, output.as_deref()),
+    }
+}
 
-    // spawn a thread-pool serving http requests, using tokio
-    let server = server::server::run(cli.reporter(), resolver, cli.pid());
+/// Polls the event queue until stopped, checks lost events every second
+#[cfg(feature = "user")]
+fn poll_events<F, L>(
+    skeleton: &mut ebpf::Skeleton<App>,
+    running: &AtomicBool,
+    on_event: F,
+    mut on_lost: L,
+) where
+    F: FnMut(&[u8]) + 'static,
+    L: FnMut(Vec<(&'static str, u64)>),
+{
+    use ebpf::RingBufferRegistry;
+    use std::{
+        io,
+        sync::atomic::Ordering,
+        time::{Duration, Instant},
+    };
+    use self::bpf_map::{LostEventsMap, MapFd};
 
+    // acquire fd of event stream
+    let fd = map_fd(&mut skeleton.app.event_queue);
     let mut rb = RingBufferRegistry::default();
-    let mut cli = cli;
-    rb.add_fd(fd, move |data| cli.arrive(data))
+    rb.add_fd(fd, on_event)
         .map_err(|_| io::Error::last_os_error())
         .expect("failed to setup ring buffer");
 
-    let mut overall_cnt = 0;
-    let mut old_cnt = 0;
+    let mut lost_events = LostEventsMap::new(MapFd(map_fd(&mut skeleton.app.lost_events)))
+        .expect("failed to read the number of cpus");
+    let mut last_check = Instant::now();
     while running.load(Ordering::Relaxed) {
         match rb.poll(Duration::from_secs(1)) {
-            Ok(_) => {
-                overall_cnt += 1;
-                if overall_cnt & 0xffff == 0 {
-                    let cnt = skeleton
-                        .app
-                        .lost_events
-                        .get(&0u32.to_ne_bytes())
-                        .map(u32::from_le_bytes)
-                        .unwrap_or(0);
-                    if cnt - old_cnt != 0 {
-                        log::warn!("lost events: {}", cnt - old_cnt);
-                        old_cnt = cnt;
-                    } else {
-                        log::debug!("check: ok");
-                    }
-                }
-            }
+            Ok(_) => (),
             Err(c) => {
                 if c != -4 {
                     log::error!("code: {}, error: {}", c, io::Error::last_os_error());
@@ -525,9 +1196,378 @@ fn main() {
                 }
             }
         }
+        if last_check.elapsed() >= Duration::from_secs(1) {
+            last_check = Instant::now();
+            let lost = lost_events.delta();
+            if !lost.is_empty() {
+                log::warn!("lost events: {:?}", lost);
+            }
+            on_lost(lost);
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+fn consume_events<T>(
+    config: &cli::Config,
+    mut skeleton: ebpf::Skeleton<App>,
+    running: Arc<AtomicBool>,
+) where
+    T: server::Tracker + server::Reporter + Default + Send + 'static,
+{
+    use std::time::SystemTime;
+    use server::{Consumer, Reporter};
+
+    let mut cli = Consumer::<T>::default();
+    if config.bpf.stack_id {
+        let stacks_fd = map_fd(&mut skeleton.app.stacks);
+        cli.set_stack_source(bpf_map::StackMap(bpf_map::MapFd(stacks_fd)));
+    }
+    let tracker = cli.reporter();
+
+    // spawn a thread attaching the allocator probes once the process has mapped its libraries
+    let programs = uprobe::Programs {
+        heap: config.bpf.heap_probes.then(|| {
+            let call_fd = prog_fd(&mut skeleton.app.heap_call);
+            (call_fd, prog_fd(&mut skeleton.app.heap_return))
+        }),
+        usdt: config
+            .bpf
+            .alloc_probes
+            .then(|| prog_fd(&mut skeleton.app.alloc_probe)),
+    };
+    let _uprobes = if programs.heap.is_some() || programs.usdt.is_some() {
+        let (pid, functions) = (cli.pid(), cli.heap_functions());
+        Some(uprobe::spawn(pid, functions, programs, running.clone()))
+    } else {
+        None
+    };
+
+    if config.output.dump.is_some() {
+        tracker.lock().unwrap().turn_on_dump();
+    }
+    tracker.lock().unwrap().set_sampling(config.sampling());
+
+    // spawn a thread monitoring process map from `/proc/<pid>/maps` and loading symbol tables
+    let resolver = spawn_resolver(config, cli.pid());
+    cli.set_map_refresh(resolver.read().unwrap().map_refresh());
+    if config.bpf.unwind {
+        cli.set_unwinder(resolver.clone());
+    }
+
+    // spawn a thread-pool serving http requests, using tokio
+    let http = config.http().unwrap_or_else(|error| panic!("{}", error));
+    let server = server::server::run(&http, cli.reporter(), resolver, cli.pid(), cli.stream())
+        .unwrap_or_else(|error| panic!("{}", error));
+
+    poll_events(
+        &mut skeleton,
+        &running,
+        move |data| cli.arrive(data),
+        |lost| {
+            tracker
+                .lock()
+                .unwrap()
+                .lost_events_mut()
+                .observe(SystemTime::now(), lost)
+        },
+    );
+
+    if let Some(path) = &config.output.dump {
+        tracker.lock().unwrap().store_dump(path);
+    }
+    log::info!("stop server");
+    let _ = server;
+}
+
+/// Write the events as they come from the kernel, without any processing
+#[cfg(feature = "user")]
+fn record(config: &cli::Config, mut skeleton: ebpf::Skeleton<App>, running: Arc<AtomicBool>) {
+    use std::{fs::File, io::BufWriter, sync::Mutex};
+    use server::Recorder;
+
+    let path = &config.output.recording;
+    let file = File::create(path)
+        .unwrap_or_else(|error| panic!("failed to create {}: {}", path.display(), error));
+    let recorder = Recorder::new(BufWriter::new(file))
+        .unwrap_or_else(|error| panic!("failed to write {}: {}", path.display(), error));
+    let recorder = Arc::new(Mutex::new(recorder));
+
+    log::info!("recording into {}", path.display());
+    let recorder_ref = recorder.clone();
+    poll_events(
+        &mut skeleton,
+        &running,
+        move |data| {
+            if let Err(error) = recorder_ref.lock().unwrap().record(data) {
+                log::error!("failed to record event: {}", error);
+            }
+        },
+        |_| (),
+    );
+
+    if let Err(error) = recorder.lock().unwrap().flush() {
+        log::error!("failed to write {}: {}", path.display(), error);
+    }
+    log::info!("stop recording");
+}
+
+#[cfg(feature = "user")]
+fn read_recording<T>(input: &Path, config: &cli::Config) -> server::Consumer<T>
+where
+    T: server::Tracker + server::Reporter + Default,
+{
+    use std::{fs::File, io::BufReader};
+    use server::{Consumer, Recording, Reporter};
+
+    let file = File::open(input)
+        .unwrap_or_else(|error| panic!("failed to open {}: {}", input.display(), error));
+    let mut recording = Recording::new(BufReader::new(file))
+        .unwrap_or_else(|error| panic!("failed to read {}: {}", input.display(), error));
+
+    let mut cli = Consumer::<T>::default();
+    cli.reporter()
+        .lock()
+        .unwrap()
+        .set_sampling(config.sampling());
+    let mut count = 0;
+    loop {
+        match recording.next_record() {
+            Ok(Some(data)) => cli.arrive(data),
+            Ok(None) => break,
+            Err(error) => {
+                log::error!("failed to read {}: {}", input.display(), error);
+                break;
+            }
+        }
+        count += 1;
+    }
+    log::info!("replayed {} events", count);
+
+    cli
+}
+
+#[cfg(feature = "user")]
+fn replay<T>(config: &cli::Config, input: &Path, running: Arc<AtomicBool>)
+where
+    T: server::Tracker + server::Reporter + Default + Send + 'static,
+{
+    use std::{sync::atomic::Ordering, thread, time::Duration};
+
+    let cli = read_recording::<T>(input, config);
+    let resolver = spawn_resolver(config, cli.pid());
+    let http = config.http().unwrap_or_else(|error| panic!("{}", error));
+    let server = server::server::run(&http, cli.reporter(), resolver, cli.pid(), cli.stream())
+        .unwrap_or_else(|error| panic!("{}", error));
+
+    while running.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_secs(1));
+    }
+    log::info!("stop server");
+    let _ = server;
+}
+
+/// Print the report of the recorded events, the process must be still running
+/// to resolve the symbols
+#[cfg(feature = "user")]
+fn report<T>(
+    config: &cli::Config,
+    input: &Path,
+    short: bool,
+    threshold: u64,
+    reverse: bool,
+    output: Option<&Path>,
+) where
+    T: server::Tracker + server::Reporter + Default,
+{
+    use std::{
+        fs::File,
+        io::{self, Write},
+        sync::atomic::Ordering,
+    };
+    use server::{Folding, Reporter, StackResolver};
+
+    #[derive(serde::Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct ShortReport {
+        total: u64,
+        cache: u64,
+        anon: u64,
+    }
+
+    let cli = read_recording::<T>(input, config);
+    let output: Box<dyn Write> = match output {
+        Some(path) => Box::new(
+            File::create(path)
+                .unwrap_or_else(|error| panic!("failed to create {}: {}", path.display(), error)),
+        ),
+        None => Box::new(io::stdout()),
+    };
+
+    let tracker = cli.reporter();
+    let tracker = tracker.lock().unwrap();
+    let result = if short {
+        let (total, cache) = tracker.short_report();
+        let report = ShortReport {
+            total,
+            cache,
+            anon: total - cache,
+        };
+        serde_json::to_writer_pretty(output, &report)
+    } else {
+        let mut resolver = StackResolver::load(cli.pid().load(Ordering::Relaxed));
+        resolver.set_categories(
+            config
+                .categories()
+                .unwrap_or_else(|error| panic!("{}", error)),
+        );
+        let folding = Folding::new(&config.tree_rules()).expect("checked in `Config::load`");
+        let report = if folding.is_empty() {
+            tracker.tree_report(&resolver, threshold, reverse)
+        } else {
+            let mut report = tracker.tree_report(&resolver, 0, reverse);
+            report.fold(&folding, threshold);
+            report
+        };
+        serde_json::to_writer_pretty(output, &report)
+    };
+    if let Err(error) = result {
+        log::error!("failed to write the report: {}", error);
+    }
+}
+
+/// Spawns the thread following the process map, see `StackResolver::spawn`
+#[cfg(feature = "user")]
+fn spawn_resolver(
+    config: &cli::Config,
+    pid: Arc<AtomicU32>,
+) -> Arc<std::sync::RwLock<server::StackResolver>> {
+    let symbols = config.resolver.symbols;
+    let resolver = server::StackResolver::spawn(pid, config.refresh_interval(), symbols);
+    let categories = config
+        .categories()
+        .unwrap_or_else(|error| panic!("{}", error));
+    resolver.write().unwrap().set_categories(categories);
+    resolver
+}
+
+/// Fill the function names into the report, the binaries are looked up in the `store`
+#[cfg(feature = "user")]
+fn symbolize(config: &cli::Config, input: &Path, store: &Path, output: Option<&Path>) {
+    use std::{
+        fs::{self, File},
+        io::{self, Write},
+        process,
+    };
+    use server::Symbolizer;
+
+    let mut report: serde_json::Value = fs::read(input)
+        .map_err(|error| error.to_string())
+        .and_then(|data| serde_json::from_slice(&data).map_err(|error| error.to_string()))
+        .unwrap_or_else(|error| {
+            log::error!("failed to read {}: {}", input.display(), error);
+            process::exit(1);
+        });
+    let categories = config
+        .categories()
+        .unwrap_or_else(|error| panic!("{}", error));
+    let mut symbolizer = Symbolizer::new(store, categories);
+    let resolved = symbolizer.symbolize(&mut report);
+    log::info!("resolved {} frames", resolved);
+
+    let output: Box<dyn Write> = match output {
+        Some(path) => Box::new(
+            File::create(path)
+                .unwrap_or_else(|error| panic!("failed to create {}: {}", path.display(), error)),
+        ),
+        None => Box::new(io::stdout()),
+    };
+    if let Err(error) = serde_json::to_writer_pretty(output, &report) {
+        log::error!("failed to write the report: {}", error);
+    }
+}
+
+/// The bpf program counts allocations of each order per stack itself, read its maps periodically
+#[cfg(feature = "user")]
+fn poll_usage(config: &cli::Config, mut skeleton: ebpf::Skeleton<App>, running: Arc<AtomicBool>) {
+    use std::{
+        collections::HashSet,
+        sync::{atomic::Ordering, Mutex},
+        thread,
+        time::{Duration, SystemTime},
+    };
+    use event::{StackCache, PAGE_ORDERS};
+    use server::{Reporter, Snapshot, Tracked};
+    use self::bpf_map::{LostEventsMap, MapFd, StackMap};
+
+    let pid = Arc::new(AtomicU32::new(0));
+    let snapshot = Arc::new(Mutex::new(Tracked::<Snapshot>::default()));
+    snapshot.lock().unwrap().set_sampling(config.sampling());
+
+    let resolver = spawn_resolver(config, pid.clone());
+    let http = config.http().unwrap_or_else(|error| panic!("{}", error));
+    // no page events, only the usage
+    let stream = server::EventStream::default();
+    let server = server::server::run(&http, snapshot.clone(), resolver, pid.clone(), stream)
+        .unwrap_or_else(|error| panic!("{}", error));
+
+    let usage = MapFd(map_fd(&mut skeleton.app.usage));
+    let stacks_map = MapFd(map_fd(&mut skeleton.app.stacks));
+    let mut stacks = StackCache::new(StackMap(stacks_map));
+    // the keys of `usage` which were zero at the previous poll
+    let mut idle = HashSet::new();
+    let mut lost_events = LostEventsMap::new(MapFd(map_fd(&mut skeleton.app.lost_events)))
+        .expect("failed to read the number of cpus");
+    while running.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_secs(1));
+
+        if let Some(target_pid) = skeleton.app.pid.get(&0u32.to_ne_bytes()) {
+            pid.store(u32::from_ne_bytes(target_pid), Ordering::Relaxed);
+        }
+
+        let mut state = vec![];
+        let mut zero = HashSet::new();
+        for key in usage.keys::<8>() {
+            let mut value = [0; 0x10 * PAGE_ORDERS];
+            if usage.lookup(&key, &mut value).is_err() {
+                continue;
+            }
+            // the stack has had no pages since the previous poll, free the entry and the stack
+            // for other stacks; an allocation in the very moment of the removal is lost
+            if value == [0; 0x10 * PAGE_ORDERS] {
+                if idle.contains(&key) {
+                    let _ = usage.delete(&key);
+                    let _ = stacks_map.delete(&key);
+                } else {
+                    zero.insert(key);
+                }
+                continue;
+            }
+            // the counters are updated without synchronization with this thread,
+            // they might be slightly negative for a moment
+            let mut counters = [[0; PAGE_ORDERS]; 2];
+            for (counter, bytes) in counters.iter_mut().flatten().zip(value.chunks(8)) {
+                *counter = i64::from_ne_bytes(bytes.try_into().unwrap()).max(0) as u64;
+            }
+            let [allocations, cache] = counters;
+            if allocations == [0; PAGE_ORDERS] {
+                continue;
+            }
+            match stacks.get(u64::from_ne_bytes(key)) {
+                Some(stack) => state.push((stack.clone(), allocations, cache)),
+                None => log::warn!("no stack for id {:016x}", u64::from_ne_bytes(key)),
+            }
+        }
+        idle = zero;
+        let lost = lost_events.delta();
+        if !lost.is_empty() {
+            log::warn!("lost events: {:?}", lost);
+        }
+        let mut snapshot = snapshot.lock().unwrap();
+        snapshot.tracker_mut().update(state);
+        snapshot.lost_events_mut().observe(SystemTime::now(), lost);
     }
 
-    aggregator.lock().unwrap().store_dump();
     log::info!("stop server");
     let _ = server;
 }
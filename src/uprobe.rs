@@ -0,0 +1,235 @@
+// Copyright (c) Viable Systems
+// SPDX-License-Identifier: MIT
+
+//! Attach the allocator probes and the USDT probes of `bpf-mem-alloc`,
+//! the path and the offset of a uprobe are known
+//! only when the process has mapped its libraries, so it is done at runtime
+//! with `perf_event_open` instead of by the skeleton.
+
+use std::{
+    collections::HashSet,
+    ffi::CString,
+    fs, io, mem,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use server::{function_offsets, usdt_offsets, HeapFunction, HeapFunctions, ProcessMap};
+
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+const PERF_EVENT_IOC_SET_BPF: libc::c_ulong = 0x40042408;
+
+/// The bit of `config` which turns the uprobe into a uretprobe
+const PERF_PROBE_CONFIG_IS_RETPROBE: u64 = 1;
+
+/// The first fields of `struct perf_event_attr`, the size is `PERF_ATTR_SIZE_VER5`
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    ty: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    /// The pointer to the path
+    config1: u64,
+    /// The offset in the file
+    config2: u64,
+    _rest: [u64; 5],
+}
+
+/// The perf event with the bpf program, closed on drop
+struct Probe(i32);
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+impl Probe {
+    fn attach(
+        pmu: u32,
+        pid: u32,
+        path: &str,
+        offset: u64,
+        prog_fd: i32,
+        ret: bool,
+    ) -> io::Result<Self> {
+        let path =
+            CString::new(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let attr = PerfEventAttr {
+            ty: pmu,
+            size: mem::size_of::<PerfEventAttr>() as u32,
+            config: if ret {
+                PERF_PROBE_CONFIG_IS_RETPROBE
+            } else {
+                0
+            },
+            config1: path.as_ptr() as u64,
+            config2: offset,
+            ..Default::default()
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                pid as libc::pid_t,
+                -1 as libc::c_int,
+                -1 as libc::c_int,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let probe = Probe(fd as i32);
+        if unsafe { libc::ioctl(probe.0, PERF_EVENT_IOC_SET_BPF, prog_fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::ioctl(probe.0, PERF_EVENT_IOC_ENABLE, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(probe)
+    }
+}
+
+fn uprobe_pmu() -> io::Result<u32> {
+    fs::read_to_string("/sys/bus/event_source/devices/uprobe/type")?
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The file descriptors of the programs, `None` if not enabled
+pub struct Programs {
+    /// The entry and the return of the allocator functions
+    pub heap: Option<(i32, i32)>,
+    pub usdt: Option<i32>,
+}
+
+/// Attach the programs to the allocator functions of every file the process maps,
+/// the map is checked periodically, the libraries might be loaded later;
+/// the probes are detached when the thread exits
+pub fn spawn(
+    pid: Arc<AtomicU32>,
+    functions: Arc<RwLock<HeapFunctions>>,
+    programs: Programs,
+    running: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let pmu = match uprobe_pmu() {
+            Ok(v) => v,
+            Err(error) => {
+                log::error!("uprobes are not supported: {}", error);
+                return;
+            }
+        };
+        let mut attacher = Attacher {
+            pmu,
+            pid: 0,
+            programs,
+            functions,
+            visited: HashSet::new(),
+            probes: vec![],
+        };
+        while running.load(Ordering::Relaxed) {
+            let pid = pid.load(Ordering::SeqCst);
+            if pid != 0 {
+                attacher.refresh(pid);
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    })
+}
+
+struct Attacher {
+    pmu: u32,
+    pid: u32,
+    programs: Programs,
+    functions: Arc<RwLock<HeapFunctions>>,
+    visited: HashSet<String>,
+    probes: Vec<Probe>,
+}
+
+impl Attacher {
+    fn refresh(&mut self, pid: u32) {
+        if pid != self.pid {
+            self.pid = pid;
+            self.visited.clear();
+            self.probes.clear();
+        }
+        let map = ProcessMap::new(pid).unwrap_or_default();
+        for file in map.files() {
+            if self.visited.insert(file.clone()) {
+                self.attach_file(&map, &file);
+            }
+        }
+    }
+
+    fn attach_file(&mut self, map: &ProcessMap, file: &str) {
+        // the file as the process sees it, might be in a container
+        let path = format!("/proc/{}/root{}", self.pid, file);
+        let data = match fs::read(&path) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+
+        let mut probes = vec![];
+        if let Some((call_fd, return_fd)) = self.programs.heap {
+            let names = HeapFunction::SYMBOLS.iter().map(|(name, _)| *name);
+            for (name, offset) in function_offsets(&data, names).unwrap_or_default() {
+                let function = HeapFunction::by_symbol(&name).expect("looked up by these names");
+                let return_fd = function.returns_pointer().then_some(return_fd);
+                probes.push((name, offset, function, call_fd, return_fd));
+            }
+        }
+        if let Some(usdt_fd) = self.programs.usdt {
+            let provider = HeapFunction::USDT_PROVIDER;
+            for (name, offset) in usdt_offsets(&data, provider).unwrap_or_default() {
+                if let Some(function) = HeapFunction::by_usdt(&name) {
+                    probes.push((name, offset, function, usdt_fd, None));
+                }
+            }
+        }
+
+        for (name, offset, function, call_fd, return_fd) in probes {
+            let address = match map.address(file, offset as usize) {
+                Some(v) => v,
+                None => continue,
+            };
+            // the probe fires as soon as it is attached, the function must be known by then
+            self.functions
+                .write()
+                .unwrap()
+                .insert(address as u64, function);
+            let attach =
+                |prog_fd, ret| Probe::attach(self.pmu, self.pid, &path, offset, prog_fd, ret);
+            // the call is probed first, a return without its call would take the outer call
+            let result = attach(call_fd, false).and_then(|call| match return_fd {
+                Some(return_fd) => Ok(vec![call, attach(return_fd, true)?]),
+                None => Ok(vec![call]),
+            });
+            match result {
+                Ok(probes) => {
+                    self.probes.extend(probes);
+                    log::info!("probe {} at {:016x} in {}", name, address, file);
+                }
+                Err(error) => {
+                    // the probes attached so far are dropped, so detached
+                    self.functions.write().unwrap().remove(address as u64);
+                    log::warn!("failed to probe {} in {}: {}", name, file, error);
+                }
+            }
+        }
+    }
+}